@@ -3,7 +3,8 @@ use std::io::BufWriter;
 use dbc_codegen::{Config, FeatureConfig};
 
 fn main() {
-    let dbc_path = "./dbc/pcan.dbc";
+    // The DBC file lives with the firmware
+    let dbc_path = "../firmware/dbc/pcan.dbc";
     let dbc_file = std::fs::read(dbc_path).unwrap();
     println!("cargo:rerun-if-changed={}", dbc_path);
 
@@ -5,23 +5,30 @@
 //!
 //! However having it allows clearing all faults, and allows us to extend later to send
 //! a "crashed" and open contactors in an emergency.
-use crate::app;
-use crate::car::Ignition;
+use crate::can::CanTx;
+use crate::car::{CarState, Ignition};
 use crate::dbc::pcan;
-use crate::hardware::Mono;
+use crate::time::Clock;
+use core::convert::Infallible;
+use embedded_hal::digital::v2::OutputPin;
 use fugit::RateExtU32;
 use hex_literal::hex;
-use rtic::Mutex;
-use rtic_monotonics::Monotonic;
-use stm32g4xx_hal::prelude::OutputPin;
+use rtic_core::Mutex;
 
 // Task does two things:
 // - 1Hz Send CAN message (constant contents)
 // - 50Hz soft PWM output, 80% high duty for "not crashed", 20% for "crashed"
-pub async fn task_airbag_control(cx: app::task_airbag_control::Context<'_>) {
-    let mut car = cx.shared.car;
-    let mut pcan_tx = cx.shared.pcan_tx;
-    let crash_out = cx.local.srs_crash_out;
+pub async fn task_airbag_control<C, MCAR, MTX, TX, P>(
+    mut car: MCAR,
+    mut pcan_tx: MTX,
+    crash_out: &mut P,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+    P: OutputPin<Error = Infallible>,
+{
 
     let airbag_status = pcan::AirbagStatus::try_from(hex!("000000C025029101").as_slice()).unwrap();
     let duty_pct = 80;
@@ -29,7 +36,7 @@ pub async fn task_airbag_control(cx: app::task_airbag_control::Context<'_>) {
     let cycle_time = 50.Hz::<1, 1000>().into_duration();
     let time_high = cycle_time * duty_pct / 100;
 
-    let mut next_cycle = Mono::now();
+    let mut next_cycle = C::now();
 
     loop {
         // Every 1Hz
@@ -39,10 +46,10 @@ pub async fn task_airbag_control(cx: app::task_airbag_control::Context<'_>) {
 
         for _ in 0..50 {
             crash_out.set_high().unwrap();
-            Mono::delay(time_high).await;
+            C::delay(time_high).await;
             crash_out.set_low().unwrap();
             next_cycle += cycle_time;
-            Mono::delay_until(next_cycle).await;
+            C::delay_until(next_cycle).await;
         }
     }
 }
@@ -0,0 +1,267 @@
+//! Cell level battery data, polled from the BMS over UDS.
+//!
+//! The BMS only broadcasts pack level values on PCAN. The rest is available
+//! from ReadDataByIdentifier, the same as OBD apps poll. The layouts are the
+//! community decodes of the Kona's "2101".."2105" PIDs (offsets here are
+//! from the start of the data, after the identifier).
+//!
+//! The same PIDs also have the state of health and the insulation
+//! resistance, but their offsets haven't been checked against a real pack,
+//! so they aren't decoded yet.
+use crate::can::{CanRx, CanTx};
+use crate::car::CarState;
+use crate::time::Clock;
+use crate::uds::{self, Error, BMS};
+use crate::Duration;
+use defmt::Format;
+use rtic_core::Mutex;
+
+/// How often to poll while the car is on
+pub const POLL_PERIOD: Duration = Duration::secs(10);
+
+/// Pack summary, including the module temperatures
+const DID_SUMMARY: u16 = 0x0101;
+/// Cell voltages 1-96, 32 cells per identifier
+const DID_CELLS: [u16; 3] = [0x0102, 0x0103, 0x0104];
+/// More pack values, including cell voltages 97 and 98
+const DID_LAST_CELLS: u16 = 0x0105;
+
+const SUMMARY_TEMP_MAX: usize = 14;
+const SUMMARY_TEMP_MIN: usize = 15;
+const SUMMARY_MODULE_TEMPS: core::ops::Range<usize> = 16..21;
+/// Not yet checked against a real pack, so they're skipped if the response
+/// is too short
+const LAST_CELLS: core::ops::Range<usize> = 27..29;
+
+/// The 64kWh pack has 98 cells in series
+const CELLS: usize = 98;
+const CELLS_PER_DID: usize = 32;
+const _: () = assert!(DID_CELLS.len() * CELLS_PER_DID + LAST_CELLS.end - LAST_CELLS.start == CELLS);
+/// Cell voltage units are 20mV
+const CELL_V_SCALE: f32 = 0.02;
+
+/// Battery detail which the BMS only reports over UDS
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct BatteryDetail {
+    /// Lowest, highest and average cell voltage, in V
+    pub cell_v_min: f32,
+    pub cell_v_max: f32,
+    pub cell_v_avg: f32,
+    /// Lowest, highest and average module temperature, in °C
+    pub temp_min: i8,
+    pub temp_max: i8,
+    pub temp_avg: f32,
+}
+
+/// Running summary of the cell voltages, in the BMS's 20mV units
+struct CellVoltages {
+    min: u8,
+    max: u8,
+    sum: u32,
+    count: u32,
+}
+
+impl Default for CellVoltages {
+    fn default() -> Self {
+        Self {
+            min: u8::MAX,
+            max: 0,
+            sum: 0,
+            count: 0,
+        }
+    }
+}
+
+impl CellVoltages {
+    fn add(&mut self, cells: &[u8]) {
+        // Packs with fewer cells report 0 for the ones they don't have
+        for &cell in cells.iter().filter(|&&cell| cell != 0) {
+            self.min = self.min.min(cell);
+            self.max = self.max.max(cell);
+            self.sum += cell as u32;
+            self.count += 1;
+        }
+    }
+}
+
+/// Poll the BMS for all of the BatteryDetail, and store it in the CarState.
+pub async fn poll_detail<C, MCAR, RX, MTX, TX>(
+    car: &mut MCAR,
+    rx: &mut RX,
+    tx: &mut MTX,
+) -> Result<(), Error>
+where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let mut data = [0u8; 64];
+
+    let len = uds::read_data::<C, _, _, _>(&BMS, DID_SUMMARY, rx, tx, &mut data).await?;
+    if len < SUMMARY_MODULE_TEMPS.end {
+        return Err(Error::Unexpected);
+    }
+    let temps = &data[SUMMARY_MODULE_TEMPS];
+    let temp_sum: i32 = temps.iter().map(|&t| t as i8 as i32).sum();
+    let temp_min = data[SUMMARY_TEMP_MIN] as i8;
+    let temp_max = data[SUMMARY_TEMP_MAX] as i8;
+    let temp_avg = temp_sum as f32 / temps.len() as f32;
+
+    let mut cells = CellVoltages::default();
+    for did in DID_CELLS {
+        let len = uds::read_data::<C, _, _, _>(&BMS, did, rx, tx, &mut data).await?;
+        cells.add(&data[..len.min(CELLS_PER_DID)]);
+    }
+
+    let len = uds::read_data::<C, _, _, _>(&BMS, DID_LAST_CELLS, rx, tx, &mut data).await?;
+    cells.add(data[..len].get(LAST_CELLS).unwrap_or_default());
+    if cells.count == 0 {
+        return Err(Error::Unexpected);
+    }
+
+    let detail = BatteryDetail {
+        cell_v_min: cells.min as f32 * CELL_V_SCALE,
+        cell_v_max: cells.max as f32 * CELL_V_SCALE,
+        cell_v_avg: cells.sum as f32 * CELL_V_SCALE / cells.count as f32,
+        temp_min,
+        temp_max,
+        temp_avg,
+    };
+    defmt::info!("BMS {}", detail);
+    car.lock(|car| car.set_battery_detail(detail));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fresh::IsFresh;
+    use crate::isotp;
+    use crate::test_util::{step_clock, Shared, TestEcu};
+    use crate::time::StepClock;
+    use core::pin::pin;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// BMS which answers ReadDataByIdentifier with `data` for each DID
+    fn bms(data: Vec<(u16, Vec<u8>)>) -> TestEcu {
+        TestEcu::new(isotp::Config::new(BMS.response, BMS.request), move |req| {
+            let did = u16::from_be_bytes([req[1], req[2]]);
+            let (_, data) = data.iter().find(|(d, _)| *d == did).unwrap();
+            let mut response = vec![0x62, req[1], req[2]];
+            response.extend(data);
+            vec![(Duration::millis(20), response)]
+        })
+    }
+
+    /// Responses in the layout above, shorter than the BMS's real ones
+    fn responses(last_cells_len: usize) -> Vec<(u16, Vec<u8>)> {
+        let mut summary = vec![0u8; 40];
+        summary[SUMMARY_TEMP_MAX] = 24;
+        summary[SUMMARY_TEMP_MIN] = -4i8 as u8;
+        summary[SUMMARY_MODULE_TEMPS].copy_from_slice(&[24, 20, 10, 0, -4i8 as u8]);
+
+        // 3.80V, apart from a low cell and a high one
+        let mut cells = [[190u8; CELLS_PER_DID]; 3];
+        cells[1][5] = 185;
+        cells[2][31] = 195;
+
+        let mut last_cells = vec![0u8; last_cells_len];
+        if let Some(last) = last_cells.get_mut(LAST_CELLS) {
+            last.copy_from_slice(&[189, 191]);
+        }
+
+        let mut responses = vec![(DID_SUMMARY, summary), (DID_LAST_CELLS, last_cells)];
+        for (did, cells) in DID_CELLS.into_iter().zip(cells) {
+            responses.push((did, cells.to_vec()));
+        }
+        responses
+    }
+
+    fn poll(ecu: &mut TestEcu) -> (Option<Result<(), Error>>, Option<BatteryDetail>) {
+        let mut car = Shared::new(CarState::<StepClock>::new());
+        let mut rx = ecu.to_tester.clone();
+        let mut tx = ecu.from_tester.clone();
+        let result = {
+            let poll = pin!(poll_detail::<StepClock, _, _, _, _>(
+                &mut car, &mut rx, &mut tx
+            ));
+            ecu.run(poll, Duration::secs(10))
+        };
+        (result, car.lock(|car| car.battery_detail().get()))
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{value} != {expected}");
+    }
+
+    #[test]
+    fn poll_detail_all_cells() {
+        let _clock = step_clock();
+        let mut ecu = bms(responses(30));
+        let (result, detail) = poll(&mut ecu);
+        assert_eq!(result, Some(Ok(())));
+        let detail = detail.unwrap();
+        assert_near(detail.cell_v_min, 3.70);
+        assert_near(detail.cell_v_max, 3.90);
+        // 94 * 190 + 185 + 195 + 189 + 191 = 98 * 190
+        assert_near(detail.cell_v_avg, 3.80);
+        assert_eq!(detail.temp_min, -4);
+        assert_eq!(detail.temp_max, 24);
+        assert_near(detail.temp_avg, 10.0);
+
+        let dids: Vec<_> = ecu.requests.iter().map(|req| req[1..].to_vec()).collect();
+        assert_eq!(dids, vec![[1, 1], [1, 2], [1, 3], [1, 4], [1, 5]]);
+    }
+
+    #[test]
+    fn poll_detail_short_last_cells() {
+        let _clock = step_clock();
+        // Without cells 97 and 98 the other 96 are still used
+        let mut ecu = bms(responses(LAST_CELLS.start));
+        let (result, detail) = poll(&mut ecu);
+        assert_eq!(result, Some(Ok(())));
+        let detail = detail.unwrap();
+        assert_near(detail.cell_v_min, 3.70);
+        assert_near(detail.cell_v_max, 3.90);
+        assert_near(detail.cell_v_avg, 3.80);
+    }
+
+    #[test]
+    fn poll_detail_short_summary() {
+        let _clock = step_clock();
+        let mut responses = responses(30);
+        responses[0].1.truncate(SUMMARY_MODULE_TEMPS.end - 1);
+        let mut ecu = bms(responses);
+        assert_eq!(poll(&mut ecu), (Some(Err(Error::Unexpected)), None));
+        assert_eq!(ecu.requests.len(), 1);
+    }
+
+    #[test]
+    fn all_98_cells() {
+        let mut cells = CellVoltages::default();
+        for _ in DID_CELLS {
+            cells.add(&[190; CELLS_PER_DID]);
+        }
+        // Cells 97 and 98 are the lowest and highest
+        let mut last = [0u8; LAST_CELLS.end];
+        last[LAST_CELLS].copy_from_slice(&[180, 200]);
+        cells.add(&last[LAST_CELLS]);
+
+        assert_eq!(cells.count, CELLS as u32);
+        assert_eq!(cells.min, 180);
+        assert_eq!(cells.max, 200);
+        assert_eq!(cells.sum, 190 * 96 + 180 + 200);
+    }
+
+    #[test]
+    fn missing_cells_ignored() {
+        let mut cells = CellVoltages::default();
+        cells.add(&[0, 190, 0, 191]);
+        assert_eq!(cells.count, 2);
+        assert_eq!(cells.min, 190);
+        assert_eq!(cells.max, 191);
+    }
+}
@@ -0,0 +1,222 @@
+//! CAN transmit and receive abstractions, and bus health.
+use crate::Duration;
+use core::future::Future;
+use defmt::Format;
+use embedded_can::{Frame, Id};
+
+/// Sink for transmitted CAN frames, i.e. a software TX queue.
+///
+/// Doesn't block or return an error: Fakon's messages are periodic, so
+/// there's nothing useful a caller can do about a frame which is lost.
+///
+/// Queued frames have latest-value semantics: a frame replaces any frame
+/// with the same ID which is still waiting to be sent.
+pub trait CanTx {
+    fn transmit(&mut self, frame: &impl Frame);
+
+    /// Transmit a frame which is only worth sending within `lifetime`, after
+    /// that it's dropped from the queue. Implementations without a queue can
+    /// ignore the lifetime.
+    fn transmit_within(&mut self, frame: &impl Frame, lifetime: Duration) {
+        let _ = lifetime;
+        self.transmit(frame);
+    }
+
+    /// Transmit a frame which is part of a sequence (i.e. ISO-TP), so it
+    /// doesn't replace a queued frame with the same ID. Frames with the same
+    /// ID go out in the order they were transmitted.
+    fn transmit_in_order(&mut self, frame: &impl Frame) {
+        self.transmit(frame);
+    }
+}
+
+/// Source of received CAN frames, i.e. the receiving end of a channel which
+/// the RX task forwards some IDs to.
+pub trait CanRx {
+    type Frame: Frame;
+
+    /// Wait for the next frame
+    fn recv(&mut self) -> impl Future<Output = Self::Frame>;
+
+    /// Take the next frame if one has already arrived, without waiting
+    fn try_recv(&mut self) -> Option<Self::Frame>;
+}
+
+/// A classic CAN data frame, for frames which aren't built from a DBC
+/// message (i.e. ones from a PC tool, or forwarded from another bus.)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClassicFrame {
+    id: Id,
+    len: u8,
+    data: [u8; 8],
+}
+
+impl ClassicFrame {
+    /// Data of the frame, for modifying in place
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len as usize]
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+}
+
+impl Frame for ClassicFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        let mut frame = Self {
+            id: id.into(),
+            len: data.len() as u8,
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    fn new_remote(_id: impl Into<Id>, _dlc: usize) -> Option<Self> {
+        None // Not supported, same as can_queue
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        false
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.len as usize
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Which IDs are waiting in a CAN controller's hardware TX buffers, for a
+/// software TX queue in front of it.
+///
+/// Controllers like the STM32 FDCAN send the highest priority buffer first,
+/// but break ties between equal IDs by buffer index, not by the order the
+/// buffers were written. So a frame mustn't be written to hardware while
+/// another with the same ID is still pending there, or it may overtake it.
+#[derive(Clone, Copy)]
+pub struct TxBuffers<const N: usize> {
+    ids: [Option<Id>; N],
+}
+
+impl<const N: usize> TxBuffers<N> {
+    pub const fn new() -> Self {
+        Self { ids: [None; N] }
+    }
+
+    /// A frame with `id` was written to buffer `index`
+    pub fn on_write(&mut self, index: usize, id: Id) {
+        self.ids[index] = Some(id);
+    }
+
+    /// Update from the controller's transmission request pending bits, one
+    /// per buffer. Buffers which aren't pending any more were sent or aborted.
+    pub fn on_pending(&mut self, pending: u32) {
+        for (index, id) in self.ids.iter_mut().enumerate() {
+            if pending & (1 << index) == 0 {
+                *id = None;
+            }
+        }
+    }
+
+    /// Is a frame with this ID waiting in hardware?
+    pub fn contains(&self, id: Id) -> bool {
+        self.ids.contains(&Some(id))
+    }
+
+    pub fn clear(&mut self) {
+        self.ids = [None; N];
+    }
+}
+
+impl<const N: usize> Default for TxBuffers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fault confinement state of a CAN controller
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum BusState {
+    /// Normal, both error counters below 128
+    ErrorActive,
+    /// An error counter is 128 or over. Still communicating, but something
+    /// is wrong with the bus.
+    ErrorPassive,
+    /// Transmit error counter overflowed, the controller is off the bus
+    /// until it is restarted.
+    BusOff,
+}
+
+/// Health of a CAN bus, as reported by its controller
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct BusStatus {
+    pub state: BusState,
+    /// Transmit Error Counter
+    pub tec: u8,
+    /// Receive Error Counter
+    pub rec: u8,
+    /// Number of times the bus has gone Bus Off since reset
+    pub bus_off_count: u16,
+    /// Received frames have been dropped for several seconds in a row,
+    /// because the app isn't keeping up
+    pub rx_overloaded: bool,
+}
+
+impl BusStatus {
+    pub const fn new() -> Self {
+        Self {
+            state: BusState::ErrorActive,
+            tec: 0,
+            rec: 0,
+            bus_off_count: 0,
+            rx_overloaded: false,
+        }
+    }
+
+    /// Bus is working, but not reliably (or not at all)
+    pub fn is_degraded(&self) -> bool {
+        self.state != BusState::ErrorActive || self.rx_overloaded
+    }
+}
+
+impl Default for BusStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::StandardId;
+
+    const ID: Id = Id::Standard(StandardId::new(0x7E8).unwrap());
+    const OTHER: Id = Id::Standard(StandardId::new(0x100).unwrap());
+
+    #[test]
+    fn tx_buffers_clear_when_not_pending() {
+        let mut buffers = TxBuffers::<3>::new();
+        buffers.on_write(0, ID);
+        buffers.on_write(2, OTHER);
+        buffers.on_pending(0b100);
+        assert!(!buffers.contains(ID));
+        assert!(buffers.contains(OTHER));
+        buffers.clear();
+        assert!(!buffers.contains(OTHER));
+    }
+}
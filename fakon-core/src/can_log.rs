@@ -0,0 +1,232 @@
+//! Compact binary CAN log, for storing a capture in flash to play back onto
+//! the bus.
+//!
+//! Made from a candump or ASC capture with `fakon-sim --convert-log`. The log
+//! is a sequence of records, each one frame:
+//!
+//! - u32 LE milliseconds since the start of the log
+//! - u32 LE ID, with bit 31 set if it's an extended ID
+//! - u8 data length, 0 to 8
+//! - the data bytes
+use crate::Duration;
+use embedded_can::{ExtendedId, Id, StandardId};
+
+const EXTENDED_FLAG: u32 = 1 << 31;
+const HEADER_LEN: usize = 9;
+
+/// A frame in the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record<'a> {
+    /// Time since the start of the log
+    pub offset: Duration,
+    pub id: Id,
+    pub data: &'a [u8],
+}
+
+/// Iterator over the records of a log. Stops early (with an error logged) if
+/// the log is corrupt.
+pub struct Records<'a> {
+    log: &'a [u8],
+}
+
+pub fn records(log: &[u8]) -> Records<'_> {
+    Records { log }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        if self.log.is_empty() {
+            return None;
+        }
+        let record = decode(self.log);
+        match record {
+            Some((record, len)) => {
+                self.log = &self.log[len..];
+                Some(record)
+            }
+            None => {
+                defmt::error!("Corrupt CAN log record, {} bytes left", self.log.len());
+                self.log = &[];
+                None
+            }
+        }
+    }
+}
+
+// Decode the record at the start of `log`, returning it and its length
+fn decode(log: &[u8]) -> Option<(Record<'_>, usize)> {
+    let header = log.get(..HEADER_LEN)?;
+    let offset = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let raw_id = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let len = header[8] as usize;
+    if len > 8 {
+        return None;
+    }
+    let id: Id = if raw_id & EXTENDED_FLAG != 0 {
+        ExtendedId::new(raw_id & !EXTENDED_FLAG)?.into()
+    } else {
+        StandardId::new(raw_id.try_into().ok()?)?.into()
+    };
+    let data = log.get(HEADER_LEN..HEADER_LEN + len)?;
+    let record = Record {
+        offset: Duration::millis(offset),
+        id,
+        data,
+    };
+    Some((record, HEADER_LEN + len))
+}
+
+/// Append a record to a log. Panics if there's more than 8 bytes of data.
+pub fn push_record(log: &mut impl Extend<u8>, record: &Record) {
+    assert!(
+        record.data.len() <= 8,
+        "CAN log only holds classic CAN frames"
+    );
+    let raw_id = match record.id {
+        Id::Standard(id) => id.as_raw().into(),
+        Id::Extended(id) => id.as_raw() | EXTENDED_FLAG,
+    };
+    log.extend(record.offset.to_millis().to_le_bytes());
+    log.extend(raw_id.to_le_bytes());
+    log.extend([record.data.len() as u8]);
+    log.extend(record.data.iter().copied());
+}
+
+/// Filters and modifications applied to a log as it plays back
+#[derive(Clone, Copy, Debug)]
+pub struct PlaybackConfig<'a> {
+    /// Only play back frames with these IDs, or every frame if None
+    pub only: Option<&'a [Id]>,
+    /// IDs which Fakon's own emulation sends instead. Frames in the log with
+    /// these IDs are skipped, and they are the only IDs the emulation is
+    /// allowed to send.
+    pub emulated: &'a [Id],
+    /// Start again from the beginning of the log when it ends
+    pub repeat: bool,
+}
+
+impl PlaybackConfig<'_> {
+    /// Should a frame with this ID in the log be played back?
+    pub fn plays(&self, id: Id) -> bool {
+        !self.emulated.contains(&id) && self.only.is_none_or(|only| only.contains(&id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn std_id(raw: u16) -> Id {
+        StandardId::new(raw).unwrap().into()
+    }
+
+    fn ext_id(raw: u32) -> Id {
+        ExtendedId::new(raw).unwrap().into()
+    }
+
+    fn test_log() -> (Vec<u8>, [Record<'static>; 3]) {
+        let records = [
+            Record {
+                offset: Duration::millis(0),
+                id: std_id(0x7FF),
+                data: &[1, 2, 3],
+            },
+            Record {
+                offset: Duration::millis(10),
+                id: ext_id(0x18DA_F110),
+                data: &[0xAA; 8],
+            },
+            Record {
+                offset: Duration::millis(70_000),
+                id: std_id(0x000),
+                data: &[],
+            },
+        ];
+        let mut log = Vec::new();
+        for record in &records {
+            push_record(&mut log, record);
+        }
+        (log, records)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (log, expected) = test_log();
+        assert_eq!(log.len(), 3 * HEADER_LEN + 3 + 8);
+        // The extended flag is stored in bit 31 of the ID
+        assert_eq!(log[HEADER_LEN + 3 + 4..][..4], [0x10, 0xF1, 0xDA, 0x98]);
+        let decoded: Vec<_> = records(&log).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn empty_log() {
+        assert_eq!(records(&[]).next(), None);
+    }
+
+    #[test]
+    fn truncated_record_stops() {
+        let (log, expected) = test_log();
+        // Cut off in the data of the second record, and in its header
+        for end in [HEADER_LEN + 3 + HEADER_LEN + 4, HEADER_LEN + 3 + 5] {
+            let decoded: Vec<_> = records(&log[..end]).collect();
+            assert_eq!(decoded, expected[..1]);
+        }
+    }
+
+    #[test]
+    fn bad_length_stops() {
+        let (mut log, expected) = test_log();
+        log[HEADER_LEN + 3 + 8] = 9;
+        let decoded: Vec<_> = records(&log).collect();
+        assert_eq!(decoded, expected[..1]);
+    }
+
+    #[test]
+    fn bad_standard_id_stops() {
+        let mut log = Vec::new();
+        log.extend(0u32.to_le_bytes());
+        log.extend(0x800u32.to_le_bytes());
+        log.push(0);
+        assert_eq!(records(&log).next(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn push_too_long() {
+        let mut log = Vec::new();
+        push_record(
+            &mut log,
+            &Record {
+                offset: Duration::millis(0),
+                id: std_id(1),
+                data: &[0; 9],
+            },
+        );
+    }
+
+    #[test]
+    fn playback_filter() {
+        let emulated = [std_id(0x100)];
+        let only = [std_id(0x100), std_id(0x200)];
+        let all = PlaybackConfig {
+            only: None,
+            emulated: &emulated,
+            repeat: false,
+        };
+        assert!(!all.plays(std_id(0x100)));
+        assert!(all.plays(std_id(0x200)));
+        assert!(all.plays(ext_id(0x100)));
+
+        let some = PlaybackConfig {
+            only: Some(&only),
+            ..all
+        };
+        assert!(!some.plays(std_id(0x100)));
+        assert!(some.plays(std_id(0x200)));
+        assert!(!some.plays(std_id(0x300)));
+    }
+}
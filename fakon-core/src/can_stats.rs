@@ -0,0 +1,356 @@
+//! CAN bus statistics: per-ID frame counts and periods, bus load, and the
+//! health of the software queues.
+//!
+//! Used to spot a Kona module which has stopped sending, or Fakon's own
+//! transmit schedule slipping.
+use crate::{Duration, Instant};
+use defmt::Format;
+use embedded_can::Id;
+
+/// Length of the window used to measure bus load
+const LOAD_WINDOW: Duration = Duration::secs(1);
+
+/// Statistics for a single CAN ID, received and transmitted
+#[derive(Clone, Copy)]
+pub struct IdStats {
+    pub id: Id,
+    pub rx: u32,
+    pub tx: u32,
+    last_seen: Instant,
+    /// Smoothed period between frames, in ms
+    pub period_ms: u32,
+    /// Shortest and longest period since the last clear_periods()
+    pub min_period_ms: u32,
+    pub max_period_ms: u32,
+}
+
+impl IdStats {
+    fn new(id: Id, now: Instant) -> Self {
+        Self {
+            id,
+            rx: 0,
+            tx: 0,
+            last_seen: now,
+            period_ms: 0,
+            min_period_ms: u32::MAX,
+            max_period_ms: 0,
+        }
+    }
+
+    fn on_frame(&mut self, now: Instant) {
+        // Received frames are timestamped when they arrived on the bus, so
+        // can be earlier than a frame queued for transmit just before. They
+        // don't count towards the period.
+        let Some(period) = now.checked_duration_since(self.last_seen) else {
+            return;
+        };
+        if self.rx + self.tx > 0 {
+            let period = period.to_millis();
+            self.period_ms = if self.period_ms == 0 {
+                period
+            } else {
+                (self.period_ms * 7 + period) / 8
+            };
+            self.min_period_ms = self.min_period_ms.min(period);
+            self.max_period_ms = self.max_period_ms.max(period);
+        }
+        self.last_seen = now;
+    }
+
+    /// Difference between the longest and shortest period
+    pub fn jitter_ms(&self) -> u32 {
+        self.max_period_ms.saturating_sub(self.min_period_ms)
+    }
+
+    /// Has this ID not been seen for more than 3 of its usual periods?
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.period_ms > 0 && elapsed(now, self.last_seen).to_millis() > self.period_ms * 3
+    }
+}
+
+/// A bus's counters, without the per-ID statistics
+#[derive(Clone, Copy, Debug, Default, Format)]
+pub struct Counters {
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    pub rx_overruns: u32,
+    pub rx_dropped: u32,
+    pub tx_replaced: u32,
+    pub tx_expired: u32,
+    pub tx_dropped: u32,
+    pub untracked: u32,
+    pub tx_queue_high_water: u16,
+    pub load_percent: u8,
+}
+
+/// Statistics for one CAN bus, tracking up to N different IDs
+pub struct BusStats<const N: usize> {
+    ids: [Option<IdStats>; N],
+    /// Frames whose ID didn't fit in the table
+    pub untracked: u32,
+
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    pub rx_overruns: u32,
+    /// Frames dropped because the app's RX queue was full
+    pub rx_dropped: u32,
+    pub tx_queue_high_water: usize,
+    /// Queued frames overwritten by a newer frame with the same ID
+    pub tx_replaced: u32,
+    /// Queued frames dropped because they weren't sent before they expired
+    pub tx_expired: u32,
+    /// Frames dropped because the queue was full of higher priority frames
+    pub tx_dropped: u32,
+
+    bitrate: u32,
+    window_start: Instant,
+    window_bits: u32,
+    /// Bus load over the last complete window, in percent
+    pub load_percent: u8,
+}
+
+impl<const N: usize> BusStats<N> {
+    pub fn new(bitrate: u32) -> Self {
+        Self {
+            ids: [None; N],
+            untracked: 0,
+            rx_frames: 0,
+            tx_frames: 0,
+            rx_overruns: 0,
+            rx_dropped: 0,
+            tx_queue_high_water: 0,
+            tx_replaced: 0,
+            tx_expired: 0,
+            tx_dropped: 0,
+            bitrate,
+            window_start: Instant::from_ticks(0),
+            window_bits: 0,
+            load_percent: 0,
+        }
+    }
+
+    pub fn on_rx(&mut self, id: Id, len: usize, now: Instant) {
+        self.rx_frames += 1;
+        if let Some(stats) = self.on_frame(id, len, now) {
+            stats.rx += 1;
+        }
+    }
+
+    /// Count a frame when it's queued for transmit (so this measures Fakon's
+    /// schedule, not when the frame made it onto the bus.)
+    pub fn on_tx(&mut self, id: Id, len: usize, now: Instant) {
+        self.tx_frames += 1;
+        if let Some(stats) = self.on_frame(id, len, now) {
+            stats.tx += 1;
+        }
+    }
+
+    pub fn on_rx_overrun(&mut self) {
+        self.rx_overruns += 1;
+    }
+
+    pub fn on_rx_dropped(&mut self) {
+        self.rx_dropped += 1;
+    }
+
+    pub fn on_tx_queue_len(&mut self, len: usize) {
+        self.tx_queue_high_water = self.tx_queue_high_water.max(len);
+    }
+
+    pub fn on_tx_replaced(&mut self) {
+        self.tx_replaced += 1;
+    }
+
+    pub fn on_tx_expired(&mut self, count: usize) {
+        self.tx_expired += count as u32;
+    }
+
+    pub fn on_tx_dropped(&mut self) {
+        self.tx_dropped += 1;
+    }
+
+    fn on_frame(&mut self, id: Id, len: usize, now: Instant) -> Option<&mut IdStats> {
+        self.count_bits(id, len, now);
+
+        let idx = match self.ids.iter().position(|s| s.is_some_and(|s| s.id == id)) {
+            Some(idx) => idx,
+            None => match self.ids.iter().position(Option::is_none) {
+                Some(idx) => {
+                    self.ids[idx] = Some(IdStats::new(id, now));
+                    idx
+                }
+                None => {
+                    self.untracked += 1;
+                    return None;
+                }
+            },
+        };
+        let stats = self.ids[idx].as_mut().unwrap();
+        stats.on_frame(now);
+        Some(stats)
+    }
+
+    fn count_bits(&mut self, id: Id, len: usize, now: Instant) {
+        // A frame from before the window started is counted in this one
+        let in_window = elapsed(now, self.window_start);
+        if in_window >= LOAD_WINDOW {
+            let window_bits = self.bitrate as u64 * in_window.to_millis() as u64 / 1000;
+            self.load_percent = (self.window_bits as u64 * 100 / window_bits.max(1)).min(100) as u8;
+            self.window_start = now;
+            self.window_bits = 0;
+        }
+        // Frame length without stuff bits, including interframe space
+        let overhead = match id {
+            Id::Standard(_) => 47,
+            Id::Extended(_) => 67,
+        };
+        self.window_bits += overhead + 8 * len as u32;
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            rx_frames: self.rx_frames,
+            tx_frames: self.tx_frames,
+            rx_overruns: self.rx_overruns,
+            rx_dropped: self.rx_dropped,
+            tx_replaced: self.tx_replaced,
+            tx_expired: self.tx_expired,
+            tx_dropped: self.tx_dropped,
+            untracked: self.untracked,
+            tx_queue_high_water: self.tx_queue_high_water.min(u16::MAX as usize) as u16,
+            load_percent: self.load_percent,
+        }
+    }
+
+    /// Statistics for each ID seen, in the order they were first seen
+    pub fn ids(&self) -> impl Iterator<Item = &IdStats> {
+        self.ids.iter().flatten()
+    }
+
+    /// IDs which were being received regularly but have stopped
+    pub fn overdue(&self, now: Instant) -> impl Iterator<Item = &IdStats> {
+        self.ids().filter(move |s| s.is_overdue(now))
+    }
+
+    /// Restart the min and max period measurements, i.e. after logging them
+    pub fn clear_periods(&mut self) {
+        for stats in self.ids.iter_mut().flatten() {
+            stats.min_period_ms = u32::MAX;
+            stats.max_period_ms = 0;
+        }
+    }
+
+    /// Log a one line summary, plus a line for each overdue ID
+    pub fn log_summary(&self, name: &str, now: Instant) {
+        defmt::info!(
+            "{} RX {} TX {} load {}% RX overruns {} dropped {} TX high water {} replaced {} expired {} dropped {}",
+            name,
+            self.rx_frames,
+            self.tx_frames,
+            self.load_percent,
+            self.rx_overruns,
+            self.rx_dropped,
+            self.tx_queue_high_water,
+            self.tx_replaced,
+            self.tx_expired,
+            self.tx_dropped,
+        );
+        for stats in self.overdue(now) {
+            defmt::warn!(
+                "{} ID {=u32:#x} overdue, last seen {}ms ago (period {}ms)",
+                name,
+                raw_id(stats.id),
+                elapsed(now, stats.last_seen).to_millis(),
+                stats.period_ms
+            );
+        }
+    }
+
+    /// Log every ID's counts and periods
+    pub fn log_ids(&self, name: &str) {
+        for stats in self.ids() {
+            defmt::info!(
+                "{} ID {=u32:#x} RX {} TX {} period {}ms jitter {}ms",
+                name,
+                raw_id(stats.id),
+                stats.rx,
+                stats.tx,
+                stats.period_ms,
+                stats.jitter_ms()
+            );
+        }
+    }
+
+    /// Time since this ID was last seen, if it has been seen
+    pub fn since_last(&self, id: Id, now: Instant) -> Option<Duration> {
+        self.ids()
+            .find(|s| s.id == id)
+            .map(|s| elapsed(now, s.last_seen))
+    }
+}
+
+/// Time from `since` to `now`, or zero if `since` is later. Instants passed
+/// in aren't always in order, see IdStats::on_frame().
+fn elapsed(now: Instant, since: Instant) -> Duration {
+    now.checked_duration_since(since)
+        .unwrap_or(Duration::from_ticks(0))
+}
+
+fn raw_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw().into(),
+        Id::Extended(id) => id.as_raw(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::StandardId;
+
+    const ID: Id = Id::Standard(StandardId::new(0x7D4).unwrap());
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_ticks(ms)
+    }
+
+    #[test]
+    fn period_and_overdue() {
+        let mut stats = BusStats::<4>::new(500_000);
+        for ms in [0, 10, 20, 30] {
+            stats.on_tx(ID, 8, at(ms));
+        }
+        let id = stats.ids().next().unwrap();
+        assert_eq!((id.tx, id.period_ms, id.jitter_ms()), (4, 10, 0));
+        assert!(!id.is_overdue(at(60)));
+        assert!(id.is_overdue(at(61)));
+    }
+
+    #[test]
+    fn rx_time_before_last_tx() {
+        let mut stats = BusStats::<4>::new(500_000);
+        stats.on_tx(ID, 8, at(1000));
+        stats.on_tx(ID, 8, at(1010));
+        // Arrived before the last frame was queued, i.e. a reply read out of
+        // the RX FIFO a little later
+        stats.on_rx(ID, 8, at(1005));
+
+        let id = stats.ids().next().unwrap();
+        assert_eq!((id.rx, id.tx), (1, 2));
+        assert_eq!(
+            (id.period_ms, id.min_period_ms, id.max_period_ms),
+            (10, 10, 10)
+        );
+        assert_eq!(
+            stats.since_last(ID, at(1005)),
+            Some(Duration::from_ticks(0))
+        );
+        assert!(!id.is_overdue(at(1000)));
+
+        // And the load window, which started at the first frame
+        stats.on_rx(ID, 8, at(999));
+        stats.on_tx(ID, 8, at(2000));
+        assert_eq!(stats.rx_frames + stats.tx_frames, 5);
+        stats.log_summary("test", at(900));
+    }
+}
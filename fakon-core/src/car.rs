@@ -0,0 +1,606 @@
+//! Common state of the entire "car" as presented to the Kona
+//! components.
+use crate::bms::BatteryDetail;
+use crate::can::{BusState, BusStatus};
+use crate::dbc::pcan::{
+    BattHvStatus, BattHvStatusPrechargeRelay, Bms542, Bms5a3, InverterStatus, Messages, Obc58e,
+    Vcu200, Vcu200CurrentGear,
+};
+use crate::fresh::{Fresh, IsFresh};
+use crate::time::Clock;
+use crate::Instant;
+use defmt::Format;
+use embedded_can::Id;
+
+/// IDs of the messages which CarState::update_state() uses.
+///
+/// These are also what pcan_receiving() is based on, as PCAN RX is filtered
+/// to these and the handful of other IDs the emulation uses. They're all
+/// periodic: Vcu200 and InverterStatus every 10ms while the VCU and inverter
+/// are awake, the BMS messages while the BMS is awake, and Obc58e while the
+/// OBC is awake (including when only charging). So this also works as the
+/// list of messages that show the powertrain is on the bus.
+pub const CAN_RX_IDS: &[Id] = &[
+    Bms5a3::MESSAGE_ID,
+    BattHvStatus::MESSAGE_ID,
+    Bms542::MESSAGE_ID,
+    Obc58e::MESSAGE_ID,
+    InverterStatus::MESSAGE_ID,
+    Vcu200::MESSAGE_ID,
+];
+
+/// IDs which mustn't be dropped if PCAN RX is overloaded: contactor state
+pub const CAN_RX_PRIORITY_IDS: &[Id] = &[Bms5a3::MESSAGE_ID];
+
+#[derive(Clone, Format)]
+pub struct CarState<C: Clock> {
+    /// Main ignition power state. Updated from hard wired inputs.
+    ignition: Ignition,
+
+    /// The "most on" that the car has been since reset
+    most_on: Ignition,
+
+    /// Main high voltage contactor state. Updated from BMS whenever IG1 or IG3 is on.
+    contactor: Fresh<Contactor, 3, C>,
+
+    /// Debounced and de-inverted level of EV Ready input
+    ev_ready_input: bool,
+
+    charge_port: ChargeLock,
+    is_braking: bool,
+
+    gear: Fresh<Gear, 3, C>,
+
+    soc_batt: f32,
+    v_batt: f32,
+    i_batt: f32,
+    v_inverter: Fresh<u16, 3, C>,
+    motor_rpm: Fresh<u16, 1, C>,
+
+    /// Cell level data polled from the BMS over UDS, see bms::poll_detail()
+    battery_detail: Fresh<BatteryDetail, 30, C>,
+
+    // Internal state of pre-charge relay. Used to update 'contactor' field. Updated from BMS.
+    last_precharge: Fresh<bool, 3, C>,
+
+    /// Connected EVSE detected by OBC. Doubles as tracker for OBC powered on
+    evse_detected: Fresh<bool, 3, C>,
+
+    /// Timestamp of last time a valid CAN message was received via PCAN
+    last_pcan_rx: Option<Instant>,
+
+    /// PCAN controller state, reported by the CAN driver
+    pcan_status: BusStatus,
+
+    /// Diagnostics asked for the IG3 relay to be switched off and on again
+    ig3_cycle_requested: bool,
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Ignition {
+    /// Car is off, and will probably transition to sleep soon
+    Off,
+    /// Car is partially on for charging (IG3 relay), but not to drive
+    IG3,
+    /// Car is fully on with key in ignition (both IG1 and IG3 relays)
+    On,
+}
+
+/// Map the state of the charge port lock actuator
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum ChargeLock {
+    Unlocked,
+    Locked,
+}
+
+impl Ignition {
+    /// Return true if IG3 is on (with or without IG1)
+    pub fn ig3_on(&self) -> bool {
+        !matches!(self, Ignition::Off)
+    }
+}
+
+/// High Voltage Contactor state
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Contactor {
+    /// Contactors are open (i.e. high voltage isolated)
+    Open,
+    /// Contactors are pre-charging the inverter
+    PreCharging,
+    /// Contactors are fully closed and HV is fully on
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Gear {
+    Park,
+    Neutral,
+    Drive,
+    Reverse,
+}
+
+impl TryFrom<Vcu200CurrentGear> for Gear {
+    type Error = ();
+
+    fn try_from(value: Vcu200CurrentGear) -> Result<Self, Self::Error> {
+        match value {
+            Vcu200CurrentGear::P => Ok(Self::Park),
+            Vcu200CurrentGear::D => Ok(Self::Drive),
+            Vcu200CurrentGear::N => Ok(Self::Neutral),
+            Vcu200CurrentGear::R => Ok(Self::Reverse),
+            Vcu200CurrentGear::_Other(_) => Err(()),
+        }
+    }
+}
+
+impl<C: Clock> CarState<C> {
+    pub fn new() -> Self {
+        // Note this is where all of the stale timeouts for the Fresh values are set
+        Self {
+            ignition: Ignition::Off,
+            most_on: Ignition::On,
+            contactor: Fresh::new(),
+            ev_ready_input: false,
+            charge_port: ChargeLock::Unlocked,
+            is_braking: false,
+
+            gear: Fresh::new(),
+
+            soc_batt: 0.0,
+            v_batt: 0.0,
+            i_batt: 0.0,
+            v_inverter: Fresh::new(),
+            motor_rpm: Fresh::new(),
+            battery_detail: Fresh::new(),
+
+            last_precharge: Fresh::new(),
+            evse_detected: Fresh::new(),
+            last_pcan_rx: None,
+            pcan_status: BusStatus::new(),
+            ig3_cycle_requested: false,
+        }
+    }
+
+    #[inline]
+    pub fn ignition(&self) -> Ignition {
+        self.ignition
+    }
+
+    #[inline]
+    /// Return the contactor state.
+    pub fn contactor(&self) -> impl IsFresh<Contactor> {
+        self.contactor
+    }
+
+    #[inline]
+    pub fn v_inverter(&self) -> impl IsFresh<u16> {
+        self.v_inverter
+    }
+
+    #[inline]
+    pub fn motor_rpm(&self) -> impl IsFresh<u16> {
+        self.motor_rpm
+    }
+
+    #[inline]
+    pub fn soc_batt(&self) -> f32 {
+        self.soc_batt
+    }
+
+    #[inline]
+    pub fn battery_detail(&self) -> impl IsFresh<BatteryDetail> {
+        self.battery_detail
+    }
+
+    pub fn set_battery_detail(&mut self, value: BatteryDetail) {
+        self.battery_detail.set(value);
+    }
+
+    #[inline]
+    pub fn gear(&self) -> impl IsFresh<Gear> {
+        self.gear
+    }
+
+    #[inline]
+    pub fn evse_detected(&self) -> impl IsFresh<bool> {
+        self.evse_detected
+    }
+
+    pub fn set_ignition(&mut self, value: Ignition) {
+        if value != self.ignition {
+            defmt::info!("Ignition => {}", value);
+            self.ignition = value;
+        }
+        // Update the lifetime "most on" value
+        self.most_on = match (self.most_on, value) {
+            (Ignition::Off, Ignition::IG3) => Ignition::IG3,
+            (_, Ignition::On) => Ignition::On,
+            (existing, _) => existing,
+        };
+    }
+
+    #[inline]
+    pub fn is_braking(&self) -> bool {
+        self.is_braking
+    }
+
+    #[inline]
+    pub fn set_is_braking(&mut self, value: bool) {
+        if value != self.is_braking {
+            defmt::info!("Braking => {}", value);
+            self.is_braking = value;
+        }
+    }
+
+    #[inline]
+    pub fn ev_ready(&self) -> bool {
+        // As this is an active low input, it's also low when VCU is off
+        self.ev_ready_input && self.v_inverter.is_fresh()
+    }
+
+    #[inline]
+    pub fn set_ev_ready_input(&mut self, value: bool) {
+        if value != self.ev_ready_input {
+            defmt::info!("EV Ready => {}", value);
+            self.ev_ready_input = value;
+        }
+    }
+
+    #[inline]
+    pub fn charge_port(&self) -> ChargeLock {
+        self.charge_port
+    }
+
+    #[inline]
+    pub fn set_charge_port(&mut self, value: ChargeLock) {
+        if value != self.charge_port {
+            defmt::info!("Charge Port => {}", value);
+            self.charge_port = value;
+        }
+    }
+
+    // Return the "most on" that the ignition has been since reset
+    #[inline]
+    pub(crate) fn most_on(&self) -> Ignition {
+        self.most_on
+    }
+
+    pub fn set_evse_detected(&mut self, value: bool, at: Instant) {
+        self.evse_detected.set_at(value, at);
+    }
+
+    /// Update the timestamp of the last PCAN message received from the
+    /// powertrain. update_state() calls this for each message, i.e. for the
+    /// IDs in CAN_RX_IDS.
+    pub fn set_last_pcan_rx(&mut self, at: Instant) {
+        self.last_pcan_rx = Some(at);
+    }
+
+    /// Until we have IG3 direct monitoring, use "OBC is sending messages" as a proxy
+    pub fn ig3_appears_powered(&self) -> bool {
+        // evse_detected is for OBC
+        self.evse_detected.is_fresh()
+    }
+
+    /// True if one of the CAN_RX_IDS messages arrived in the last 2 seconds
+    pub fn pcan_receiving(&self) -> bool {
+        self.last_pcan_rx
+            .and_then(|val| C::now().checked_duration_since(val))
+            .is_some_and(|age| age.to_secs() < 2)
+    }
+
+    /// Update the PCAN controller state, logging any change of state
+    pub fn set_pcan_status(&mut self, status: BusStatus) {
+        if status.state != self.pcan_status.state {
+            if status.state == BusState::ErrorActive {
+                defmt::info!("PCAN => {}", status);
+            } else {
+                defmt::warn!("PCAN => {}", status);
+            }
+        }
+        if status.rx_overloaded != self.pcan_status.rx_overloaded {
+            if status.rx_overloaded {
+                defmt::error!("PCAN RX overloaded, dropping frames");
+            } else {
+                defmt::info!("PCAN RX no longer overloaded");
+            }
+        }
+        self.pcan_status = status;
+    }
+
+    /// Ask ignition_sequence to switch the IG3 relay off and on again, i.e.
+    /// to restart the modules it powers. Only happens if the ignition is on.
+    pub fn request_ig3_cycle(&mut self) {
+        self.ig3_cycle_requested = true;
+    }
+
+    /// Has an IG3 cycle been requested? Clears the request.
+    pub fn take_ig3_cycle_request(&mut self) -> bool {
+        core::mem::replace(&mut self.ig3_cycle_requested, false)
+    }
+
+    pub fn pcan_status(&self) -> BusStatus {
+        self.pcan_status
+    }
+
+    pub fn pcan_bus_off(&self) -> bool {
+        self.pcan_status.state == BusState::BusOff
+    }
+
+    // Contactor state only updates in response to BMS CAN messages
+    fn set_contactor(&mut self, new_state: Contactor, at: Instant) {
+        if self.contactor.get() != Some(new_state) {
+            // Log contactor transitions, including warnings for unexpected
+            // ones
+            if matches!(
+                (self.contactor.get_unchecked(), new_state),
+                (None, Contactor::Closed) // Firmware reset w/ contactors closed
+                    | (None, Contactor::PreCharging) // Firmware reset in precharge state
+                    | (Some(Contactor::Open), Contactor::Closed) // Skipped precharge
+                    | (Some(Contactor::PreCharging), Contactor::Open) // Precharge failed
+                    | (Some(Contactor::Closed), Contactor::PreCharging) // ???
+            ) {
+                defmt::warn!(
+                    "Unexpected main contactors {:?} => {:?} inverter {:?} V",
+                    self.contactor,
+                    new_state,
+                    self.v_inverter
+                );
+            } else {
+                defmt::info!(
+                    "Main contactors {:?} => {:?} inverter {:?} V",
+                    self.contactor,
+                    new_state,
+                    self.v_inverter
+                );
+            }
+        }
+        // Always call set here to mark freshness of the value
+        self.contactor.set_at(new_state, at);
+    }
+
+    /// Update from a received PCAN message. `rx_time` is when the frame
+    /// arrived on the bus, which may be a little before now.
+    pub fn update_state(&mut self, pcan_msg: &Messages, rx_time: Instant) {
+        match pcan_msg {
+            Messages::Bms5a3(msg) => {
+                if self.contactor.is_fresh() && self.last_precharge.is_stale() {
+                    // We've received more than one of this message recently, but none of
+                    // the higher priority BmsHvMonitor message with the precharge state
+                    defmt::error!("BMS is sending contactor state without precharge state");
+                } else {
+                    let contactor_closed = msg.contactor_closed();
+                    let precharging = self.last_precharge.get().unwrap_or(false);
+                    let new_state = match (contactor_closed, precharging) {
+                        (true, _) => Contactor::Closed,
+                        (false, true) => Contactor::PreCharging,
+                        (false, false) => Contactor::Open,
+                    };
+                    self.set_contactor(new_state, rx_time);
+                }
+            }
+            Messages::BattHvStatus(msg) => {
+                // Pre-charge relay state
+                {
+                    // Don't update the contactor state here, wait for the next Bms5a3 message
+                    // and switch it there (this is to avoid races when switching in and out of
+                    // pre-charge
+                    self.last_precharge.set_at(
+                        msg.precharge_relay() == BattHvStatusPrechargeRelay::Closed,
+                        rx_time,
+                    );
+                }
+
+                // Raw battery stats
+                {
+                    self.v_batt = msg.v_batt();
+                    self.i_batt = msg.i_batt();
+                }
+            }
+            Messages::Bms542(msg) => {
+                // Recording the "display SoC" not the "real SoC" i.e. as shown on dash
+                self.soc_batt = msg.soc_disp()
+            }
+            Messages::Obc58e(msg) => {
+                self.set_evse_detected(msg.evse_detected(), rx_time);
+            }
+            Messages::InverterStatus(msg) => {
+                // as these two have the same "freshness" they could conceivably be merged somehow
+                self.v_inverter.set_at(msg.v_inverter(), rx_time);
+                self.motor_rpm.set_at(msg.speed_abs() as u16, rx_time);
+            }
+            Messages::Vcu200(msg) => {
+                if let Ok(gear) = msg.current_gear().try_into() {
+                    self.gear.set_at(gear, rx_time);
+                } else if self.ignition().ig3_on() {
+                    defmt::warn!(
+                        "VCU200 message sent invalid gear value {}",
+                        msg.current_gear_raw()
+                    );
+                }
+            }
+            _ => (),
+        }
+        self.set_last_pcan_rx(rx_time);
+    }
+}
+
+impl<C: Clock> Default for CarState<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChargeLock {
+    pub fn is_locked(&self) -> bool {
+        return *self == Self::Locked;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::step_clock;
+    use crate::time::StepClock;
+    use crate::Duration;
+
+    fn batt_hv_status(precharging: bool) -> Messages {
+        let mut msg = BattHvStatus::try_from([0u8; BattHvStatus::DLC as usize].as_slice()).unwrap();
+        if precharging {
+            msg.set_precharge_relay(BattHvStatusPrechargeRelay::Closed.into())
+                .unwrap();
+        }
+        Messages::BattHvStatus(msg)
+    }
+
+    fn bms5a3(closed: bool) -> Messages {
+        let mut msg = Bms5a3::try_from([0u8; Bms5a3::DLC as usize].as_slice()).unwrap();
+        msg.set_contactor_closed(closed).unwrap();
+        Messages::Bms5a3(msg)
+    }
+
+    // The BMS sends its precharge relay state, then its contactor state
+    fn bms_update(car: &mut CarState<StepClock>, precharging: bool, closed: bool) {
+        let now = StepClock::now();
+        car.update_state(&batt_hv_status(precharging), now);
+        car.update_state(&bms5a3(closed), now);
+    }
+
+    #[test]
+    fn contactor_precharge_sequence() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        assert_eq!(car.contactor().get(), None);
+
+        bms_update(&mut car, false, false);
+        assert_eq!(car.contactor().get(), Some(Contactor::Open));
+
+        StepClock::step(Duration::millis(100));
+        bms_update(&mut car, true, false);
+        assert_eq!(car.contactor().get(), Some(Contactor::PreCharging));
+
+        StepClock::step(Duration::millis(100));
+        bms_update(&mut car, false, true);
+        assert_eq!(car.contactor().get(), Some(Contactor::Closed));
+
+        StepClock::step(Duration::millis(100));
+        bms_update(&mut car, false, false);
+        assert_eq!(car.contactor().get(), Some(Contactor::Open));
+    }
+
+    #[test]
+    fn contactor_waits_for_bms5a3() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        bms_update(&mut car, false, false);
+
+        // Precharge relay state alone doesn't change the contactor state
+        StepClock::step(Duration::millis(100));
+        car.update_state(&batt_hv_status(true), StepClock::now());
+        assert_eq!(car.contactor().get(), Some(Contactor::Open));
+
+        car.update_state(&bms5a3(false), StepClock::now());
+        assert_eq!(car.contactor().get(), Some(Contactor::PreCharging));
+    }
+
+    #[test]
+    fn contactor_ignored_without_precharge_state() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        bms_update(&mut car, false, false);
+
+        // Bms5a3 keeps the contactor state fresh, but BattHvStatus stops
+        StepClock::step(Duration::millis(2000));
+        car.update_state(&bms5a3(false), StepClock::now());
+        StepClock::step(Duration::millis(1500));
+        car.update_state(&bms5a3(true), StepClock::now());
+        assert_eq!(car.contactor().get(), Some(Contactor::Open));
+    }
+
+    #[test]
+    fn pcan_receiving_goes_stale() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        assert!(!car.pcan_receiving());
+
+        StepClock::step(Duration::millis(100));
+        car.update_state(&bms5a3(false), StepClock::now() - Duration::millis(10));
+        assert!(car.pcan_receiving());
+
+        StepClock::step(Duration::millis(1989));
+        assert!(car.pcan_receiving());
+        StepClock::step(Duration::millis(1));
+        assert!(!car.pcan_receiving());
+
+        // A timestamp from the future isn't receiving yet, and doesn't panic
+        car.set_last_pcan_rx(StepClock::now() + Duration::millis(5));
+        assert!(!car.pcan_receiving());
+    }
+
+    #[test]
+    fn contactor_goes_stale() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        bms_update(&mut car, false, true);
+
+        StepClock::step(Duration::millis(2999));
+        assert_eq!(car.contactor().get(), Some(Contactor::Closed));
+        StepClock::step(Duration::millis(1));
+        assert_eq!(car.contactor().get(), None);
+        assert_eq!(car.contactor().get_unchecked(), Some(Contactor::Closed));
+    }
+
+    #[test]
+    fn gear_goes_stale() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        let mut msg = Vcu200::try_from([0u8; Vcu200::DLC as usize].as_slice()).unwrap();
+        msg.set_current_gear(Vcu200CurrentGear::D.into()).unwrap();
+        car.update_state(&Messages::Vcu200(msg), StepClock::now());
+
+        StepClock::step(Duration::millis(2999));
+        assert_eq!(car.gear().get(), Some(Gear::Drive));
+        StepClock::step(Duration::millis(1));
+        assert_eq!(car.gear().get(), None);
+    }
+
+    #[test]
+    fn evse_detected_goes_stale() {
+        let _clock = step_clock();
+        let mut car = CarState::<StepClock>::new();
+        let mut msg = Obc58e::try_from([0u8; Obc58e::DLC as usize].as_slice()).unwrap();
+        msg.set_evse_detected(true).unwrap();
+
+        // Freshness counts from when the frame arrived, not when it's handled
+        StepClock::step(Duration::millis(1000));
+        car.update_state(&Messages::Obc58e(msg), Instant::from_ticks(990));
+        assert_eq!(car.evse_detected().get(), Some(true));
+        assert!(car.ig3_appears_powered());
+
+        StepClock::step(Duration::millis(2989));
+        assert_eq!(car.evse_detected().get(), Some(true));
+        StepClock::step(Duration::millis(1));
+        assert_eq!(car.evse_detected().get(), None);
+        assert!(!car.ig3_appears_powered());
+    }
+
+    #[test]
+    fn ignition_most_on() {
+        let mut car = CarState::<StepClock>::new();
+        // Assumes the car was on before a reset
+        assert_eq!(car.most_on(), Ignition::On);
+
+        car.most_on = Ignition::Off;
+        car.set_ignition(Ignition::IG3);
+        assert_eq!(car.ignition(), Ignition::IG3);
+        assert_eq!(car.most_on(), Ignition::IG3);
+
+        car.set_ignition(Ignition::Off);
+        assert_eq!(car.most_on(), Ignition::IG3);
+
+        car.set_ignition(Ignition::On);
+        car.set_ignition(Ignition::IG3);
+        assert_eq!(car.ignition(), Ignition::IG3);
+        assert_eq!(car.most_on(), Ignition::On);
+    }
+}
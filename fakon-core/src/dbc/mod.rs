@@ -0,0 +1,3 @@
+//! This module is a namespace wrapper for CAN message types generated from DBC
+//! by build.rs. See comment in build.rs. Other files in this dir are .gitignored.
+pub mod pcan;
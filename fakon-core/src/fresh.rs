@@ -0,0 +1,172 @@
+use core::marker::PhantomData;
+use defmt::Format;
+
+use crate::time::Clock;
+use crate::Instant;
+
+/// Struct to wrap a periodic signal value that can be "fresh" or "stale"
+#[derive(Clone, Copy)]
+pub struct Fresh<VALUE, const STALE_SECS: u32, C>
+where
+    VALUE: Copy,
+    C: Clock,
+{
+    value: Option<(Instant, VALUE)>, // (Last Set, Value)
+    _clock: PhantomData<C>,
+}
+
+// Using a trait here allows return types to be "impl IsFresh<V>" instead of
+// "Fresh<V, SECS, C>" which leaks the const parameter out unnecessarily.
+//
+// Making Format a supertrait here is semi-laziness so we can have
+// functions "-> impl IsFresh<V>" instead of "-> impl IsFresh<V> + Format"
+pub trait IsFresh<VALUE>: Format + Copy {
+    /// Set the value and update its last set timestamp
+    fn set(&mut self, value: VALUE);
+
+    /// Set the value as of an earlier time, i.e. when the CAN frame carrying
+    /// it was received. `at` shouldn't be in the future, if it is then the
+    /// value is stale until then.
+    fn set_at(&mut self, value: VALUE, at: Instant);
+
+    /// Get a reference to the value, or None if it's stale or was never set.
+    fn get(&self) -> Option<VALUE>;
+
+    /// Get the last set value, even if it is stale
+    fn get_unchecked(&self) -> Option<VALUE>;
+
+    /// Return true if the value is fresh (i.e. was last set within the stale_after period)
+    fn is_fresh(&self) -> bool;
+
+    /// Return true if the value is stale (i.e. not fresh)
+    #[inline]
+    fn is_stale(&self) -> bool {
+        !self.is_fresh()
+    }
+}
+
+impl<VALUE, const STALE_SECS: u32, C> Fresh<VALUE, STALE_SECS, C>
+where
+    VALUE: Copy,
+    C: Clock,
+{
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            value: None,
+            _clock: PhantomData,
+        }
+    }
+}
+
+impl<VALUE, const STALE_SECS: u32, C> IsFresh<VALUE> for Fresh<VALUE, STALE_SECS, C>
+where
+    VALUE: Copy + Format,
+    C: Clock,
+{
+    fn set(&mut self, value: VALUE) {
+        self.set_at(value, C::now());
+    }
+
+    fn set_at(&mut self, value: VALUE, at: Instant) {
+        self.value = Some((at, value));
+    }
+
+    fn get(&self) -> Option<VALUE> {
+        if self.is_fresh() {
+            self.get_unchecked()
+        } else {
+            None // Value never set
+        }
+    }
+
+    fn get_unchecked(&self) -> Option<VALUE> {
+        self.value.map(|(_, value)| value)
+    }
+
+    fn is_fresh(&self) -> bool {
+        // A value set in the future (which shouldn't happen) is stale
+        self.value.is_some_and(|(last_set, _)| {
+            C::now()
+                .checked_duration_since(last_set)
+                .is_some_and(|age| age.to_secs() < STALE_SECS)
+        })
+    }
+}
+
+impl<VALUE, const STALE_SECS: u32, C> Format for Fresh<VALUE, STALE_SECS, C>
+where
+    VALUE: Copy + Format,
+    C: Clock,
+{
+    fn format(&self, fmt: defmt::Formatter) {
+        match &self.value {
+            Some((_, value)) => {
+                if self.is_fresh() {
+                    defmt::write!(fmt, "{:?}", value);
+                } else {
+                    defmt::write!(fmt, "Stale({:?})", value);
+                }
+            }
+            None => defmt::write!(fmt, "Stale(None)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::step_clock;
+    use crate::time::StepClock;
+    use crate::Duration;
+
+    #[test]
+    fn stale_until_set() {
+        let _clock = step_clock();
+        let value = Fresh::<u8, 3, StepClock>::new();
+        assert!(value.is_stale());
+        assert_eq!(value.get(), None);
+        assert_eq!(value.get_unchecked(), None);
+    }
+
+    #[test]
+    fn stale_after_period() {
+        let _clock = step_clock();
+        let mut value = Fresh::<u8, 3, StepClock>::new();
+        StepClock::step(Duration::millis(500));
+        value.set(7);
+
+        StepClock::step(Duration::millis(2999));
+        assert_eq!(value.get(), Some(7));
+        StepClock::step(Duration::millis(1));
+        assert!(value.is_stale());
+        assert_eq!(value.get(), None);
+        assert_eq!(value.get_unchecked(), Some(7));
+
+        // Setting it again makes it fresh again
+        value.set(8);
+        assert_eq!(value.get(), Some(8));
+    }
+
+    #[test]
+    fn set_at_earlier_time() {
+        let _clock = step_clock();
+        let mut value = Fresh::<u8, 1, StepClock>::new();
+        StepClock::step(Duration::millis(2000));
+        value.set_at(1, Instant::from_ticks(1100));
+        assert!(value.is_fresh());
+        StepClock::step(Duration::millis(100));
+        assert!(value.is_stale());
+    }
+
+    #[test]
+    fn future_value_is_stale() {
+        let _clock = step_clock();
+        let mut value = Fresh::<u8, 3, StepClock>::new();
+        StepClock::step(Duration::millis(100));
+        value.set_at(1, Instant::from_ticks(150));
+        assert!(value.is_stale());
+        StepClock::step(Duration::millis(50));
+        assert!(value.is_fresh());
+    }
+}
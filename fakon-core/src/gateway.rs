@@ -0,0 +1,260 @@
+//! Gateway between PCAN and another CAN bus, as the real IGPM is.
+//!
+//! A transplant often has a second network (the donor vehicle, an instrument
+//! cluster, or COMP CAN) which needs some of the Kona powertrain's messages,
+//! or has to supply some. Each direction has a table of Routes, and each
+//! received frame which matches a Route is forwarded to the other bus:
+//!
+//! ```ignore
+//! pub const PCAN_TO_COMPCAN: &[Route] = &[
+//!     // State of charge, to a cluster which expects it at 0x3F0 and 5Hz
+//!     Route::new(Bms5a3::MESSAGE_ID)
+//!         .remap(Id::Standard(StandardId::new(0x3F0).unwrap()))
+//!         .rate_limit(Duration::millis(200)),
+//!     // Gear, with the signals rearranged for the other vehicle
+//!     Route::new(Vcu109::MESSAGE_ID).translate(translate_gear),
+//! ];
+//! ```
+//!
+//! Both tables are empty by default, as the routes depend on the vehicle.
+use crate::can::{CanTx, ClassicFrame};
+use crate::{Duration, Instant};
+use embedded_can::{Frame, Id};
+use rtic_core::Mutex;
+
+/// Frames from PCAN to forward to COMP CAN
+pub const PCAN_TO_COMPCAN: &[Route] = &[];
+
+/// Frames from COMP CAN to forward to PCAN
+pub const COMPCAN_TO_PCAN: &[Route] = &[];
+
+/// Most routes in one direction
+pub const MAX_ROUTES: usize = 16;
+
+/// Rule for forwarding one ID
+#[derive(Clone, Copy, Debug)]
+pub struct Route {
+    /// ID received on the source bus
+    pub id: Id,
+    /// ID to send it as on the destination bus, None to keep the same ID
+    pub remap: Option<Id>,
+    /// Forward at most one frame per period, None for no limit
+    pub min_period: Option<Duration>,
+    /// Rewrite the frame data, i.e. to translate signals between the two
+    /// networks' encodings. Returns false to drop the frame.
+    pub translate: Option<fn(&mut [u8]) -> bool>,
+}
+
+impl Route {
+    /// Forward frames with this ID unchanged
+    pub const fn new(id: Id) -> Self {
+        Self {
+            id,
+            remap: None,
+            min_period: None,
+            translate: None,
+        }
+    }
+
+    pub const fn remap(mut self, to: Id) -> Self {
+        self.remap = Some(to);
+        self
+    }
+
+    /// Frames which arrive less than `min_period` after the last forwarded
+    /// one are dropped. Only suits periodic messages, where the next frame
+    /// carries the latest value.
+    pub const fn rate_limit(mut self, min_period: Duration) -> Self {
+        self.min_period = Some(min_period);
+        self
+    }
+
+    pub const fn translate(mut self, translate: fn(&mut [u8]) -> bool) -> Self {
+        self.translate = Some(translate);
+        self
+    }
+}
+
+/// Forwards frames in one direction, according to a table of Routes
+pub struct Gateway {
+    routes: &'static [Route],
+    last_forwarded: [Option<Instant>; MAX_ROUTES],
+    /// Frames forwarded to the destination bus
+    pub forwarded: u32,
+    /// Frames dropped by a rate limit or translation
+    pub dropped: u32,
+}
+
+impl Gateway {
+    pub fn new(routes: &'static [Route]) -> Self {
+        assert!(routes.len() <= MAX_ROUTES, "too many gateway routes");
+        Self {
+            routes,
+            last_forwarded: [None; MAX_ROUTES],
+            forwarded: 0,
+            dropped: 0,
+        }
+    }
+
+    /// IDs which need to be received on the source bus
+    pub fn ids(&self) -> impl Iterator<Item = Id> + '_ {
+        self.routes.iter().map(|route| route.id)
+    }
+
+    /// Handle a frame received on the source bus, forwarding it to `dest` if
+    /// it matches a Route. Returns true if it was forwarded.
+    pub fn on_rx<MTX, TX>(&mut self, frame: &impl Frame, now: Instant, dest: &mut MTX) -> bool
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        let Some(idx) = self.routes.iter().position(|route| route.id == frame.id()) else {
+            return false;
+        };
+        let route = &self.routes[idx];
+
+        if let (Some(min_period), Some(last)) = (route.min_period, self.last_forwarded[idx]) {
+            // A timestamp earlier than the last one (i.e. frames from the two
+            // RX FIFOs handled out of order) isn't rate limited
+            if now
+                .checked_duration_since(last)
+                .is_some_and(|since| since < min_period)
+            {
+                self.dropped += 1;
+                return false;
+            }
+        }
+
+        let Some(mut out) = ClassicFrame::new(route.remap.unwrap_or(route.id), frame.data())
+        else {
+            // CAN FD, no route can forward these yet
+            self.dropped += 1;
+            return false;
+        };
+        if let Some(translate) = route.translate {
+            if !translate(out.data_mut()) {
+                self.dropped += 1;
+                return false;
+            }
+        }
+
+        dest.lock(|tx| tx.transmit(&out));
+        self.last_forwarded[idx] = Some(now);
+        self.forwarded += 1;
+        true
+    }
+
+    /// Log the counters, unless there are no routes in this direction
+    pub fn log_summary(&self, name: &str) {
+        if self.routes.is_empty() {
+            return;
+        }
+        defmt::info!(
+            "{} gateway forwarded {} dropped {}",
+            name,
+            self.forwarded,
+            self.dropped
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{RecordTx, Shared};
+    use embedded_can::StandardId;
+    use std::vec;
+
+    const fn id(raw: u16) -> Id {
+        Id::Standard(StandardId::new(raw).unwrap())
+    }
+
+    fn invert(data: &mut [u8]) -> bool {
+        data[0] = !data[0];
+        true
+    }
+
+    fn only_nonzero(data: &mut [u8]) -> bool {
+        data[0] != 0
+    }
+
+    static ROUTES: &[Route] = &[
+        Route::new(id(0x100)),
+        Route::new(id(0x200)).remap(id(0x3F0)),
+        Route::new(id(0x300)).rate_limit(Duration::millis(200)),
+        Route::new(id(0x400)).translate(invert),
+        Route::new(id(0x500)).translate(only_nonzero),
+    ];
+
+    fn frame(raw: u16, data: &[u8]) -> ClassicFrame {
+        ClassicFrame::new(id(raw), data).unwrap()
+    }
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_ticks(ms)
+    }
+
+    #[test]
+    fn unrouted_ignored() {
+        let mut gateway = Gateway::new(ROUTES);
+        let mut dest = Shared::new(RecordTx::default());
+        assert!(!gateway.on_rx(&frame(0x101, &[1]), at(0), &mut dest));
+        assert!(dest.lock(|tx| tx.frames.is_empty()));
+        assert_eq!((gateway.forwarded, gateway.dropped), (0, 0));
+    }
+
+    #[test]
+    fn forward_and_remap() {
+        let mut gateway = Gateway::new(ROUTES);
+        let mut dest = Shared::new(RecordTx::default());
+        assert!(gateway.on_rx(&frame(0x100, &[1, 2]), at(0), &mut dest));
+        assert!(gateway.on_rx(&frame(0x200, &[3]), at(0), &mut dest));
+        assert_eq!(
+            dest.lock(|tx| core::mem::take(&mut tx.frames)),
+            vec![(id(0x100), vec![1, 2]), (id(0x3F0), vec![3])]
+        );
+        assert_eq!(gateway.forwarded, 2);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut gateway = Gateway::new(ROUTES);
+        let mut dest = Shared::new(RecordTx::default());
+        let limited = frame(0x300, &[0]);
+        assert!(gateway.on_rx(&limited, at(1000), &mut dest));
+        assert!(!gateway.on_rx(&limited, at(1100), &mut dest));
+        assert!(!gateway.on_rx(&limited, at(1199), &mut dest));
+        assert!(gateway.on_rx(&limited, at(1200), &mut dest));
+        // An earlier timestamp than the last forwarded frame doesn't panic,
+        // and isn't limited
+        assert!(gateway.on_rx(&limited, at(1150), &mut dest));
+        assert_eq!((gateway.forwarded, gateway.dropped), (3, 2));
+        // Other routes aren't affected
+        assert!(gateway.on_rx(&frame(0x100, &[0]), at(1150), &mut dest));
+    }
+
+    #[test]
+    fn translate() {
+        let mut gateway = Gateway::new(ROUTES);
+        let mut dest = Shared::new(RecordTx::default());
+        assert!(gateway.on_rx(&frame(0x400, &[0x0F, 9]), at(0), &mut dest));
+        assert!(!gateway.on_rx(&frame(0x500, &[0]), at(0), &mut dest));
+        assert!(gateway.on_rx(&frame(0x500, &[1]), at(0), &mut dest));
+        assert_eq!(
+            dest.lock(|tx| core::mem::take(&mut tx.frames)),
+            vec![(id(0x400), vec![0xF0, 9]), (id(0x500), vec![1])]
+        );
+        assert_eq!((gateway.forwarded, gateway.dropped), (2, 1));
+    }
+
+    #[test]
+    fn dropped_frame_not_rate_limited() {
+        static LIMITED: &[Route] = &[Route::new(id(0x500))
+            .rate_limit(Duration::millis(200))
+            .translate(only_nonzero)];
+        let mut gateway = Gateway::new(LIMITED);
+        let mut dest = Shared::new(RecordTx::default());
+        assert!(!gateway.on_rx(&frame(0x500, &[0]), at(0), &mut dest));
+        assert!(gateway.on_rx(&frame(0x500, &[1]), at(10), &mut dest));
+    }
+}
@@ -3,23 +3,25 @@
 //! Also manages traction control and vehicle stability control messages. Most of this
 //! is spoofed, the VCU's perspective should be that it's forever driving in a straight
 //! line down a road with perfect traction...
-use crate::app;
+use crate::can::CanTx;
 use crate::car::{CarState, Ignition};
 use crate::dbc::pcan::{
     Ieb2a2, Ieb331, Ieb386Wheel, Ieb387Wheel, Ieb507Tcs, ParkingBrake, StabilityControl,
     TractionControlFast, TractionControlMed,
 };
-use crate::hardware::Mono;
 use crate::repeater::{Period, Repeater};
+use crate::time::Clock;
 use fugit::ExtU32;
 use hex_literal::hex;
-use rtic::Mutex;
-use rtic_monotonics::Monotonic;
-
-pub async fn task_ieb(cx: app::task_ieb::Context<'_>) {
-    let mut car = cx.shared.car;
-    let mut pcan_tx = cx.shared.pcan_tx;
-
+use rtic_core::Mutex;
+
+pub async fn task_ieb<C, MCAR, MTX, TX>(mut car: MCAR, mut pcan_tx: MTX)
+where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
     // Initialise all the raw CAN messages
 
     let ieb507 = Ieb507Tcs::try_from(hex!("00000001").as_slice()).unwrap();
@@ -31,11 +33,11 @@ pub async fn task_ieb(cx: app::task_ieb::Context<'_>) {
         while car.lock(|car| car.ignition() != Ignition::On) {
             // IEB only runs while ignition is on. Until we have some
             // event trigger for this, poll for it in a loop...
-            Mono::delay(20.millis()).await;
+            C::delay(20.millis()).await;
         }
 
         // Restart all the periodic counters each time ignition comes on
-        let mut repeater = Repeater::new();
+        let mut repeater = Repeater::<C>::new();
 
         // Counters
         let mut tf_counter1 = 0u8;
@@ -129,7 +131,7 @@ impl TractionControlFast {
 }
 
 impl TractionControlMed {
-    fn latest(car: &CarState, counter: &mut u8) -> Self {
+    fn latest<C: Clock>(car: &CarState<C>, counter: &mut u8) -> Self {
         let mut res = Self::try_from(hex!("00E00000FF43B298").as_slice()).unwrap();
 
         // Update brake pedal state
@@ -163,7 +165,7 @@ impl TractionControlMed {
 
 impl Ieb2a2 {
     // Brake pedal data. Includes pedal force field and other brake-proportional field.
-    fn latest(car: &CarState, counter: &mut bool) -> Self {
+    fn latest<C: Clock>(car: &CarState<C>, counter: &mut bool) -> Self {
         let mut res = Self::try_from(hex!("0500001C1000005E").as_slice()).unwrap();
 
         res.set_heart_beat(*counter).unwrap();
@@ -182,7 +184,7 @@ impl Ieb2a2 {
 }
 
 impl Ieb331 {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: Clock>(car: &CarState<C>) -> Self {
         let mut res = Self::try_from(hex!("F000000000000000").as_slice()).unwrap();
 
         // Seems to be approx 2x "BrakeUnknown" in Ieb2a2
@@ -195,7 +197,7 @@ impl Ieb331 {
 
 impl Ieb386Wheel {
     // Wheel speed data
-    fn latest(_car: &CarState, counter1: &mut u8, counter2: &mut u8) -> Self {
+    fn latest<C: Clock>(_car: &CarState<C>, counter1: &mut u8, counter2: &mut u8) -> Self {
         let mut res = Self::try_from(hex!("0000000000400080").as_slice()).unwrap();
 
         // Live counters in the top 2 bits of each 16-bit wheel speed value
@@ -224,7 +226,7 @@ impl Ieb386Wheel {
 }
 
 impl Ieb387Wheel {
-    fn latest(_car: &CarState, counter: &mut u8) -> Self {
+    fn latest<C: Clock>(_car: &CarState<C>, counter: &mut u8) -> Self {
         let mut res = Self::try_from(hex!("0A0D000000000A00").as_slice()).unwrap();
 
         // Update counter
@@ -276,3 +278,99 @@ impl StabilityControl {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::StepClock;
+
+    // Sum of the nibbles of `bytes`, modulo 16
+    fn nibble_sum(bytes: &[u8]) -> u8 {
+        bytes
+            .iter()
+            .fold(0u8, |n, e| (n + (e >> 4) + (e & 0xF)) & 0xF)
+    }
+
+    #[test]
+    fn traction_control_fast_counters_wrap() {
+        let mut counter1 = TractionControlFast::COUNTER1_MAX;
+        let mut counter2 = 0x8;
+        let msg = TractionControlFast::latest(&mut counter1, &mut counter2);
+        assert_eq!(counter1, TractionControlFast::COUNTER1_MIN);
+        assert_eq!(counter2, 0xA, "counter2 skips 0x9");
+        assert_eq!(msg.counter1(), counter1);
+        assert_eq!(msg.counter2(), counter2);
+
+        let mut counter2 = TractionControlFast::COUNTER2_MAX;
+        TractionControlFast::latest(&mut counter1, &mut counter2);
+        assert_eq!(counter2, TractionControlFast::COUNTER2_MIN);
+    }
+
+    #[test]
+    fn traction_control_med_counter_and_checksum() {
+        let mut car = CarState::<StepClock>::new();
+        let mut counter = TractionControlMed::COUNTER_MIN;
+        let mut expected = counter;
+
+        for n in 0..40 {
+            car.set_is_braking(n % 3 == 0);
+            let msg = TractionControlMed::latest(&car, &mut counter);
+
+            expected = if expected == TractionControlMed::COUNTER_MAX {
+                TractionControlMed::COUNTER_MIN
+            } else {
+                expected + 1
+            };
+            assert_eq!(msg.counter(), expected);
+            assert_eq!(msg.driver_braking(), n % 3 == 0);
+
+            // Checksum is the two's complement of the nibble sum of the rest
+            // of the message
+            let mut unsummed = TractionControlMed::try_from(msg.raw()).unwrap();
+            unsummed.set_checksum(0).unwrap();
+            assert_eq!((nibble_sum(&unsummed.raw()[..7]) + msg.checksum()) & 0xF, 0);
+        }
+    }
+
+    #[test]
+    fn ieb2a2_heartbeat_toggles() {
+        let car = CarState::<StepClock>::new();
+        let mut heartbeat = false;
+        let beats: [bool; 4] =
+            core::array::from_fn(|_| Ieb2a2::latest(&car, &mut heartbeat).heart_beat());
+        assert_eq!(beats, [false, true, false, true]);
+    }
+
+    #[test]
+    fn ieb387_wheel_counter_and_checksum() {
+        let car = CarState::<StepClock>::new();
+        let mut counter = Ieb387Wheel::ALIVE_COUNTER_WHL_PUL_MAX;
+        let msg = Ieb387Wheel::latest(&car, &mut counter);
+        assert_eq!(counter, Ieb387Wheel::ALIVE_COUNTER_WHL_PUL_MIN);
+        assert_eq!(msg.alive_counter_whl_pul(), counter);
+
+        for _ in 0..20 {
+            let msg = Ieb387Wheel::latest(&car, &mut counter);
+            let mut unsummed = Ieb387Wheel::try_from(msg.raw()).unwrap();
+            unsummed.set_whl_pul_chksum(0).unwrap();
+            let sum = unsummed.raw().iter().fold(0u8, |s, n| s.wrapping_add(*n));
+            assert_eq!(msg.whl_pul_chksum(), sum);
+        }
+    }
+
+    #[test]
+    fn stability_control_counter_and_checksum() {
+        let mut counter = StabilityControl::COUNTER_MAX;
+        let msg = StabilityControl::latest(&mut counter);
+        assert_eq!(counter, StabilityControl::COUNTER_MIN);
+        assert_eq!(msg.counter(), counter);
+
+        for _ in 0..20 {
+            let msg = StabilityControl::latest(&mut counter);
+            let mut unsummed = StabilityControl::try_from(msg.raw()).unwrap();
+            unsummed.set_checksum(0).unwrap();
+            let sum = unsummed.raw().iter().fold(0u8, |n, e| n.wrapping_add(*e));
+            assert_eq!(msg.checksum(), (sum ^ 0x9) & 0xF);
+        }
+    }
+}
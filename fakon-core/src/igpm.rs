@@ -3,26 +3,38 @@
 //!
 //! Some of these messages may originate from other modules in the car, and be
 //! forwarded onto the PCAN bus by the IGPM. Others originate from the IGPM.
+use crate::can::{CanRx, CanTx};
 use crate::car::{self, CarState, ChargeLock, Contactor, Ignition};
 use crate::dbc::pcan::{
     BodyState, BodyStateDrvDoorSw, BodyStateDrvSeatBeltSw, BodyStateIgnitionSw,
     BodyStatePassDoorSw, BodyWarnings, Cgw450, Cgw45d, Cgw45e, Cgw462, Cgw4fe, Cgw55c, Cgw55f,
     Cgw561, Cgw578, Cgw588, Cgw5b3, Cgw5b3PowerState, Cgw5b3UnkPowerRelated, Cgw5df, ChargePort,
-    ChargeSettings, ChargeSettingsAcChargingCurrent, Clock, Messages, Odometer, Steering,
+    ChargeSettings, ChargeSettingsAcChargingCurrent, Clock, Messages, Obc58e, Odometer, Steering,
 };
 use crate::fresh::IsFresh;
-use crate::hardware::Mono;
+use crate::isotp;
 use crate::repeater::{Period, Repeater};
-use crate::{app, Duration};
+use crate::time;
+use crate::uds::SID_READ_DATA_BY_IDENTIFIER;
+use crate::uds_server::{
+    self, Handler, Outcome, Response, NRC_INCORRECT_LENGTH, NRC_REQUEST_OUT_OF_RANGE,
+    NRC_SERVICE_NOT_SUPPORTED,
+};
+use crate::Duration;
+use core::convert::Infallible;
+use embedded_can::{Id, StandardId};
+use embedded_hal::digital::v2::{OutputPin, PinState};
 use fugit::ExtU32;
 use hex_literal::hex;
-use rtic::Mutex;
-use rtic_monotonics::Monotonic;
-use stm32g4xx_hal::prelude::{OutputPin, PinState};
+use rtic_core::Mutex;
 
-pub async fn task_igpm(cx: app::task_igpm::Context<'_>) {
-    let mut pcan_tx = cx.shared.pcan_tx;
-    let mut car = cx.shared.car;
+pub async fn task_igpm<C, MCAR, MTX, TX>(mut car: MCAR, mut pcan_tx: MTX)
+where
+    C: time::Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
 
     let charge_settings =
         ChargeSettings::new(ChargeSettingsAcChargingCurrent::Maximum.into()).unwrap();
@@ -71,7 +83,7 @@ pub async fn task_igpm(cx: app::task_igpm::Context<'_>) {
 
     let mut steering_counter = 0u8;
 
-    let mut repeater = Repeater::new();
+    let mut repeater = Repeater::<C>::new();
 
     loop {
         for next in repeater.tick().await {
@@ -129,22 +141,27 @@ pub async fn task_igpm(cx: app::task_igpm::Context<'_>) {
     }
 }
 
-pub fn on_can_rx(outer_msg: &Messages) {
+/// IDs of the messages handled by on_can_rx()
+pub const CAN_RX_IDS: &[Id] = &[Obc58e::MESSAGE_ID];
+
+/// Handler function for received CAN messages.
+///
+/// Returns the direction to move the charge port lock, if the OBC has
+/// requested it. The caller is responsible for spawning
+/// task_lock_charge_port with the result.
+pub fn on_can_rx(outer_msg: &Messages) -> Option<ChargeLock> {
     if let Messages::Obc58e(msg) = outer_msg {
         let unlock = msg.port_unlock_req();
         let lock = msg.port_lock_req();
         if lock && unlock {
             defmt::error!("Invalid OBC lock and unlock requested simultaneously");
-        } else if lock || unlock {
-            let direction = if lock {
-                ChargeLock::Locked
-            } else {
-                ChargeLock::Unlocked
-            };
-            // Result: Ignoring result because an existing lock/unlock may be in progress
-            let _ = app::task_lock_charge_port::spawn(direction);
+        } else if lock {
+            return Some(ChargeLock::Locked);
+        } else if unlock {
+            return Some(ChargeLock::Unlocked);
         }
     }
+    None
 }
 
 /// Task which is spawned to lock or unlock the charge port.
@@ -154,12 +171,17 @@ pub fn on_can_rx(outer_msg: &Messages) {
 ///
 /// This is in the IGPM module as in the original Kona this function is
 /// managed by the IGPM, although it makes a bit less sense here in Fakon.
-pub async fn task_lock_charge_port(
-    cx: app::task_lock_charge_port::Context<'_>,
+pub async fn task_lock_charge_port<C, MCAR, D, R>(
+    mut car: MCAR,
+    drive: &mut D,
+    dir: &mut R,
     direction: ChargeLock,
-) {
-    let mut car = cx.shared.car;
-
+) where
+    C: time::Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    D: OutputPin<Error = Infallible>,
+    R: OutputPin<Error = Infallible>,
+{
     defmt::info!("Moving charge port to {}", direction);
 
     // Charge port actuator is "Kusler 04S" also sold as EV-T2M3S-E-LOCK12V (datasheet online)
@@ -167,9 +189,6 @@ pub async fn task_lock_charge_port(
     let drive_time: Duration = 600.millis(); // "Recommended adaptation time 600 ms"
     let pause_time: Duration = 3.secs(); // "Pause time after entry or exit path 3 s"
 
-    let drive = cx.local.charge_lock_drive;
-    let dir = cx.local.charge_lock_dir;
-
     dir.set_state(match direction {
         ChargeLock::Unlocked => PinState::Low,
         ChargeLock::Locked => PinState::High,
@@ -178,7 +197,7 @@ pub async fn task_lock_charge_port(
 
     drive.set_high().unwrap(); // Start actuator
 
-    Mono::delay(drive_time).await;
+    C::delay(drive_time).await;
 
     drive.set_low().unwrap(); // Stop actuator
 
@@ -193,11 +212,72 @@ pub async fn task_lock_charge_port(
     // failing, but the OBC appears to give up and go into a fault state after
     // about ~30s if it doesn't see the expected result - relying on that to
     // avoid wearing the motor out.
-    Mono::delay(pause_time).await;
+    C::delay(pause_time).await;
+}
+
+/// ID the other modules send the IGPM diagnostic requests on
+pub const DIAG_REQUEST_ID: Id = Id::Standard(StandardId::new(0x770).unwrap());
+/// ID the IGPM responds on
+pub const DIAG_RESPONSE_ID: Id = Id::Standard(StandardId::new(0x778).unwrap());
+
+/// Vehicle Identification Number, ASCII
+const DID_VIN: u16 = 0xF190;
+/// Variant coding, i.e. which options the car has
+const DID_VARIANT_CODING: u16 = 0xF1A0;
+
+// The IGPM's diagnostics: only enough for the other modules' queries
+struct Diag {
+    vin: &'static str,
+    coding: [u8; 8],
+}
+
+impl<MTX> Handler<MTX> for Diag {
+    fn handle(&mut self, request: &[u8], _extended: bool, _tx: &mut MTX) -> Outcome {
+        let &[sid, hi, lo] = request else {
+            return match request.first() {
+                Some(&SID_READ_DATA_BY_IDENTIFIER) => Err(NRC_INCORRECT_LENGTH),
+                _ => Err(NRC_SERVICE_NOT_SUPPORTED),
+            };
+        };
+        if sid != SID_READ_DATA_BY_IDENTIFIER {
+            return Err(NRC_SERVICE_NOT_SUPPORTED);
+        }
+        let mut response = Response::new(sid);
+        response.push(&[hi, lo])?;
+        match u16::from_be_bytes([hi, lo]) {
+            DID_VIN => response.push(self.vin.as_bytes())?,
+            DID_VARIANT_CODING => response.push(&self.coding)?,
+            did => {
+                defmt::debug!("IGPM DID {=u16:#x} requested", did);
+                return Err(NRC_REQUEST_OUT_OF_RANGE);
+            }
+        }
+        Ok(Some(response))
+    }
+}
+
+/// Answer the diagnostic requests other modules send the IGPM, so they don't
+/// log faults about the gateway not responding. `rx` only needs to receive
+/// frames on DIAG_REQUEST_ID. `vin` and `coding` are reported for the VIN and
+/// variant coding DIDs.
+pub async fn task_diag<C, RX, MTX, TX>(
+    vin: &'static str,
+    coding: [u8; 8],
+    rx: &mut RX,
+    pcan_tx: MTX,
+) where
+    C: time::Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let config = isotp::Config::new(DIAG_RESPONSE_ID, DIAG_REQUEST_ID);
+    let mut diag = Diag { vin, coding };
+    uds_server::serve::<C, _, _, _, _>("IGPM", config, &mut diag, rx, pcan_tx).await
 }
 
 impl BodyState {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: time::Clock>(car: &CarState<C>) -> Self {
         // BodyState constructor has 43 args, so start from all zeroes and then set some bits!
         let mut result = Self::try_from(hex!("0000000000000000").as_ref()).unwrap();
 
@@ -243,12 +323,12 @@ impl BodyState {
 }
 
 impl Clock {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: time::Clock>(car: &CarState<C>) -> Self {
         if !car.most_on().ig3_on() {
             // First arg is "unknown" field as seen when vehicle is off, details are unknown...
             Self::new(0x8D, 0, 0, 0, 0).unwrap()
         } else {
-            let total_secs = Mono::now().duration_since_epoch().to_secs();
+            let total_secs = C::now().duration_since_epoch().to_secs();
 
             let second = total_secs % 60;
             let minute = (total_secs / 60) % 60;
@@ -261,7 +341,7 @@ impl Clock {
 }
 
 impl ChargePort {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: time::Clock>(car: &CarState<C>) -> Self {
         Self::new(car.charge_port().is_locked(), false, false, false).unwrap()
     }
 }
@@ -287,7 +367,7 @@ impl Steering {
 }
 
 impl Cgw588 {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: time::Clock>(car: &CarState<C>) -> Self {
         // Cgw588 reflects vehicle status in some way, not exactly
         // clear how
         let (b0, b1, b2) = match car.ignition() {
@@ -301,7 +381,7 @@ impl Cgw588 {
 }
 
 impl Cgw5b3 {
-    fn latest(car: &CarState) -> Self {
+    fn latest<C: time::Clock>(car: &CarState<C>) -> Self {
         let ignition = car.ignition();
 
         let unk_power_related = if ignition.ig3_on() {
@@ -327,3 +407,68 @@ impl Cgw5b3 {
         Self::new(unk_power_related.into(), unknown2, 0xFF, power_state.into()).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const VIN: &str = "KMHK381GFLU000002";
+    const CODING: [u8; 8] = hex!("0123456789ABCDEF");
+
+    fn diag_request(request: &[u8]) -> Result<Option<Vec<u8>>, u8> {
+        let mut diag = Diag {
+            vin: VIN,
+            coding: CODING,
+        };
+        Handler::<()>::handle(&mut diag, request, false, &mut ())
+            .map(|response| response.map(|response| response.data().to_vec()))
+    }
+
+    #[test]
+    fn diag_read_vin() {
+        let response = diag_request(&[0x22, 0xF1, 0x90]).unwrap().unwrap();
+        assert_eq!(response[..3], [0x62, 0xF1, 0x90]);
+        assert_eq!(&response[3..], VIN.as_bytes());
+    }
+
+    #[test]
+    fn diag_read_variant_coding() {
+        let response = diag_request(&[0x22, 0xF1, 0xA0]).unwrap().unwrap();
+        assert_eq!(response[..3], [0x62, 0xF1, 0xA0]);
+        assert_eq!(response[3..], CODING);
+    }
+
+    #[test]
+    fn diag_unsupported() {
+        assert_eq!(
+            diag_request(&[0x22, 0xF1, 0x95]),
+            Err(NRC_REQUEST_OUT_OF_RANGE)
+        );
+        // Only one DID per request
+        assert_eq!(
+            diag_request(&[0x22, 0xF1, 0x90, 0xF1, 0xA0]),
+            Err(NRC_INCORRECT_LENGTH)
+        );
+        assert_eq!(
+            diag_request(&[0x19, 0x02, 0xFF]),
+            Err(NRC_SERVICE_NOT_SUPPORTED)
+        );
+        assert_eq!(diag_request(&[0x14]), Err(NRC_SERVICE_NOT_SUPPORTED));
+    }
+
+    #[test]
+    fn steering_counter_and_checksum() {
+        let mut counter = Steering::COUNTER_MAX;
+        let msg = Steering::latest(&mut counter);
+        assert_eq!(counter, Steering::COUNTER_MIN);
+        assert_eq!(msg.counter(), counter);
+
+        for _ in 0..20 {
+            let msg = Steering::latest(&mut counter);
+            // With the checksum included, every nibble XORs to zero
+            let xor = msg.raw().iter().fold(0, |n, a| n ^ a);
+            assert_eq!((xor >> 4) ^ (xor & 0x0f), 0);
+        }
+    }
+}
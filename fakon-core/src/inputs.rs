@@ -0,0 +1,315 @@
+//! Hard wired inputs, and the ignition state machine which mostly follows them.
+use crate::car::{CarState, ChargeLock, Ignition};
+use crate::time::Clock;
+use crate::Duration;
+use core::convert::Infallible;
+use debouncr::debounce_stateful_12;
+use debouncr::debounce_stateful_3;
+use debouncr::debounce_stateful_5;
+use debouncr::Edge::Falling;
+use debouncr::Edge::Rising;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use fugit::ExtU32;
+use rtic_core::Mutex;
+
+/// How long the IG3 relay stays off when it's cycled from diagnostics, long
+/// enough for the modules it powers to reset
+const IG3_CYCLE_OFF_TIME: Duration = Duration::secs(2);
+
+// Power state changes are slow, so poll them in a timed loop with some debounce logic
+pub async fn poll_slow_inputs<C, MCAR, B, R, L>(
+    mut car: MCAR,
+    brake_input: &mut B,
+    ev_ready_input: &mut R,
+    charge_lock_sensor: &mut L,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    B: InputPin<Error = Infallible>,
+    R: InputPin<Error = Infallible>,
+    L: InputPin<Error = Infallible>,
+{
+    // Time base for the debouncing delays
+    let period = 10.millis();
+    // Debouncers. Each debounce period is (_N * period)
+    let mut brakes_on = debounce_stateful_3(false);
+    let mut ev_ready = debounce_stateful_5(false);
+    // Note: we track the charge port lock state here not from task_lock_charge_port as it
+    // can also be manually unlocked at any time
+    let mut charge_lock = debounce_stateful_12(false);
+
+    let mut next = C::now() + period;
+    loop {
+        C::delay_until(next).await;
+        next += period;
+
+        let brakes_edge = brakes_on.update(brake_input.is_high().unwrap());
+        let ready_edge = ev_ready.update(ev_ready_input.is_high().unwrap());
+        let charge_lock_edge = charge_lock.update(charge_lock_sensor.is_high().unwrap());
+
+        if [brakes_edge, ready_edge, charge_lock_edge]
+            .into_iter()
+            .any(|o| o.is_some())
+        {
+            car.lock(|car| {
+                if let Some(edge) = brakes_edge {
+                    car.set_is_braking(edge == Rising);
+                }
+                if let Some(edge) = ready_edge {
+                    car.set_ev_ready_input(edge == Rising);
+                }
+                if let Some(edge) = charge_lock_edge {
+                    car.set_charge_port(match edge {
+                        Rising => ChargeLock::Unlocked,
+                        Falling => ChargeLock::Locked,
+                    });
+                }
+            });
+        }
+    }
+}
+
+/// Tracks the ignition state of the vehicle by monitoring:
+/// - IG1 power on input
+/// - CAN messages sent when IG3 powered on (may change to hard-wired in future)
+/// - Any CAN messages received at all
+///
+/// Returns if rest of vehicle is asleep, the caller should then go to Standby
+/// (pending a reset).
+pub async fn ignition_sequence<C, MCAR, I, R, L>(
+    mut car: MCAR,
+    ig1_on_input: &mut I,
+    relay_ig3: &mut R,
+    led_ignition: &mut L,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    I: InputPin<Error = Infallible>,
+    R: OutputPin<Error = Infallible>,
+    L: OutputPin<Error = Infallible>,
+{
+    let mut ig1_on = debounce_stateful_5(false);
+    let mut idle_count = 0;
+
+    loop {
+        let ignition = car.lock(|car| car.ignition());
+
+        if car.lock(|car| car.take_ig3_cycle_request()) && ignition == Ignition::On {
+            defmt::info!("Cycling IG3 relay");
+            relay_ig3.set_low().unwrap();
+            C::delay(IG3_CYCLE_OFF_TIME).await;
+            relay_ig3.set_high().unwrap();
+        }
+
+        let ig1_edge = ig1_on.update(ig1_on_input.is_high().unwrap());
+
+        let ig3_alive = car.lock(|car| car.ig3_appears_powered());
+
+        // If PCAN goes Bus Off with the ignition on, the CAN driver keeps
+        // trying to recover it and the emulation carries on in the meantime
+        // (nothing is transmitted until it recovers.)
+        let pcan_bus_off = car.lock(|car| car.pcan_bus_off());
+
+        let next_ignition = match ignition {
+            Ignition::Off => {
+                if ig1_edge == Some(Rising) {
+                    Some(Ignition::On)
+                }
+                else if ig3_alive {
+                    Some(Ignition::IG3)
+                }
+                else if pcan_bus_off || !car.lock(|car| car.pcan_receiving()) {
+                    // FIXME: Hacky check while we don't have wakeup sources
+                    // to automatically get back out of standby mode: wait
+                    // at least 10 more seconds before going into standby to
+                    // give the rest of the vehicle a chance to start up if
+                    // we just powered on
+                    idle_count += 1;
+                    if idle_count > 500 {
+                        return;
+                    } else {
+                        None
+                    }
+                }
+                else {
+                    idle_count = 0;
+                    None // No change
+                }
+            },
+            Ignition::IG3 => {
+                if ig1_edge == Some(Rising) {
+                    Some(Ignition::On)
+                }
+                else if !ig3_alive {
+                    Some(Ignition::Off)
+                }
+                else {
+                    None // No change
+                }
+
+            },
+            Ignition::On => {
+                if ig1_edge == Some(Falling) {
+                    if ig3_alive {
+                        Some(Ignition::IG3)
+                    } else {
+                        // This starts a shutdown sequence that last
+                        // slightly over 3 minutes before the VCU and other
+                        // modules stop sending messages (if ignition stays off).
+                        //
+                        // Can possibly make this time out faster by telling it the
+                        // doors are locked, or stopping some of our own IGPM messages.
+                        Some(Ignition::Off)
+                    }
+                }
+                else {
+                    None // No change
+                }
+            },
+        };
+
+        if let Some(next) = next_ignition {
+            car.lock(|car| car.set_ignition(next));
+
+            // Update the IG3 external power on relay, if needed
+            if next == Ignition::On {
+                relay_ig3.set_high().unwrap();
+                led_ignition.set_high().unwrap();
+            } else {
+                relay_ig3.set_low().unwrap();
+                led_ignition.set_low().unwrap();
+            }
+        }
+
+        C::delay(20.millis()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run_for, step_clock, Shared, TestPin};
+    use crate::time::StepClock;
+    use core::pin::pin;
+
+    struct Harness {
+        car: Shared<CarState<StepClock>>,
+        ig1: TestPin,
+        relay_ig3: TestPin,
+        led: TestPin,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                car: Shared::new(CarState::new()),
+                ig1: TestPin::default(),
+                relay_ig3: TestPin::default(),
+                led: TestPin::default(),
+            }
+        }
+
+        fn ignition(&self) -> Ignition {
+            self.car.clone().lock(|car| car.ignition())
+        }
+
+        // Keep the OBC (and so IG3) looking alive, and PCAN receiving
+        fn obc_awake(&self) {
+            self.car.clone().lock(|car| {
+                let now = StepClock::now();
+                car.set_evse_detected(false, now);
+                car.set_last_pcan_rx(now);
+            });
+        }
+    }
+
+    #[test]
+    fn ig1_turns_car_on_and_off() {
+        let _clock = step_clock();
+        let h = Harness::new();
+        let (mut ig1, mut relay, mut led) = (h.ig1.clone(), h.relay_ig3.clone(), h.led.clone());
+        let mut task = pin!(ignition_sequence::<StepClock, _, _, _, _>(
+            h.car.clone(),
+            &mut ig1,
+            &mut relay,
+            &mut led
+        ));
+
+        assert!(run_for(task.as_mut(), Duration::millis(200)).is_none());
+        assert_eq!(h.ignition(), Ignition::Off);
+
+        // IG1 is debounced over 5 polls
+        h.ig1.set(true);
+        run_for(task.as_mut(), Duration::millis(60));
+        assert_eq!(h.ignition(), Ignition::Off);
+        run_for(task.as_mut(), Duration::millis(100));
+        assert_eq!(h.ignition(), Ignition::On);
+        assert!(h.relay_ig3.get());
+        assert!(h.led.get());
+
+        // Nothing else awake, so straight to Off
+        h.ig1.set(false);
+        run_for(task.as_mut(), Duration::millis(200));
+        assert_eq!(h.ignition(), Ignition::Off);
+        assert!(!h.relay_ig3.get());
+        assert!(!h.led.get());
+    }
+
+    #[test]
+    fn ig3_follows_obc() {
+        let _clock = step_clock();
+        let h = Harness::new();
+        let (mut ig1, mut relay, mut led) = (h.ig1.clone(), h.relay_ig3.clone(), h.led.clone());
+        let mut task = pin!(ignition_sequence::<StepClock, _, _, _, _>(
+            h.car.clone(),
+            &mut ig1,
+            &mut relay,
+            &mut led
+        ));
+
+        h.obc_awake();
+        run_for(task.as_mut(), Duration::millis(100));
+        assert_eq!(h.ignition(), Ignition::IG3);
+        assert!(
+            !h.relay_ig3.get(),
+            "IG3 relay is only driven with the key on"
+        );
+
+        // Key on and off while charging returns to IG3
+        h.ig1.set(true);
+        for _ in 0..4 {
+            h.obc_awake();
+            run_for(task.as_mut(), Duration::millis(50));
+        }
+        assert_eq!(h.ignition(), Ignition::On);
+        h.ig1.set(false);
+        for _ in 0..4 {
+            h.obc_awake();
+            run_for(task.as_mut(), Duration::millis(50));
+        }
+        assert_eq!(h.ignition(), Ignition::IG3);
+
+        // OBC goes quiet, its messages go stale after 3 seconds
+        run_for(task.as_mut(), Duration::millis(2900));
+        assert_eq!(h.ignition(), Ignition::IG3);
+        run_for(task.as_mut(), Duration::millis(200));
+        assert_eq!(h.ignition(), Ignition::Off);
+    }
+
+    #[test]
+    fn returns_when_car_asleep() {
+        let _clock = step_clock();
+        let h = Harness::new();
+        let (mut ig1, mut relay, mut led) = (h.ig1.clone(), h.relay_ig3.clone(), h.led.clone());
+        let mut task = pin!(ignition_sequence::<StepClock, _, _, _, _>(
+            h.car.clone(),
+            &mut ig1,
+            &mut relay,
+            &mut led
+        ));
+
+        // Waits 500 polls (10 seconds) with nothing on PCAN
+        assert!(run_for(task.as_mut(), Duration::millis(9900)).is_none());
+        assert!(run_for(task.as_mut(), Duration::millis(200)).is_some());
+    }
+}
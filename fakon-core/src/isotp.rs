@@ -0,0 +1,826 @@
+//! ISO-TP (ISO 15765-2) transport, for diagnostic messages longer than a
+//! single CAN frame.
+//!
+//! IsoTp is one end of a link between a pair of CAN IDs. It works the same
+//! whether Fakon is the client (tester) or the server (ECU), and doesn't do
+//! any I/O of its own: the owner feeds it received frames with on_frame(),
+//! and calls poll() by the time given by next_deadline() so consecutive
+//! frames go out and timeouts are noticed. Frames are sent on a CanTx with
+//! transmit_in_order(), so they aren't coalesced like periodic frames.
+//!
+//! Only normal addressing and classic CAN frames are supported, which is all
+//! the Kona uses.
+use crate::can::{CanTx, ClassicFrame};
+use crate::{Duration, Instant};
+use defmt::Format;
+use embedded_can::{Frame, Id};
+use rtic_core::Mutex;
+
+/// Longest message in either direction. The Kona's diagnostic responses are
+/// a few hundred bytes at most.
+pub const MAX_MESSAGE: usize = 512;
+
+const SINGLE: u8 = 0x0;
+const FIRST: u8 = 0x1;
+const CONSECUTIVE: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+const FLOW_CONTINUE: u8 = 0x0;
+const FLOW_WAIT: u8 = 0x1;
+const FLOW_OVERFLOW: u8 = 0x2;
+
+/// A sender can ask us to wait this many times before we give up
+const MAX_WAITS: u8 = 10;
+
+/// Settings for one end of a link
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// ID this end transmits on
+    pub tx_id: Id,
+    /// ID this end receives on
+    pub rx_id: Id,
+    /// Block size to ask the other end for, 0 for no limit
+    pub block_size: u8,
+    /// STmin to ask the other end for, in ms (0 to 127)
+    pub st_min_ms: u8,
+    /// Fill byte to pad every frame to 8 bytes with, or None to send the
+    /// shortest frame. The Kona modules pad with 0xAA.
+    pub padding: Option<u8>,
+    /// How long to wait for a flow control or consecutive frame (N_Bs and N_Cr)
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Link between a tester and a Kona module, with the usual settings
+    pub const fn new(tx_id: Id, rx_id: Id) -> Self {
+        Self {
+            tx_id,
+            rx_id,
+            block_size: 0,
+            st_min_ms: 0,
+            padding: Some(0xAA),
+            timeout: Duration::millis(1000),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Error {
+    /// A message is already being sent
+    Busy,
+    /// Message is longer than MAX_MESSAGE (sending), or the other end sent a
+    /// message which is (receiving)
+    TooLong,
+    /// Sending: no flow control frame arrived in time, or the receiver asked
+    /// us to wait too many times
+    TxTimeout,
+    /// Receiving: the next consecutive frame didn't arrive in time
+    RxTimeout,
+    /// The receiver doesn't have room for our message
+    Overflow,
+    /// A consecutive frame arrived out of order, the message is dropped
+    WrongSequence,
+    /// Frame with an invalid header
+    Malformed,
+}
+
+enum TxState {
+    Idle,
+    WaitFlow {
+        deadline: Instant,
+        waits: u8,
+    },
+    Sending {
+        next: Instant,
+        /// Frames left in this block, None if the block size is unlimited
+        block_left: Option<u8>,
+        st_min: Duration,
+    },
+}
+
+enum RxState {
+    Idle,
+    Receiving {
+        deadline: Instant,
+        /// Frames left before we send another flow control, if limited
+        block_left: Option<u8>,
+    },
+}
+
+pub struct IsoTp {
+    config: Config,
+
+    tx: TxState,
+    tx_buf: [u8; MAX_MESSAGE],
+    tx_len: usize,
+    tx_pos: usize,
+    tx_sn: u8,
+
+    rx: RxState,
+    rx_buf: [u8; MAX_MESSAGE],
+    rx_len: usize,
+    rx_pos: usize,
+    rx_sn: u8,
+}
+
+impl IsoTp {
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config,
+            tx: TxState::Idle,
+            tx_buf: [0; MAX_MESSAGE],
+            tx_len: 0,
+            tx_pos: 0,
+            tx_sn: 0,
+            rx: RxState::Idle,
+            rx_buf: [0; MAX_MESSAGE],
+            rx_len: 0,
+            rx_pos: 0,
+            rx_sn: 0,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Is a message still being sent?
+    pub fn is_sending(&self) -> bool {
+        !matches!(self.tx, TxState::Idle)
+    }
+
+    /// Is a multi-frame message part way through arriving?
+    pub fn is_receiving(&self) -> bool {
+        !matches!(self.rx, RxState::Idle)
+    }
+
+    /// Start sending a message. A single frame message is sent straight away,
+    /// otherwise the rest goes out from poll().
+    pub fn send<MTX, TX>(&mut self, data: &[u8], now: Instant, tx: &mut MTX) -> Result<(), Error>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        if self.is_sending() {
+            return Err(Error::Busy);
+        }
+        if data.len() > MAX_MESSAGE {
+            return Err(Error::TooLong);
+        }
+
+        if data.len() <= 7 {
+            let mut frame = [0u8; 8];
+            frame[0] = SINGLE << 4 | data.len() as u8;
+            frame[1..=data.len()].copy_from_slice(data);
+            self.transmit(&frame[..=data.len()], tx);
+            return Ok(());
+        }
+
+        self.tx_buf[..data.len()].copy_from_slice(data);
+        self.tx_len = data.len();
+        let mut frame = [0u8; 8];
+        frame[0] = FIRST << 4 | (data.len() >> 8) as u8;
+        frame[1] = data.len() as u8;
+        frame[2..].copy_from_slice(&data[..6]);
+        self.transmit(&frame, tx);
+        self.tx_pos = 6;
+        self.tx_sn = 1;
+        self.tx = TxState::WaitFlow {
+            deadline: now + self.config.timeout,
+            waits: 0,
+        };
+        Ok(())
+    }
+
+    /// Handle a frame from the bus. Returns a complete received message, or
+    /// an error if a message (in either direction) has failed.
+    ///
+    /// Frames with other IDs are ignored, so every received frame can be
+    /// passed to every link.
+    pub fn on_frame<MTX, TX>(
+        &mut self,
+        frame: &impl Frame,
+        now: Instant,
+        tx: &mut MTX,
+    ) -> Result<Option<&[u8]>, Error>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        if frame.id() != self.config.rx_id || frame.is_remote_frame() {
+            return Ok(None);
+        }
+        let data = frame.data();
+        let Some(&pci) = data.first() else {
+            return Err(Error::Malformed);
+        };
+
+        match pci >> 4 {
+            SINGLE => {
+                let len = (pci & 0xF) as usize;
+                if len == 0 || len > data.len() - 1 {
+                    return Err(Error::Malformed);
+                }
+                // Starting a new message abandons any partly received one
+                self.rx = RxState::Idle;
+                self.rx_buf[..len].copy_from_slice(&data[1..=len]);
+                Ok(Some(&self.rx_buf[..len]))
+            }
+            FIRST => {
+                if data.len() < 8 {
+                    return Err(Error::Malformed);
+                }
+                let len = ((pci & 0xF) as usize) << 8 | data[1] as usize;
+                if len < 8 {
+                    return Err(Error::Malformed);
+                }
+                if len > MAX_MESSAGE {
+                    self.rx = RxState::Idle;
+                    self.send_flow(FLOW_OVERFLOW, tx);
+                    return Err(Error::TooLong);
+                }
+                self.rx_buf[..6].copy_from_slice(&data[2..8]);
+                self.rx_len = len;
+                self.rx_pos = 6;
+                self.rx_sn = 1;
+                self.rx = RxState::Receiving {
+                    deadline: now + self.config.timeout,
+                    block_left: self.block_limit(),
+                };
+                self.send_flow(FLOW_CONTINUE, tx);
+                Ok(None)
+            }
+            CONSECUTIVE => self.on_consecutive(pci & 0xF, &data[1..], now, tx),
+            FLOW_CONTROL => {
+                if data.len() < 3 {
+                    return Err(Error::Malformed);
+                }
+                self.on_flow(pci & 0xF, data[1], data[2], now, tx)
+                    .map(|()| None)
+            }
+            _ => Err(Error::Malformed),
+        }
+    }
+
+    fn on_consecutive<MTX, TX>(
+        &mut self,
+        sn: u8,
+        data: &[u8],
+        now: Instant,
+        tx: &mut MTX,
+    ) -> Result<Option<&[u8]>, Error>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        let RxState::Receiving { block_left, .. } = self.rx else {
+            return Ok(None); // Not for a message we're receiving
+        };
+        if sn != self.rx_sn {
+            self.rx = RxState::Idle;
+            return Err(Error::WrongSequence);
+        }
+        let len = data.len().min(self.rx_len - self.rx_pos);
+        self.rx_buf[self.rx_pos..self.rx_pos + len].copy_from_slice(&data[..len]);
+        self.rx_pos += len;
+        self.rx_sn = (self.rx_sn + 1) & 0xF;
+
+        if self.rx_pos == self.rx_len {
+            self.rx = RxState::Idle;
+            return Ok(Some(&self.rx_buf[..self.rx_len]));
+        }
+
+        let block_left = match block_left.map(|left| left - 1) {
+            Some(0) => {
+                self.send_flow(FLOW_CONTINUE, tx);
+                self.block_limit()
+            }
+            left => left,
+        };
+        self.rx = RxState::Receiving {
+            deadline: now + self.config.timeout,
+            block_left,
+        };
+        Ok(None)
+    }
+
+    fn on_flow<MTX, TX>(
+        &mut self,
+        status: u8,
+        block_size: u8,
+        st_min: u8,
+        now: Instant,
+        tx: &mut MTX,
+    ) -> Result<(), Error>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        let TxState::WaitFlow { waits, .. } = self.tx else {
+            return Ok(()); // Not waiting for one, ignore it
+        };
+        match status {
+            FLOW_CONTINUE => {
+                self.tx = TxState::Sending {
+                    next: now,
+                    block_left: (block_size > 0).then_some(block_size),
+                    st_min: decode_st_min(st_min),
+                };
+                self.poll(now, tx)
+            }
+            FLOW_WAIT if waits < MAX_WAITS => {
+                self.tx = TxState::WaitFlow {
+                    deadline: now + self.config.timeout,
+                    waits: waits + 1,
+                };
+                Ok(())
+            }
+            FLOW_WAIT => {
+                self.tx = TxState::Idle;
+                Err(Error::TxTimeout)
+            }
+            FLOW_OVERFLOW => {
+                self.tx = TxState::Idle;
+                Err(Error::Overflow)
+            }
+            _ => {
+                self.tx = TxState::Idle;
+                Err(Error::Malformed)
+            }
+        }
+    }
+
+    /// Send any consecutive frame which is due, and check for timeouts.
+    pub fn poll<MTX, TX>(&mut self, now: Instant, tx: &mut MTX) -> Result<(), Error>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        if let RxState::Receiving { deadline, .. } = self.rx {
+            if now >= deadline {
+                self.rx = RxState::Idle;
+                return Err(Error::RxTimeout);
+            }
+        }
+
+        match self.tx {
+            TxState::Idle => Ok(()),
+            TxState::WaitFlow { deadline, .. } => {
+                if now >= deadline {
+                    self.tx = TxState::Idle;
+                    return Err(Error::TxTimeout);
+                }
+                Ok(())
+            }
+            TxState::Sending {
+                next,
+                block_left,
+                st_min,
+            } => {
+                if now < next {
+                    return Ok(());
+                }
+                // One frame per call, so frames are at least a tick apart
+                // even with an STmin of 0 and don't pile up in the TX queue
+                let len = (self.tx_len - self.tx_pos).min(7);
+                let mut frame = [0u8; 8];
+                frame[0] = CONSECUTIVE << 4 | self.tx_sn;
+                frame[1..=len].copy_from_slice(&self.tx_buf[self.tx_pos..self.tx_pos + len]);
+                self.transmit(&frame[..=len], tx);
+                self.tx_pos += len;
+                self.tx_sn = (self.tx_sn + 1) & 0xF;
+
+                self.tx = if self.tx_pos == self.tx_len {
+                    TxState::Idle
+                } else if block_left == Some(1) {
+                    TxState::WaitFlow {
+                        deadline: now + self.config.timeout,
+                        waits: 0,
+                    }
+                } else {
+                    TxState::Sending {
+                        next: now + st_min.max(Duration::millis(1)),
+                        block_left: block_left.map(|left| left - 1),
+                        st_min,
+                    }
+                };
+                Ok(())
+            }
+        }
+    }
+
+    /// When poll() next needs to be called, if anything is in progress
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let tx = match self.tx {
+            TxState::Idle => None,
+            TxState::WaitFlow { deadline, .. } => Some(deadline),
+            TxState::Sending { next, .. } => Some(next),
+        };
+        let rx = match self.rx {
+            RxState::Idle => None,
+            RxState::Receiving { deadline, .. } => Some(deadline),
+        };
+        match (tx, rx) {
+            (Some(tx), Some(rx)) => Some(tx.min(rx)),
+            (tx, rx) => tx.or(rx),
+        }
+    }
+
+    fn block_limit(&self) -> Option<u8> {
+        (self.config.block_size > 0).then_some(self.config.block_size)
+    }
+
+    fn send_flow<MTX, TX>(&self, status: u8, tx: &mut MTX)
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        let frame = [
+            FLOW_CONTROL << 4 | status,
+            self.config.block_size,
+            self.config.st_min_ms.min(0x7F),
+        ];
+        self.transmit(&frame, tx);
+    }
+
+    fn transmit<MTX, TX>(&self, data: &[u8], tx: &mut MTX)
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+    {
+        let mut padded = [self.config.padding.unwrap_or(0); 8];
+        padded[..data.len()].copy_from_slice(data);
+        let len = if self.config.padding.is_some() {
+            8
+        } else {
+            data.len()
+        };
+        // Unwrap: never more than 8 bytes
+        let frame = ClassicFrame::new(self.config.tx_id, &padded[..len]).unwrap();
+        tx.lock(|tx| tx.transmit_in_order(&frame));
+    }
+}
+
+// STmin from a flow control frame. The sub-millisecond values round up to a
+// whole tick, and reserved values are treated as the longest STmin.
+fn decode_st_min(raw: u8) -> Duration {
+    match raw {
+        0x00..=0x7F => Duration::millis(raw.into()),
+        0xF1..=0xF9 => Duration::millis(1),
+        _ => Duration::millis(0x7F),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{step_clock, RecordTx, Shared};
+    use crate::time::{Clock, StepClock};
+    use embedded_can::StandardId;
+    use std::vec;
+    use std::vec::Vec;
+
+    const TESTER_ID: Id = Id::Standard(StandardId::new(0x7E4).unwrap());
+    const ECU_ID: Id = Id::Standard(StandardId::new(0x7EC).unwrap());
+
+    fn tester() -> Config {
+        Config::new(TESTER_ID, ECU_ID)
+    }
+
+    fn ecu() -> Config {
+        Config::new(ECU_ID, TESTER_ID)
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn take(tx: &mut Shared<RecordTx>) -> Vec<(Id, Vec<u8>)> {
+        tx.lock(|tx| core::mem::take(&mut tx.frames))
+    }
+
+    fn frame(id: Id, data: &[u8]) -> ClassicFrame {
+        ClassicFrame::new(id, data).unwrap()
+    }
+
+    /// A tester and an ECU connected to each other
+    struct Link {
+        tester: IsoTp,
+        ecu: IsoTp,
+        tester_tx: Shared<RecordTx>,
+        ecu_tx: Shared<RecordTx>,
+        /// Every frame sent by either end: ms, ID and data
+        log: Vec<(u32, Id, Vec<u8>)>,
+    }
+
+    impl Link {
+        fn new(tester: Config, ecu: Config) -> Self {
+            Self {
+                tester: IsoTp::new(tester),
+                ecu: IsoTp::new(ecu),
+                tester_tx: Shared::new(RecordTx::default()),
+                ecu_tx: Shared::new(RecordTx::default()),
+                log: Vec::new(),
+            }
+        }
+
+        /// Step the clock 1ms at a time for `duration`, polling both ends
+        /// and passing frames between them. Returns the messages received
+        /// by the tester and by the ECU.
+        fn run(&mut self, duration: Duration) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+            let end = StepClock::now() + duration;
+            let mut received = (Vec::new(), Vec::new());
+            loop {
+                let now = StepClock::now();
+                self.tester.poll(now, &mut self.tester_tx).unwrap();
+                self.ecu.poll(now, &mut self.ecu_tx).unwrap();
+                // Replies are delivered in the same tick
+                loop {
+                    let to_ecu = take(&mut self.tester_tx);
+                    let to_tester = take(&mut self.ecu_tx);
+                    if to_ecu.is_empty() && to_tester.is_empty() {
+                        break;
+                    }
+                    for (id, data) in to_ecu {
+                        let rx = self.ecu.on_frame(&frame(id, &data), now, &mut self.ecu_tx);
+                        if let Some(message) = rx.unwrap() {
+                            received.1.push(message.to_vec());
+                        }
+                        self.log.push((now.ticks(), id, data));
+                    }
+                    for (id, data) in to_tester {
+                        let rx = self
+                            .tester
+                            .on_frame(&frame(id, &data), now, &mut self.tester_tx);
+                        if let Some(message) = rx.unwrap() {
+                            received.0.push(message.to_vec());
+                        }
+                        self.log.push((now.ticks(), id, data));
+                    }
+                }
+                if now >= end {
+                    return received;
+                }
+                StepClock::step(Duration::millis(1));
+            }
+        }
+
+        /// The time and first data byte of each frame sent
+        fn pcis(&self) -> Vec<(u32, Id, u8)> {
+            self.log
+                .iter()
+                .map(|(ms, id, data)| (*ms, *id, data[0]))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn single_frame_both_ways() {
+        let _clock = step_clock();
+        let mut link = Link::new(tester(), ecu());
+        let now = StepClock::now();
+        link.tester
+            .send(&[0x22, 0xF1, 0x90], now, &mut link.tester_tx)
+            .unwrap();
+        link.ecu.send(&message(7), now, &mut link.ecu_tx).unwrap();
+        assert!(!link.tester.is_sending());
+
+        let (to_tester, to_ecu) = link.run(Duration::millis(0));
+        assert_eq!(to_ecu, vec![vec![0x22, 0xF1, 0x90]]);
+        assert_eq!(to_tester, vec![message(7)]);
+        assert_eq!(
+            link.log,
+            vec![
+                (
+                    0,
+                    TESTER_ID,
+                    vec![0x03, 0x22, 0xF1, 0x90, 0xAA, 0xAA, 0xAA, 0xAA]
+                ),
+                (0, ECU_ID, vec![0x07, 0, 1, 2, 3, 4, 5, 6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unpadded() {
+        let _clock = step_clock();
+        let config = |config: Config| Config {
+            padding: None,
+            ..config
+        };
+        let mut link = Link::new(config(tester()), config(ecu()));
+        let now = StepClock::now();
+        link.tester
+            .send(&[0x3E, 0x00], now, &mut link.tester_tx)
+            .unwrap();
+        link.ecu.send(&message(8), now, &mut link.ecu_tx).unwrap();
+        let (to_tester, _) = link.run(Duration::millis(2));
+        assert_eq!(to_tester, vec![message(8)]);
+        assert_eq!(
+            link.log,
+            vec![
+                (0, TESTER_ID, vec![0x02, 0x3E, 0x00]),
+                (0, ECU_ID, vec![0x10, 0x08, 0, 1, 2, 3, 4, 5]),
+                (0, TESTER_ID, vec![0x30, 0x00, 0x00]),
+                (0, ECU_ID, vec![0x21, 6, 7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn segmented_block_size_and_st_min() {
+        let _clock = step_clock();
+        let tester = Config {
+            block_size: 2,
+            st_min_ms: 5,
+            ..tester()
+        };
+        let mut link = Link::new(tester, ecu());
+        link.ecu
+            .send(&message(40), StepClock::now(), &mut link.ecu_tx)
+            .unwrap();
+        let (to_tester, _) = link.run(Duration::millis(50));
+        assert_eq!(to_tester, vec![message(40)]);
+
+        // 6 bytes in the first frame and 34 in five consecutive frames. The
+        // tester sends flow control after the first frame and every second
+        // consecutive frame, and the consecutive frames in a block are 5ms
+        // apart.
+        assert_eq!(
+            link.pcis(),
+            vec![
+                (0, ECU_ID, 0x10),
+                (0, TESTER_ID, 0x30),
+                (0, ECU_ID, 0x21),
+                (5, ECU_ID, 0x22),
+                (5, TESTER_ID, 0x30),
+                (5, ECU_ID, 0x23),
+                (10, ECU_ID, 0x24),
+                (10, TESTER_ID, 0x30),
+                (10, ECU_ID, 0x25),
+            ]
+        );
+        assert_eq!(link.log[0].2[..2], [0x10, 40]);
+        assert_eq!(link.log[1].2, [0x30, 2, 5, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]);
+        assert_eq!(link.log[8].2, [0x25, 34, 35, 36, 37, 38, 39, 0xAA]);
+        assert!(!link.ecu.is_sending());
+        assert!(!link.tester.is_receiving());
+        assert_eq!(link.ecu.next_deadline(), None);
+    }
+
+    #[test]
+    fn sequence_number_wraps() {
+        let _clock = step_clock();
+        let mut link = Link::new(tester(), ecu());
+        link.ecu
+            .send(&message(130), StepClock::now(), &mut link.ecu_tx)
+            .unwrap();
+        let (to_tester, _) = link.run(Duration::millis(50));
+        assert_eq!(to_tester, vec![message(130)]);
+
+        // 18 consecutive frames, one per ms as STmin is 0
+        let consecutive: Vec<_> = link
+            .pcis()
+            .into_iter()
+            .filter(|(_, id, pci)| *id == ECU_ID && pci >> 4 == CONSECUTIVE)
+            .collect();
+        let expected: Vec<_> = (1..=18u32)
+            .map(|n| (n - 1, ECU_ID, 0x20 | (n % 16) as u8))
+            .collect();
+        assert_eq!(consecutive, expected);
+    }
+
+    #[test]
+    fn flow_wait() {
+        let _clock = step_clock();
+        let mut ecu = IsoTp::new(ecu());
+        let mut tx = Shared::new(RecordTx::default());
+        ecu.send(&message(20), StepClock::now(), &mut tx).unwrap();
+        take(&mut tx);
+
+        // Each wait restarts the N_Bs timeout
+        let wait = frame(TESTER_ID, &[0x31, 0, 0]);
+        StepClock::step(Duration::millis(900));
+        assert_eq!(ecu.on_frame(&wait, StepClock::now(), &mut tx), Ok(None));
+        StepClock::step(Duration::millis(900));
+        assert_eq!(ecu.poll(StepClock::now(), &mut tx), Ok(()));
+        assert!(take(&mut tx).is_empty());
+
+        let resume = frame(TESTER_ID, &[0x30, 0, 0]);
+        assert_eq!(ecu.on_frame(&resume, StepClock::now(), &mut tx), Ok(None));
+        assert_eq!(take(&mut tx)[0].1[0], 0x21);
+        assert!(ecu.is_sending());
+    }
+
+    #[test]
+    fn flow_wait_too_many() {
+        let _clock = step_clock();
+        let mut ecu = IsoTp::new(ecu());
+        let mut tx = Shared::new(RecordTx::default());
+        let now = StepClock::now();
+        ecu.send(&message(20), now, &mut tx).unwrap();
+        let wait = frame(TESTER_ID, &[0x31, 0, 0]);
+        for _ in 0..MAX_WAITS {
+            assert_eq!(ecu.on_frame(&wait, now, &mut tx), Ok(None));
+        }
+        assert_eq!(ecu.on_frame(&wait, now, &mut tx), Err(Error::TxTimeout));
+        assert!(!ecu.is_sending());
+    }
+
+    #[test]
+    fn flow_overflow() {
+        let _clock = step_clock();
+        let mut ecu = IsoTp::new(ecu());
+        let mut tx = Shared::new(RecordTx::default());
+        let now = StepClock::now();
+        ecu.send(&message(20), now, &mut tx).unwrap();
+        assert_eq!(ecu.send(&message(3), now, &mut tx), Err(Error::Busy));
+        let overflow = frame(TESTER_ID, &[0x32, 0, 0]);
+        assert_eq!(ecu.on_frame(&overflow, now, &mut tx), Err(Error::Overflow));
+        assert!(!ecu.is_sending());
+        assert_eq!(take(&mut tx).len(), 1);
+    }
+
+    #[test]
+    fn wrong_sequence_aborts() {
+        let _clock = step_clock();
+        let mut tester = IsoTp::new(tester());
+        let mut tx = Shared::new(RecordTx::default());
+        let now = StepClock::now();
+        let first = frame(ECU_ID, &[0x10, 20, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(tester.on_frame(&first, now, &mut tx), Ok(None));
+        assert!(tester.is_receiving());
+
+        let skipped = frame(ECU_ID, &[0x22, 13, 14, 15, 16, 17, 18, 19]);
+        assert_eq!(
+            tester.on_frame(&skipped, now, &mut tx),
+            Err(Error::WrongSequence)
+        );
+        assert!(!tester.is_receiving());
+        // The rest of the message is ignored
+        let next = frame(ECU_ID, &[0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(tester.on_frame(&next, now, &mut tx), Ok(None));
+    }
+
+    #[test]
+    fn flow_control_timeout() {
+        let _clock = step_clock();
+        let mut ecu = IsoTp::new(ecu());
+        let mut tx = Shared::new(RecordTx::default());
+        ecu.send(&message(20), StepClock::now(), &mut tx).unwrap();
+        assert_eq!(ecu.next_deadline(), Some(Instant::from_ticks(1000)));
+
+        StepClock::step(Duration::millis(999));
+        assert_eq!(ecu.poll(StepClock::now(), &mut tx), Ok(()));
+        StepClock::step(Duration::millis(1));
+        assert_eq!(ecu.poll(StepClock::now(), &mut tx), Err(Error::TxTimeout));
+        assert!(!ecu.is_sending());
+    }
+
+    #[test]
+    fn consecutive_frame_timeout() {
+        let _clock = step_clock();
+        let mut tester = IsoTp::new(tester());
+        let mut tx = Shared::new(RecordTx::default());
+        let first = frame(ECU_ID, &[0x10, 20, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(tester.on_frame(&first, StepClock::now(), &mut tx), Ok(None));
+
+        // Each consecutive frame restarts the N_Cr timeout
+        StepClock::step(Duration::millis(999));
+        let next = frame(ECU_ID, &[0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(tester.on_frame(&next, StepClock::now(), &mut tx), Ok(None));
+        StepClock::step(Duration::millis(999));
+        assert_eq!(tester.poll(StepClock::now(), &mut tx), Ok(()));
+        StepClock::step(Duration::millis(1));
+        assert_eq!(
+            tester.poll(StepClock::now(), &mut tx),
+            Err(Error::RxTimeout)
+        );
+        assert!(!tester.is_receiving());
+    }
+
+    #[test]
+    fn too_long() {
+        let _clock = step_clock();
+        let mut tx = Shared::new(RecordTx::default());
+        let now = StepClock::now();
+
+        let mut ecu = IsoTp::new(ecu());
+        let long = message(MAX_MESSAGE + 1);
+        assert_eq!(ecu.send(&long, now, &mut tx), Err(Error::TooLong));
+        assert!(!ecu.is_sending());
+        assert!(take(&mut tx).is_empty());
+        assert_eq!(ecu.send(&long[..MAX_MESSAGE], now, &mut tx), Ok(()));
+        assert_eq!(take(&mut tx)[0].1[..2], [0x12, 0x00]);
+
+        // A receiver without room replies with an overflow flow control
+        let mut tester = IsoTp::new(tester());
+        let first = frame(ECU_ID, &[0x12, 0x01, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(tester.on_frame(&first, now, &mut tx), Err(Error::TooLong));
+        assert!(!tester.is_receiving());
+        assert_eq!(
+            take(&mut tx),
+            vec![(TESTER_ID, vec![0x32, 0, 0, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA])]
+        );
+    }
+}
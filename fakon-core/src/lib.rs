@@ -0,0 +1,40 @@
+//! Fakon vehicle logic: the emulated Kona modules and the state of the "car"
+//! they present, independent of the hardware Fakon runs on.
+//!
+//! Everything time dependent is generic over a [time::Clock], and everything
+//! that transmits CAN is generic over a [can::CanTx] sink. Shared state is
+//! accessed through [rtic_core::Mutex], the same as RTIC shared resources.
+#![no_std]
+
+// The unit tests run on the host
+#[cfg(test)]
+extern crate std;
+
+pub mod airbag_control;
+pub mod bms;
+pub mod can;
+pub mod can_log;
+pub mod can_stats;
+pub mod car;
+pub mod dbc;
+pub mod fresh;
+pub mod gateway;
+pub mod ieb;
+pub mod igpm;
+pub mod inputs;
+pub mod isotp;
+pub(crate) mod repeater;
+pub mod shift_control;
+pub mod slcan;
+#[cfg(test)]
+mod test_util;
+pub mod time;
+pub mod tx_queue;
+pub mod uds;
+pub mod uds_server;
+
+// Make some common type aliases for fugit Duration, Instance and Rate
+// based on our firmware's 1ms tick period
+pub type Duration = fugit::Duration<u32, 1, 1000>;
+pub type Instant = fugit::Instant<u32, 1, 1000>;
+pub type Rate = fugit::Rate<u32, 1, 1000>;
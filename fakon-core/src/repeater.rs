@@ -1,8 +1,9 @@
 //! Simple async timer for creating a bunch of timers that go off at different
 //! repeating intervals, all awaited from the same task.
-use crate::{hardware::Mono, Duration, Instant, Rate};
+use crate::time::Clock;
+use crate::{Duration, Instant, Rate};
+use core::marker::PhantomData;
 use enumflags2::{bitflags, BitFlags};
-use rtic_monotonics::Monotonic;
 
 #[bitflags]
 #[repr(u8)]
@@ -54,22 +55,25 @@ impl Period {
 /// A set of Period values, implemented as bit flags
 pub type PeriodSet = BitFlags<Period>;
 
-/// Wrapper around Monotonic to give you something you can await for periodic
+/// Wrapper around Clock to give you something you can await for periodic
 /// ticks at various frequencies, without drift and without needing to spawn
 /// many async tasks
-pub(crate) struct Repeater {
+pub(crate) struct Repeater<C: Clock> {
     /// Timestamp of the next tick expiry
     next_tick: Instant,
 
     /// Number of ticks so far (not expected to wrap, runtime too short)
     ticks: u32,
+
+    _clock: PhantomData<C>,
 }
 
-impl Repeater {
+impl<C: Clock> Repeater<C> {
     pub fn new() -> Self {
         Repeater {
-            next_tick: Mono::now() + TICK_BASE.duration(),
+            next_tick: C::now() + TICK_BASE.duration(),
             ticks: 0,
+            _clock: PhantomData,
         }
     }
 
@@ -84,7 +88,7 @@ impl Repeater {
     /// will be enabled in the result.
     pub async fn tick_filtered(&mut self, enabled: PeriodSet) -> PeriodSet {
         loop {
-            Mono::delay_until(self.next_tick).await;
+            C::delay_until(self.next_tick).await;
 
             // Set all of the flags which are both enabled in this call and
             // due at this tick
@@ -103,7 +107,7 @@ impl Repeater {
             }
 
             // Check for skipped ticks
-            let now = Mono::now();
+            let now = C::now();
             let mut skipped = 0;
             while now > self.next_tick {
                 self.next_tick += TICK_BASE.duration();
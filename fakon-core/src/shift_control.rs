@@ -12,20 +12,28 @@
 //! We emulate both links, but don't emulate a real actuator: the emulated SCU
 //! immediately updates the parking actuator state to whatever the VCU most
 //! recently asked for.
-use crate::dbc::pcan::{Messages, Scu10c, Scu10cParkingActuator, Vcu109ParkActuatorRequest};
-use crate::hardware::Mono;
+use crate::can::CanTx;
+use crate::car::CarState;
+use crate::dbc::pcan::{Messages, Scu10c, Scu10cParkingActuator, Vcu109, Vcu109ParkActuatorRequest};
+use crate::time::Clock;
 use crate::Duration;
-use crate::{app, Instant, Rate};
+use crate::{Instant, Rate};
+use core::convert::Infallible;
 use defmt::Format;
+use embedded_can::Id;
+use embedded_hal::digital::v2::OutputPin;
 use fugit::ExtU32;
 use hex_literal::hex;
-use rtic::Mutex;
-use rtic_monotonics::Monotonic;
-use stm32g4xx_hal::prelude::{InputPin, OutputPin};
-use stm32g4xx_hal::gpio::ExtiPin;
+use rtic_core::Mutex;
 
 const PWM_PERIOD: Duration = Duration::millis(100);
 
+/// IDs of the messages handled by on_can_rx()
+pub const CAN_RX_IDS: &[Id] = &[Vcu109::MESSAGE_ID];
+
+/// IDs which mustn't be dropped if PCAN RX is overloaded
+pub const CAN_RX_PRIORITY_IDS: &[Id] = &[Vcu109::MESSAGE_ID];
+
 /// Struct to track the overall park actuator state
 ///
 /// (This struct bundles two pieces of mostly unrelated state together: the current
@@ -97,18 +105,23 @@ impl ActuatorPosition {
 }
 
 /// Soft PWM task for SCU backup TX pin
-pub async fn task_scu_pwm_tx(mut cx: app::task_scu_pwm_tx::Context<'_>) {
-    let mut car = cx.shared.car;
-    let mut actuator = cx.shared.park_actuator;
-    let scu_park_tx = &mut cx.local.scu_park_tx;
-
+pub async fn task_scu_pwm_tx<C, MCAR, MPARK, P>(
+    mut car: MCAR,
+    mut actuator: MPARK,
+    scu_park_tx: &mut P,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+    P: OutputPin<Error = Infallible>,
+{
     // TODO: check the level of this signal when vehicle is off
     scu_park_tx.set_low().unwrap();
 
     while !car.lock(|car| car.ignition().ig3_on()) {
         // SCU only runs after IG3 is on. Until we have some
         // event trigger for this, poll for it in a loop...
-        Mono::delay(Rate::Hz(10).into_duration()).await;
+        C::delay(Rate::Hz(10).into_duration()).await;
     }
 
     loop {
@@ -119,11 +132,11 @@ pub async fn task_scu_pwm_tx(mut cx: app::task_scu_pwm_tx::Context<'_>) {
 
         scu_park_tx.set_low().unwrap();
 
-        Mono::delay(low_time).await;
+        C::delay(low_time).await;
 
         scu_park_tx.set_high().unwrap();
 
-        Mono::delay(high_time).await;
+        C::delay(high_time).await;
     }
 }
 
@@ -138,6 +151,16 @@ impl Default for ActuatorState {
 }
 
 impl ActuatorState {
+    /// Position of the emulated actuator: None if unknown, otherwise whether
+    /// it's locked in park
+    pub fn is_locked(&self) -> Option<bool> {
+        match self.position {
+            ActuatorPosition::Unknown => None,
+            ActuatorPosition::Unlocked => Some(false),
+            ActuatorPosition::Locked => Some(true),
+        }
+    }
+
     /// If this new edge timestamp indicates a PWM actuator request
     /// then return the new position that's being requested.
     fn is_pwm_request(&self, rising: bool, ts: Instant) -> Option<ActuatorPosition> {
@@ -175,13 +198,18 @@ impl ActuatorState {
     }
 }
 
-// Pin interrupt for edge transitions of SCU RX PWM signal
-pub fn task_scu_pwm_rx(mut cx: app::task_scu_pwm_rx::Context) {
-    let now = Mono::now();
-    let rising = cx.local.scu_park_rx.is_high().unwrap();
-    let car = &mut cx.shared.car;
-    let park_actuator = &mut cx.shared.park_actuator;
-
+/// Handler for edge transitions of SCU RX PWM signal, called from the pin
+/// interrupt with the new level of the pin and the time of the edge.
+pub fn on_scu_pwm_edge<C, MCAR, MPARK>(
+    mut car: MCAR,
+    mut park_actuator: MPARK,
+    rising: bool,
+    now: Instant,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+{
     if !car.lock(|car| car.ignition().ig3_on()) {
         // Vehicle is off, so reset the emulated actuator state and ignore VCU PWM edge
         // transitions until it comes back on
@@ -200,28 +228,33 @@ pub fn task_scu_pwm_rx(mut cx: app::task_scu_pwm_rx::Context) {
             state.update_pwm_edge(rising, now);
         });
     }
-
-    cx.local.scu_park_rx.clear_interrupt_pending_bit();
 }
 
 /// Sender task for SCU message
-pub async fn task_scu_can_tx(cx: app::task_scu_can_tx::Context<'_>) {
-    let mut car = cx.shared.car;
-    let mut actuator = cx.shared.park_actuator;
-    let mut pcan_tx = cx.shared.pcan_tx;
+pub async fn task_scu_can_tx<C, MCAR, MPARK, MTX, TX>(
+    mut car: MCAR,
+    mut actuator: MPARK,
+    mut pcan_tx: MTX,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
     let mut counter = 0u8;
 
     while !car.lock(|car| car.ignition().ig3_on()) {
         // SCU doesn't start until IG3 is on. Until we have some
         // event trigger for this, poll for it in a loop...
-        Mono::delay(20.millis()).await;
+        C::delay(20.millis()).await;
     }
 
     loop {
         let scu10c = actuator.lock(|actuator| Scu10c::latest(actuator.position, &mut counter));
         pcan_tx.lock(|tx| tx.transmit(&scu10c));
 
-        Mono::delay(Rate::Hz(100).into_duration()).await;
+        C::delay(Rate::Hz(100).into_duration()).await;
     }
 }
 
@@ -279,3 +312,91 @@ impl Scu10c {
         scu
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::Ignition;
+    use crate::test_util::{step_clock, Shared};
+    use crate::time::StepClock;
+
+    #[test]
+    fn scu10c_counter_and_position() {
+        let mut counter = Scu10c::COUNTER_MAX;
+        let msg = Scu10c::latest(ActuatorPosition::Locked, &mut counter);
+        assert_eq!(counter, Scu10c::COUNTER_MIN);
+        assert_eq!(msg.counter(), counter);
+        assert!(matches!(
+            msg.parking_actuator(),
+            Scu10cParkingActuator::Locked
+        ));
+
+        let msg = Scu10c::latest(ActuatorPosition::Unlocked, &mut counter);
+        assert_eq!(msg.counter(), Scu10c::COUNTER_MIN + 1);
+        assert!(matches!(
+            msg.parking_actuator(),
+            Scu10cParkingActuator::Unlocked
+        ));
+    }
+
+    #[test]
+    fn pwm_rx_duty_decoding() {
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(25) == Some(ActuatorPosition::Unlocked));
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(28) == Some(ActuatorPosition::Unlocked));
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(85) == Some(ActuatorPosition::Locked));
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(81) == Some(ActuatorPosition::Locked));
+        // Idle, and out of tolerance
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(55).is_none());
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(30).is_none());
+        assert!(ActuatorPosition::from_pwm_rx_duty_percent(70).is_none());
+    }
+
+    // Feed `cycles` PWM cycles with `high_ms` high time each to on_scu_pwm_edge,
+    // starting at `start`
+    fn feed_pwm(
+        car: &Shared<CarState<StepClock>>,
+        park: &Shared<ActuatorState>,
+        start: Instant,
+        high_ms: u32,
+        cycles: u32,
+    ) {
+        for cycle in 0..cycles {
+            let falling = start + PWM_PERIOD * cycle;
+            let rising = falling + PWM_PERIOD - Duration::millis(high_ms);
+            on_scu_pwm_edge(car.clone(), park.clone(), false, falling);
+            on_scu_pwm_edge(car.clone(), park.clone(), true, rising);
+        }
+    }
+
+    #[test]
+    fn pwm_rx_requests_move_actuator() {
+        let _clock = step_clock();
+        let mut car = Shared::new(CarState::<StepClock>::new());
+        let mut park = Shared::new(ActuatorState::default());
+        car.lock(|car| car.set_ignition(Ignition::On));
+
+        feed_pwm(&car, &park, Instant::from_ticks(0), 85, 5);
+        assert_eq!(park.lock(|park| park.is_locked()), Some(true));
+
+        // Idle duty leaves the actuator where it is
+        feed_pwm(&car, &park, Instant::from_ticks(500), 55, 5);
+        assert_eq!(park.lock(|park| park.is_locked()), Some(true));
+
+        feed_pwm(&car, &park, Instant::from_ticks(1000), 25, 5);
+        assert_eq!(park.lock(|park| park.is_locked()), Some(false));
+
+        // A cycle of the wrong length means the position is unknown
+        on_scu_pwm_edge(car.clone(), park.clone(), false, Instant::from_ticks(1700));
+        assert_eq!(park.lock(|park| park.is_locked()), None);
+    }
+
+    #[test]
+    fn pwm_rx_ignored_when_off() {
+        let _clock = step_clock();
+        let car = Shared::new(CarState::<StepClock>::new());
+        let mut park = Shared::new(ActuatorState::default());
+
+        feed_pwm(&car, &park, Instant::from_ticks(0), 85, 5);
+        assert_eq!(park.lock(|park| park.is_locked()), None);
+    }
+}
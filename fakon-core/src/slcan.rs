@@ -0,0 +1,408 @@
+//! SLCAN (Lawicel) ASCII protocol, so PC tools like SavvyCAN, python-can and
+//! slcand can use Fakon as their CAN interface.
+//!
+//! Each command and frame is a line of ASCII ending in a carriage return.
+//! Only the commands those tools use are supported:
+//!
+//! - `O` open, `L` open listen only, `C` close
+//! - `S0`..`S8` set bitrate (only `S6`, 500kbit/s, as that's what PCAN runs at)
+//! - `tiiildd..` and `Tiiiiiiiildd..` transmit a standard or extended frame
+//! - `V` version, `N` serial number, `F` status flags
+//! - `Z0`/`Z1` timestamps off or on
+//!
+//! Received frames are sent to the host in the same `t`/`T` format while the
+//! channel is open, with a 4 digit millisecond timestamp if enabled.
+use crate::can::ClassicFrame;
+use crate::Instant;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+/// Longest line in either direction: extended frame with 8 bytes of data and
+/// a timestamp, plus the carriage return
+pub const MAX_LINE: usize = 1 + 8 + 1 + 16 + 4 + 1;
+
+const OK: &[u8] = b"\r";
+const ERROR: &[u8] = b"\x07";
+
+/// Bitrate command for 500kbit/s
+const BITRATE_500K: u8 = b'6';
+
+/// Result of a complete command line from the host
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    /// Send this back to the host
+    pub reply: &'static [u8],
+    /// Transmit this frame on the bus
+    pub transmit: Option<ClassicFrame>,
+}
+
+impl Response {
+    fn reply(reply: &'static [u8]) -> Self {
+        Self {
+            reply,
+            transmit: None,
+        }
+    }
+}
+
+/// State of an SLCAN channel: decodes commands from the host, and encodes
+/// received frames to send to it.
+pub struct Slcan {
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    // Line was too long, ignore everything up to the next carriage return
+    overflow: bool,
+    open: bool,
+    listen_only: bool,
+    timestamps: bool,
+}
+
+impl Slcan {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; MAX_LINE],
+            line_len: 0,
+            overflow: false,
+            open: false,
+            listen_only: false,
+            timestamps: false,
+        }
+    }
+
+    /// Is the host receiving frames?
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Handle a byte from the host. Returns a Response at the end of each
+    /// command line.
+    pub fn on_byte(&mut self, byte: u8) -> Option<Response> {
+        match byte {
+            b'\r' => {
+                let overflow = core::mem::replace(&mut self.overflow, false);
+                let len = core::mem::replace(&mut self.line_len, 0);
+                if overflow {
+                    return Some(Response::reply(ERROR));
+                }
+                let line = self.line;
+                Some(self.on_command(&line[..len]))
+            }
+            // Some tools send CRLF
+            b'\n' => None,
+            _ => {
+                if self.line_len < self.line.len() {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+
+    fn on_command(&mut self, line: &[u8]) -> Response {
+        let Some((&cmd, args)) = line.split_first() else {
+            // Empty line, tools send these to flush out any partial command
+            return Response::reply(OK);
+        };
+        match (cmd, args) {
+            (b'O', []) | (b'L', []) if !self.open => {
+                self.open = true;
+                self.listen_only = cmd == b'L';
+                Response::reply(OK)
+            }
+            (b'C', []) => {
+                self.open = false;
+                Response::reply(OK)
+            }
+            // Bitrate can only be set while closed, and PCAN is fixed
+            (b'S', [BITRATE_500K]) if !self.open => Response::reply(OK),
+            (b'Z', [b'0']) => {
+                self.timestamps = false;
+                Response::reply(OK)
+            }
+            (b'Z', [b'1']) => {
+                self.timestamps = true;
+                Response::reply(OK)
+            }
+            (b'V', []) => Response::reply(b"V0101\r"),
+            (b'N', []) => Response::reply(b"NFAKN\r"),
+            // No error flags, bus status is in the Fakon log
+            (b'F', []) => Response::reply(b"F00\r"),
+            (b't', _) | (b'T', _) if self.open && !self.listen_only => {
+                match decode_frame(cmd == b'T', args) {
+                    Some(frame) => Response {
+                        reply: if cmd == b'T' { b"Z\r" } else { b"z\r" },
+                        transmit: Some(frame),
+                    },
+                    None => Response::reply(ERROR),
+                }
+            }
+            _ => Response::reply(ERROR),
+        }
+    }
+
+    /// Encode a received frame for the host, returning the length of the
+    /// line written to `out`. None if the channel isn't open, or the frame
+    /// can't be represented (i.e. CAN FD.)
+    pub fn encode(&self, frame: &impl Frame, now: Instant, out: &mut [u8; MAX_LINE]) -> Option<usize> {
+        if !self.open || frame.dlc() > 8 || frame.is_remote_frame() {
+            return None;
+        }
+        let mut len = 0;
+        let mut push = |byte: u8| {
+            out[len] = byte;
+            len += 1;
+        };
+        match frame.id() {
+            Id::Standard(id) => {
+                push(b't');
+                push_hex(&mut push, id.as_raw().into(), 3);
+            }
+            Id::Extended(id) => {
+                push(b'T');
+                push_hex(&mut push, id.as_raw(), 8);
+            }
+        }
+        push(b'0' + frame.dlc() as u8);
+        for &byte in &frame.data()[..frame.dlc()] {
+            push_hex(&mut push, byte.into(), 2);
+        }
+        if self.timestamps {
+            // Milliseconds, wrapping every minute
+            push_hex(&mut push, now.ticks() % 60_000, 4);
+        }
+        push(b'\r');
+        Some(len)
+    }
+}
+
+impl Default for Slcan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn push_hex(push: &mut impl FnMut(u8), value: u32, digits: u32) {
+    for shift in (0..digits).rev() {
+        let nibble = (value >> (shift * 4)) & 0xF;
+        push(b"0123456789ABCDEF"[nibble as usize]);
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, &c| {
+        let nibble = (c as char).to_digit(16)?;
+        Some(value << 4 | nibble)
+    })
+}
+
+// Arguments of a t or T command: ID, DLC, then the data bytes
+fn decode_frame(extended: bool, args: &[u8]) -> Option<ClassicFrame> {
+    let id_len = if extended { 8 } else { 3 };
+    if args.len() < id_len + 1 {
+        return None;
+    }
+    let (id, rest) = args.split_at(id_len);
+    let id = parse_hex(id)?;
+    let id: Id = if extended {
+        ExtendedId::new(id)?.into()
+    } else {
+        StandardId::new(id as u16)?.into()
+    };
+
+    let (&dlc, data) = rest.split_first()?;
+    let dlc = (dlc as char).to_digit(10).filter(|&dlc| dlc <= 8)? as usize;
+    if data.len() != dlc * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (byte, hex) in bytes.iter_mut().zip(data.chunks(2)) {
+        *byte = parse_hex(hex)? as u8;
+    }
+    ClassicFrame::new(id, &bytes[..dlc])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Send a line to `slcan`, returning the Response to its carriage return
+    fn command(slcan: &mut Slcan, line: &str) -> Response {
+        for &byte in line.as_bytes() {
+            assert_eq!(slcan.on_byte(byte), None);
+        }
+        slcan.on_byte(b'\r').unwrap()
+    }
+
+    fn reply(slcan: &mut Slcan, line: &str) -> &'static [u8] {
+        command(slcan, line).reply
+    }
+
+    fn open() -> Slcan {
+        let mut slcan = Slcan::new();
+        assert_eq!(reply(&mut slcan, "O"), OK);
+        slcan
+    }
+
+    fn encode(slcan: &Slcan, frame: &ClassicFrame, ms: u32) -> Option<Vec<u8>> {
+        let mut out = [0u8; MAX_LINE];
+        let len = slcan.encode(frame, Instant::from_ticks(ms), &mut out)?;
+        Some(out[..len].to_vec())
+    }
+
+    fn std_frame(id: u16, data: &[u8]) -> ClassicFrame {
+        ClassicFrame::new(StandardId::new(id).unwrap(), data).unwrap()
+    }
+
+    #[test]
+    fn open_and_close() {
+        let mut slcan = Slcan::new();
+        assert!(!slcan.is_open());
+        assert_eq!(reply(&mut slcan, "S6"), OK);
+        assert_eq!(reply(&mut slcan, "O"), OK);
+        assert!(slcan.is_open());
+        // Already open, and the bitrate can't change while open
+        assert_eq!(reply(&mut slcan, "O"), ERROR);
+        assert_eq!(reply(&mut slcan, "S6"), ERROR);
+        assert_eq!(reply(&mut slcan, "C"), OK);
+        assert!(!slcan.is_open());
+        assert_eq!(reply(&mut slcan, "L"), OK);
+        assert!(slcan.is_open());
+    }
+
+    #[test]
+    fn only_500k() {
+        let mut slcan = Slcan::new();
+        assert_eq!(reply(&mut slcan, "S4"), ERROR);
+        assert_eq!(reply(&mut slcan, "S8"), ERROR);
+        assert_eq!(reply(&mut slcan, "S"), ERROR);
+    }
+
+    #[test]
+    fn info_commands() {
+        let mut slcan = Slcan::new();
+        assert_eq!(reply(&mut slcan, "V"), b"V0101\r");
+        assert_eq!(reply(&mut slcan, "N"), b"NFAKN\r");
+        assert_eq!(reply(&mut slcan, "F"), b"F00\r");
+        assert_eq!(reply(&mut slcan, "Z1"), OK);
+        assert_eq!(reply(&mut slcan, "Z2"), ERROR);
+        assert_eq!(reply(&mut slcan, "X"), ERROR);
+        // Empty lines flush a partial command
+        assert_eq!(reply(&mut slcan, ""), OK);
+    }
+
+    #[test]
+    fn crlf_and_overflow() {
+        let mut slcan = Slcan::new();
+        assert_eq!(slcan.on_byte(b'V'), None);
+        assert_eq!(slcan.on_byte(b'\n'), None);
+        assert_eq!(slcan.on_byte(b'\r').unwrap().reply, b"V0101\r");
+
+        // Too long, then the next line works again
+        assert_eq!(reply(&mut slcan, &"t".repeat(MAX_LINE + 1)), ERROR);
+        assert_eq!(reply(&mut slcan, "N"), b"NFAKN\r");
+    }
+
+    #[test]
+    fn transmit_standard() {
+        let mut slcan = open();
+        let response = command(&mut slcan, "t7DF80201050000000000");
+        assert_eq!(response.reply, b"z\r");
+        assert_eq!(
+            response.transmit,
+            Some(std_frame(0x7DF, &[0x02, 0x01, 0x05, 0, 0, 0, 0, 0]))
+        );
+
+        let response = command(&mut slcan, "t1230");
+        assert_eq!(response.transmit, Some(std_frame(0x123, &[])));
+    }
+
+    #[test]
+    fn transmit_extended() {
+        let mut slcan = open();
+        let response = command(&mut slcan, "T18DAF1103AABBcc");
+        assert_eq!(response.reply, b"Z\r");
+        let id = ExtendedId::new(0x18DAF110).unwrap();
+        assert_eq!(
+            response.transmit,
+            Some(ClassicFrame::new(id, &[0xAA, 0xBB, 0xCC]).unwrap())
+        );
+    }
+
+    #[test]
+    fn transmit_invalid() {
+        let mut slcan = open();
+        for line in [
+            "t12",                // No DLC
+            "t1239",              // DLC over 8
+            "t1232001",           // Data doesn't match DLC
+            "t800100",            // ID out of range
+            "t12G100",            // Not hex
+            "T2000000010",        // Extended ID out of range
+            "t1231G0",            // Data not hex
+            "T123456781AABBCCDD", // Too much data
+        ] {
+            let response = command(&mut slcan, line);
+            assert_eq!(response.reply, ERROR, "{line}");
+            assert_eq!(response.transmit, None, "{line}");
+        }
+    }
+
+    #[test]
+    fn transmit_needs_open() {
+        let mut slcan = Slcan::new();
+        assert_eq!(command(&mut slcan, "t1230").transmit, None);
+
+        assert_eq!(reply(&mut slcan, "L"), OK);
+        let response = command(&mut slcan, "t1230");
+        assert_eq!((response.reply, response.transmit), (ERROR, None));
+    }
+
+    #[test]
+    fn encode_frames() {
+        let mut slcan = Slcan::new();
+        let frame = std_frame(0x5A3, &[0x01, 0xAB]);
+        assert_eq!(encode(&slcan, &frame, 0), None);
+
+        assert_eq!(reply(&mut slcan, "O"), OK);
+        assert_eq!(encode(&slcan, &frame, 0).unwrap(), b"t5A3201AB\r");
+
+        let id = ExtendedId::new(0x18DAF110).unwrap();
+        let frame = ClassicFrame::new(id, &[0; 8]).unwrap();
+        let line = encode(&slcan, &frame, 0).unwrap();
+        assert_eq!(line, b"T18DAF11080000000000000000\r");
+        assert!(line.len() <= MAX_LINE);
+    }
+
+    #[test]
+    fn encode_timestamps() {
+        let mut slcan = open();
+        assert_eq!(reply(&mut slcan, "Z1"), OK);
+        let frame = std_frame(0x100, &[]);
+        // Milliseconds, wrapping at 60 seconds
+        assert_eq!(encode(&slcan, &frame, 1234).unwrap(), b"t100004D2\r");
+        assert_eq!(encode(&slcan, &frame, 61_000).unwrap(), b"t100003E8\r");
+
+        // Longest line fits
+        let id = ExtendedId::MAX;
+        let frame = ClassicFrame::new(id, &[0xFF; 8]).unwrap();
+        assert_eq!(encode(&slcan, &frame, 59_999).unwrap().len(), MAX_LINE);
+
+        assert_eq!(reply(&mut slcan, "Z0"), OK);
+        assert_eq!(encode(&slcan, &frame, 0).unwrap().len(), MAX_LINE - 4);
+    }
+
+    #[test]
+    fn encoded_frame_decodes() {
+        let mut slcan = open();
+        let frame = std_frame(0x7E8, &[0x03, 0x7F, 0x22, 0x31]);
+        let line = encode(&slcan, &frame, 0).unwrap();
+        let line = core::str::from_utf8(&line[..line.len() - 1]).unwrap();
+        assert_eq!(command(&mut slcan, line).transmit, Some(frame));
+    }
+}
@@ -0,0 +1,251 @@
+//! Host stand-ins for the unit tests: a defmt logger, shared resources and
+//! pins. Similar to the ones in fakon-sim.
+use crate::can::{CanRx, CanTx, ClassicFrame};
+use crate::isotp::{self, IsoTp};
+use crate::time::{Clock, StepClock};
+use crate::{Duration, Instant};
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use embedded_can::{Frame, Id};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rtic_core::Mutex;
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Mutex as StdMutex, MutexGuard};
+use std::vec::Vec;
+
+// The vehicle logic logs with defmt, which needs a logger to link. Log
+// frames are discarded.
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u32}", { StepClock::now().ticks() });
+
+static STEP_CLOCK: StdMutex<()> = StdMutex::new(());
+
+/// StepClock is global and tests run in parallel, so tests which use it take
+/// turns by holding this guard. The clock starts again from zero.
+pub fn step_clock() -> MutexGuard<'static, ()> {
+    // A test which panicked while holding the guard doesn't affect the others
+    let guard = STEP_CLOCK.lock().unwrap_or_else(|err| err.into_inner());
+    StepClock::reset();
+    guard
+}
+
+/// Stand-in for an RTIC shared resource. Clones refer to the same value.
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Rc::new(RefCell::new(value)))
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Mutex for Shared<T> {
+    type T = T;
+
+    fn lock<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.0.borrow_mut())
+    }
+}
+
+/// GPIO pin. Clones share the same level.
+#[derive(Clone, Default)]
+pub struct TestPin(Rc<Cell<bool>>);
+
+impl TestPin {
+    pub fn set(&self, level: bool) {
+        self.0.set(level);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.get()
+    }
+}
+
+impl InputPin for TestPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.get())
+    }
+}
+
+impl OutputPin for TestPin {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.set(true);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.set(false);
+        Ok(())
+    }
+}
+
+/// CanTx which records every frame transmitted, in order
+#[derive(Default)]
+pub struct RecordTx {
+    pub frames: Vec<(Id, Vec<u8>)>,
+}
+
+impl CanTx for RecordTx {
+    fn transmit(&mut self, frame: &impl Frame) {
+        self.frames.push((frame.id(), frame.data().to_vec()));
+    }
+}
+
+/// CanRx fed by the test. Clones share the same queue of frames.
+#[derive(Clone, Default)]
+pub struct TestRx(Rc<RefCell<VecDeque<ClassicFrame>>>);
+
+impl TestRx {
+    pub fn push(&self, frame: ClassicFrame) {
+        self.0.borrow_mut().push_back(frame);
+    }
+}
+
+impl CanRx for TestRx {
+    type Frame = ClassicFrame;
+
+    async fn recv(&mut self) -> ClassicFrame {
+        // Nothing wakes the task, run_for() polls it every tick
+        core::future::poll_fn(|_| self.try_recv().map_or(Poll::Pending, Poll::Ready)).await
+    }
+
+    fn try_recv(&mut self) -> Option<ClassicFrame> {
+        self.0.borrow_mut().pop_front()
+    }
+}
+
+/// Poll a future once, the same as an executor would after a wakeup
+pub fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+/// Step the StepClock 1ms at a time for `duration`, polling `future` at each
+/// tick. Returns the output if it finished.
+pub fn run_for<F: Future>(mut future: Pin<&mut F>, duration: Duration) -> Option<F::Output> {
+    let end = StepClock::now() + duration;
+    loop {
+        if let Poll::Ready(output) = poll_once(future.as_mut()) {
+            return Some(output);
+        }
+        if StepClock::now() >= end {
+            return None;
+        }
+        StepClock::step(Duration::millis(1));
+    }
+}
+
+/// Responses to one request: each message is sent this long after the
+/// previous one, or after the request for the first
+pub type Script = Vec<(Duration, Vec<u8>)>;
+
+type Respond = Box<dyn FnMut(&[u8]) -> Script>;
+
+/// A module at the other end of an ISO-TP link, which answers each request
+/// Fakon sends it with a scripted list of responses
+pub struct TestEcu {
+    link: IsoTp,
+    tx: Shared<RecordTx>,
+    respond: Respond,
+    pending: VecDeque<(Instant, Vec<u8>)>,
+    /// Frames the tester sends, pass a clone as its CanTx
+    pub from_tester: Shared<RecordTx>,
+    /// Frames the module sends, pass a clone as the tester's CanRx
+    pub to_tester: TestRx,
+    /// Every request received
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl TestEcu {
+    /// `config` is the module's end of the link
+    pub fn new(config: isotp::Config, respond: impl FnMut(&[u8]) -> Script + 'static) -> Self {
+        Self {
+            link: IsoTp::new(config),
+            tx: Shared::new(RecordTx::default()),
+            respond: Box::new(respond),
+            pending: VecDeque::new(),
+            from_tester: Shared::new(RecordTx::default()),
+            to_tester: TestRx::default(),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Handle the frames the tester has sent, and send whatever is due
+    pub fn step(&mut self) {
+        let now = StepClock::now();
+        let frames = self.from_tester.lock(|tx| core::mem::take(&mut tx.frames));
+        for (id, data) in frames {
+            let frame = ClassicFrame::new(id, &data).unwrap();
+            if let Some(request) = self.link.on_frame(&frame, now, &mut self.tx).unwrap() {
+                let request = request.to_vec();
+                let mut at = now;
+                for (delay, response) in (self.respond)(&request) {
+                    at += delay;
+                    self.pending.push_back((at, response));
+                }
+                self.requests.push(request);
+            }
+        }
+
+        while let Some(&(at, _)) = self.pending.front() {
+            if now < at || self.link.is_sending() {
+                break;
+            }
+            let (_, response) = self.pending.pop_front().unwrap();
+            self.link.send(&response, now, &mut self.tx).unwrap();
+        }
+        self.link.poll(now, &mut self.tx).unwrap();
+
+        for (id, data) in self.tx.lock(|tx| core::mem::take(&mut tx.frames)) {
+            self.to_tester.push(ClassicFrame::new(id, &data).unwrap());
+        }
+    }
+
+    /// Same as run_for(), with the module answering as it goes
+    pub fn run<F: Future>(
+        &mut self,
+        mut future: Pin<&mut F>,
+        duration: Duration,
+    ) -> Option<F::Output> {
+        let end = StepClock::now() + duration;
+        loop {
+            if let Poll::Ready(output) = poll_once(future.as_mut()) {
+                return Some(output);
+            }
+            self.step();
+            if StepClock::now() >= end {
+                return None;
+            }
+            StepClock::step(Duration::millis(1));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Time source for the vehicle logic.
+use crate::{Duration, Instant};
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Poll;
+use defmt::Format;
+
+/// Source of time with a 1ms tick, and async delays.
+///
+/// Implemented by a zero sized marker type, with the same associated functions
+/// as rtic_monotonics::Monotonic so the firmware can wrap its Monotonic.
+pub trait Clock: Copy + Format + 'static {
+    /// Current time. Not expected to wrap, runtime is too short.
+    fn now() -> Instant;
+
+    fn delay(duration: Duration) -> impl Future<Output = ()>;
+
+    fn delay_until(instant: Instant) -> impl Future<Output = ()>;
+}
+
+/// Run `future` until `deadline`. Returns None if it didn't finish in time,
+/// in which case it's dropped.
+pub async fn timeout_at<C: Clock, F: Future>(deadline: Instant, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut delay = pin!(C::delay_until(deadline));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            Poll::Ready(Some(output))
+        } else if delay.as_mut().poll(cx).is_ready() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+static STEP_NOW: AtomicU32 = AtomicU32::new(0);
+
+/// Clock that only moves when it is stepped, for running the vehicle logic
+/// deterministically off target (e.g. to check exactly when a Fresh value
+/// goes stale.)
+///
+/// There is a single global time shared by every user of StepClock.
+#[derive(Clone, Copy, Format)]
+pub struct StepClock;
+
+impl StepClock {
+    /// Move time forward to `instant`. Any delay which has now expired
+    /// completes the next time it is polled.
+    pub fn advance_to(instant: Instant) {
+        let prev = STEP_NOW.swap(instant.ticks(), Ordering::Relaxed);
+        assert!(instant.ticks() >= prev, "StepClock can't go backwards");
+    }
+
+    /// Move time forward by `duration`.
+    pub fn step(duration: Duration) {
+        Self::advance_to(Self::now() + duration);
+    }
+
+    /// Set time back to zero, i.e. to start a new run.
+    pub fn reset() {
+        STEP_NOW.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Clock for StepClock {
+    fn now() -> Instant {
+        Instant::from_ticks(STEP_NOW.load(Ordering::Relaxed))
+    }
+
+    fn delay(duration: Duration) -> impl Future<Output = ()> {
+        Self::delay_until(Self::now() + duration)
+    }
+
+    // Doesn't register a waker, the caller is expected to poll every pending
+    // task each time it steps the clock.
+    fn delay_until(instant: Instant) -> impl Future<Output = ()> {
+        poll_fn(move |_| {
+            if Self::now() >= instant {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+}
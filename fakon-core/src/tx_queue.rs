@@ -0,0 +1,488 @@
+//! Software CAN TX queue, in front of a controller's hardware TX buffers.
+//!
+//! Decides which frame is written to hardware next: the highest priority
+//! one, with frames that arbitrate the same going first in, first out.
+//! Queued frames have latest-value semantics (see [crate::can::CanTx]),
+//! except for frames transmitted in order, which are all kept.
+//!
+//! The hardware itself is driven by the caller, which reports what it wrote
+//! to which buffer and which buffers are still pending.
+use crate::can::TxBuffers;
+use crate::can_stats::BusStats;
+use crate::Instant;
+use core::cmp::Ordering;
+use embedded_can::{Frame, Id};
+use heapless::Vec;
+
+/// A frame waiting to be sent
+pub struct Pending<F> {
+    frame: F,
+    expires: Instant,
+    /// Order the frame was submitted in, so frames which arbitrate the same
+    /// go out first in, first out.
+    seq: u32,
+    /// Part of a sequence (i.e. ISO-TP), so never replaced by a newer frame
+    /// with the same ID
+    in_order: bool,
+}
+
+impl<F: Frame> Pending<F> {
+    pub fn frame(&self) -> &F {
+        &self.frame
+    }
+
+    /// Compare transmit order, the frame which should be sent first is Greater
+    fn cmp_order(&self, other: &Self) -> Ordering {
+        // Id orders by arbitration, the higher priority ID is Less
+        other
+            .frame
+            .id()
+            .cmp(&self.frame.id())
+            // Wrapping, so this holds as long as the two were submitted
+            // less than 2^31 frames apart
+            .then_with(|| (other.seq.wrapping_sub(self.seq) as i32).cmp(&0))
+    }
+}
+
+/// Queue of up to N frames, for a controller with B hardware TX buffers
+pub struct TxQueue<F, const N: usize, const B: usize> {
+    queue: Vec<Pending<F>, N>,
+    // IDs of the frames waiting in the hardware TX buffers
+    hw_ids: TxBuffers<B>,
+    next_seq: u32,
+}
+
+impl<F: Frame, const N: usize, const B: usize> TxQueue<F, N, B> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            hw_ids: TxBuffers::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Update from the controller's transmission request pending bits, see
+    /// [TxBuffers::on_pending]. Call before submit() and pop_next().
+    pub fn on_pending(&mut self, pending: u32) {
+        self.hw_ids.on_pending(pending);
+    }
+
+    /// A frame with `id` was written to hardware buffer `index`
+    pub fn on_write(&mut self, index: usize, id: Id) {
+        self.hw_ids.on_write(index, id);
+    }
+
+    /// Submit a frame to send before `expires`. Returns it if it should be
+    /// written to hardware now, otherwise it's queued.
+    pub fn submit<const S: usize>(
+        &mut self,
+        frame: F,
+        expires: Instant,
+        in_order: bool,
+        now: Instant,
+        stats: &mut BusStats<S>,
+    ) -> Option<Pending<F>> {
+        let pending = Pending {
+            frame,
+            expires,
+            seq: self.stamp(),
+            in_order,
+        };
+        let id = pending.frame.id();
+        if self.queue.iter().any(|q| q.frame.id() == id) || self.hw_ids.contains(id) {
+            // An older frame with this ID is still waiting. Sending this one
+            // straight to hardware could overtake it (FDCAN sends equal IDs
+            // in buffer order, not the order they were written) so replace
+            // it, or queue behind it, instead.
+            self.enqueue(pending, true, now, stats);
+            None
+        } else {
+            Some(pending)
+        }
+    }
+
+    /// A frame from submit() or pop_next() couldn't be written to hardware
+    pub fn requeue<const S: usize>(
+        &mut self,
+        pending: Pending<F>,
+        now: Instant,
+        stats: &mut BusStats<S>,
+    ) {
+        self.enqueue(pending, true, now, stats);
+    }
+
+    /// A lower priority frame was taken back out of hardware to make room
+    /// for another. Its expiry isn't known any more, so it gets a new one.
+    /// It was submitted before anything still in the queue though, so it
+    /// goes ahead of any queued frame with the same ID.
+    pub fn on_replaced<const S: usize>(
+        &mut self,
+        frame: F,
+        expires: Instant,
+        now: Instant,
+        stats: &mut BusStats<S>,
+    ) {
+        let pending = Pending {
+            frame,
+            expires,
+            seq: self.oldest_seq().wrapping_sub(1),
+            in_order: false,
+        };
+        self.enqueue(pending, false, now, stats);
+    }
+
+    /// Remove the highest priority frame from the queue, skipping any with
+    /// the same ID as a frame still in hardware
+    pub fn pop_next<const S: usize>(
+        &mut self,
+        now: Instant,
+        stats: &mut BusStats<S>,
+    ) -> Option<Pending<F>> {
+        self.expire(now, stats);
+        let next = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, q)| !self.hw_ids.contains(q.frame.id()))
+            .max_by(|(_, a), (_, b)| a.cmp_order(b))
+            .map(|(idx, _)| idx)?;
+        Some(self.queue.swap_remove(next))
+    }
+
+    /// Forget everything, including what's in hardware
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.hw_ids.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // Add a frame to the queue. If a frame with the same ID is already
+    // queued then the newer of the two is kept, so `latest` is false for a
+    // frame which has come back out of hardware. Frames in a sequence are
+    // all kept, in order.
+    fn enqueue<const S: usize>(
+        &mut self,
+        pending: Pending<F>,
+        latest: bool,
+        now: Instant,
+        stats: &mut BusStats<S>,
+    ) {
+        self.expire(now, stats);
+
+        let id = pending.frame.id();
+        if let Some(queued) = self.queue.iter_mut().find(|q| q.frame.id() == id) {
+            if !queued.in_order && !pending.in_order {
+                if latest {
+                    *queued = pending;
+                }
+                stats.on_tx_replaced();
+                return;
+            }
+        }
+
+        if let Err(pending) = self.queue.push(pending) {
+            // Full, so something has to go. Drop the lowest priority frame,
+            // which may be this one, rather than the whole queue.
+            stats.on_tx_dropped();
+            let lowest = self
+                .queue
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.cmp_order(b))
+                .map(|(idx, _)| idx)
+                .unwrap();
+            if self.queue[lowest].cmp_order(&pending) == Ordering::Less {
+                self.queue[lowest] = pending;
+            }
+        }
+        stats.on_tx_queue_len(self.queue.len());
+    }
+
+    // Sequence number of the oldest queued frame, or the next one to be
+    // stamped if the queue is empty
+    fn oldest_seq(&self) -> u32 {
+        self.queue
+            .iter()
+            .map(|q| q.seq)
+            .max_by_key(|&seq| self.next_seq.wrapping_sub(seq))
+            .unwrap_or(self.next_seq)
+    }
+
+    fn stamp(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        seq
+    }
+
+    // Drop queued frames which are past their expiry
+    fn expire<const S: usize>(&mut self, now: Instant, stats: &mut BusStats<S>) {
+        let before = self.queue.len();
+        self.queue.retain(|q| q.expires > now);
+        stats.on_tx_expired(before - self.queue.len());
+    }
+}
+
+impl<F: Frame, const N: usize, const B: usize> Default for TxQueue<F, N, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::can::ClassicFrame;
+    use crate::Duration;
+    use embedded_can::StandardId;
+    use std::vec::Vec;
+
+    const ID: Id = Id::Standard(StandardId::new(0x7E8).unwrap());
+    const OTHER: Id = Id::Standard(StandardId::new(0x100).unwrap());
+    const LOW: Id = Id::Standard(StandardId::new(0x700).unwrap());
+    const HIGH: Id = Id::Standard(StandardId::new(0x050).unwrap());
+
+    const NOW: Instant = Instant::from_ticks(1000);
+    const EXPIRES: Instant = Instant::from_ticks(1100);
+
+    type Stats = BusStats<8>;
+
+    fn frame(id: Id, counter: u8) -> ClassicFrame {
+        ClassicFrame::new(id, &[counter]).unwrap()
+    }
+
+    fn sent(frame: &ClassicFrame) -> (Id, u8) {
+        (frame.id(), frame.data()[0])
+    }
+
+    /// Three hardware TX buffers which arbitrate like FDCAN: lowest ID
+    /// first, then lowest buffer index. New frames go in the lowest free
+    /// buffer, or if they're all full replace the lowest priority frame
+    /// (like fdcan's transmit_preserve).
+    ///
+    /// Feeds the TxQueue the same way can_queue's Tx does.
+    struct Hardware {
+        buffers: [Option<ClassicFrame>; 3],
+        queue: TxQueue<ClassicFrame, 8, 3>,
+        stats: Stats,
+        sent: Vec<(Id, u8)>,
+    }
+
+    impl Hardware {
+        fn new() -> Self {
+            Self {
+                buffers: [None; 3],
+                queue: TxQueue::new(),
+                stats: Stats::new(500_000),
+                sent: Vec::new(),
+            }
+        }
+
+        fn pending(&self) -> u32 {
+            (0..3)
+                .filter(|&i| self.buffers[i].is_some())
+                .map(|i| 1 << i)
+                .sum()
+        }
+
+        fn submit(&mut self, frame: ClassicFrame, in_order: bool) {
+            self.queue.on_pending(self.pending());
+            if let Some(pending) = self
+                .queue
+                .submit(frame, EXPIRES, in_order, NOW, &mut self.stats)
+            {
+                self.write(pending);
+            }
+        }
+
+        fn write(&mut self, pending: Pending<ClassicFrame>) {
+            let id = pending.frame().id();
+            if let Some(index) = self.buffers.iter().position(Option::is_none) {
+                self.buffers[index] = Some(*pending.frame());
+                self.queue.on_write(index, id);
+                return;
+            }
+            let (index, lowest) = (0..3)
+                .map(|i| (i, self.buffers[i].unwrap()))
+                .max_by_key(|(_, f)| f.id())
+                .unwrap();
+            if id < lowest.id() {
+                self.buffers[index] = Some(*pending.frame());
+                self.queue.on_write(index, id);
+                self.queue
+                    .on_replaced(lowest, EXPIRES, NOW, &mut self.stats);
+            } else {
+                self.queue.requeue(pending, NOW, &mut self.stats);
+            }
+        }
+
+        /// Win arbitration with the highest priority buffer, then move the
+        /// next frame from the queue (like the TX complete interrupt)
+        fn send_one(&mut self) {
+            let next = (0..3)
+                .filter_map(|i| self.buffers[i].map(|f| (f.id(), i)))
+                .min()
+                .map(|(_, i)| i)
+                .unwrap();
+            self.sent.push(sent(&self.buffers[next].take().unwrap()));
+
+            self.queue.on_pending(self.pending());
+            if let Some(pending) = self.queue.pop_next(NOW, &mut self.stats) {
+                self.write(pending);
+            }
+        }
+
+        fn send_all(&mut self) {
+            while self.buffers.iter().any(Option::is_some) {
+                self.send_one();
+            }
+        }
+
+        fn counters(&self, id: Id) -> Vec<u8> {
+            self.sent
+                .iter()
+                .filter(|(sent_id, _)| *sent_id == id)
+                .map(|(_, counter)| *counter)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn same_id_not_overtaken_in_hardware() {
+        let mut hw = Hardware::new();
+
+        // Buffer 0 is busy with another frame when counter 0 goes into
+        // buffer 1. Once buffer 0 is free, counter 1 would go there and win
+        // the tie with buffer 1 if it were written to hardware.
+        hw.submit(frame(OTHER, 0), false);
+        hw.submit(frame(ID, 0), true);
+        hw.send_one();
+        for counter in 1..10 {
+            hw.submit(frame(ID, counter), true);
+            if counter % 3 == 0 {
+                hw.send_one();
+            }
+        }
+        hw.send_all();
+
+        assert_eq!(hw.counters(ID), (0..10).collect::<Vec<u8>>());
+        assert!(hw.queue.is_empty());
+        assert_eq!(hw.stats.tx_dropped, 0);
+    }
+
+    #[test]
+    fn latest_value_replaces_queued() {
+        let mut hw = Hardware::new();
+        hw.submit(frame(ID, 0), false);
+        hw.submit(frame(ID, 1), false);
+        hw.submit(frame(ID, 2), false);
+        hw.send_all();
+
+        // 0 was already in hardware, 2 replaced 1 in the queue
+        assert_eq!(hw.counters(ID), [0, 2]);
+        assert_eq!(hw.stats.tx_replaced, 1);
+    }
+
+    #[test]
+    fn priority_order_then_submission_order() {
+        let mut hw = Hardware::new();
+        // Fill the hardware with higher priority frames, so the rest queue
+        for raw in 0x10..0x13 {
+            hw.submit(frame(Id::Standard(StandardId::new(raw).unwrap()), 0), false);
+        }
+        hw.submit(frame(LOW, 0), true);
+        hw.submit(frame(ID, 0), true);
+        hw.submit(frame(OTHER, 0), true);
+        hw.submit(frame(ID, 1), true);
+        assert_eq!(hw.queue.len(), 4);
+        hw.send_all();
+
+        assert_eq!(hw.sent[3..], [(OTHER, 0), (LOW, 0), (ID, 0), (ID, 1)]);
+    }
+
+    #[test]
+    fn replaced_frame_goes_ahead_of_queued_same_id() {
+        let mut hw = Hardware::new();
+        hw.submit(frame(ID, 0), true);
+        hw.submit(frame(LOW, 0), true);
+        hw.submit(frame(OTHER, 0), true);
+        hw.submit(frame(ID, 1), true);
+        // Buffers are full, so this takes the lowest priority one from ID 0
+        hw.submit(frame(HIGH, 0), true);
+        assert_eq!(hw.queue.len(), 2);
+        hw.send_all();
+
+        assert_eq!(hw.sent[0], (HIGH, 0));
+        assert_eq!(hw.counters(ID), [0, 1]);
+    }
+
+    #[test]
+    fn replaced_frame_older_than_queued_same_id() {
+        let mut hw = Hardware::new();
+        hw.submit(frame(ID, 0), false);
+        hw.submit(frame(LOW, 0), false);
+        hw.submit(frame(OTHER, 0), false);
+        hw.submit(frame(ID, 1), false);
+        hw.submit(frame(HIGH, 0), false);
+        hw.send_all();
+
+        // The latest value was already queued
+        assert_eq!(hw.counters(ID), [1]);
+        assert_eq!(hw.stats.tx_replaced, 1);
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let mut hw = Hardware::new();
+        hw.queue.next_seq = u32::MAX - 1;
+        for counter in 0..4 {
+            hw.submit(frame(ID, counter), true);
+        }
+        hw.send_all();
+        assert_eq!(hw.counters(ID), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn expired_frames_dropped() {
+        let mut queue = TxQueue::<ClassicFrame, 4, 3>::new();
+        let mut stats = Stats::new(500_000);
+        queue.on_write(0, ID);
+        assert!(queue
+            .submit(frame(ID, 0), EXPIRES, false, NOW, &mut stats)
+            .is_none());
+        queue.on_pending(0);
+
+        let later = EXPIRES + Duration::millis(1);
+        assert!(queue.pop_next(later, &mut stats).is_none());
+        assert_eq!(stats.tx_expired, 1);
+    }
+
+    #[test]
+    fn full_queue_drops_lowest_priority() {
+        let mut queue = TxQueue::<ClassicFrame, 2, 3>::new();
+        let mut stats = Stats::new(500_000);
+        // Hardware busy with all three IDs, so everything queues
+        queue.on_write(0, ID);
+        queue.on_write(1, OTHER);
+        queue.on_write(2, LOW);
+        for (id, counter) in [(LOW, 1), (ID, 1), (OTHER, 1)] {
+            assert!(queue
+                .submit(frame(id, counter), EXPIRES, true, NOW, &mut stats)
+                .is_none());
+        }
+        assert_eq!(stats.tx_dropped, 1);
+
+        queue.on_pending(0);
+        let first = queue.pop_next(NOW, &mut stats).unwrap();
+        let second = queue.pop_next(NOW, &mut stats).unwrap();
+        assert_eq!(sent(first.frame()), (OTHER, 1));
+        assert_eq!(sent(second.frame()), (LOW, 1));
+        assert!(queue.pop_next(NOW, &mut stats).is_none());
+    }
+}
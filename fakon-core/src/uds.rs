@@ -0,0 +1,526 @@
+//! UDS (ISO 14229) diagnostics client, so Fakon can act as a tester: read
+//! the fault codes the transplanted Kona modules have stored without
+//! plugging in a scan tool, and poll data the modules don't broadcast.
+//!
+//! Requests go out over ISO-TP on PCAN. The module addresses are the ones
+//! from the Kona's OBD-II port, the transplanted modules keep them.
+use crate::bms;
+use crate::can::{CanRx, CanTx};
+use crate::car::{CarState, Ignition};
+use crate::isotp::{self, IsoTp, MAX_MESSAGE};
+use crate::time::{self, Clock};
+use crate::Duration;
+use core::fmt::Write;
+use defmt::Format;
+use embedded_can::{Id, StandardId};
+use fugit::ExtU32;
+use heapless::String;
+use rtic_core::Mutex;
+
+pub(crate) const SID_CLEAR_DIAGNOSTIC_INFORMATION: u8 = 0x14;
+pub(crate) const SID_READ_DTC_INFORMATION: u8 = 0x19;
+pub(crate) const SID_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+/// Added to the service ID in a positive response
+pub(crate) const POSITIVE_RESPONSE: u8 = 0x40;
+pub(crate) const NEGATIVE_RESPONSE: u8 = 0x7F;
+/// Negative response code: request received, the response will be late
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+/// ReadDTCInformation sub-function: reportDTCByStatusMask
+pub(crate) const REPORT_DTC_BY_STATUS_MASK: u8 = 0x02;
+/// Report every DTC the module has stored, whatever its status
+const DTC_STATUS_ALL: u8 = 0xFF;
+
+/// How long a module has to start responding (P2)
+const RESPONSE_TIMEOUT: Duration = Duration::millis(150);
+/// How long a module has to respond after it says the response is pending (P2*)
+const PENDING_TIMEOUT: Duration = Duration::millis(5000);
+
+/// How often to read the DTCs while the car is on
+const DTC_READ_PERIOD: Duration = Duration::secs(60);
+/// The modules take a while to start answering after the car turns on
+const WAKE_DELAY: Duration = Duration::secs(5);
+
+/// A module which answers diagnostic requests
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    pub name: &'static str,
+    /// ID requests are sent to
+    pub request: Id,
+    /// ID the module responds on
+    pub response: Id,
+}
+
+impl Module {
+    const fn new(name: &'static str, request: u16, response: u16) -> Self {
+        // Unwrap: all diagnostic IDs are valid standard IDs
+        Self {
+            name,
+            request: Id::Standard(StandardId::new(request).unwrap()),
+            response: Id::Standard(StandardId::new(response).unwrap()),
+        }
+    }
+}
+
+pub const VCU: Module = Module::new("VCU", 0x7E2, 0x7EA);
+pub const MCU: Module = Module::new("MCU", 0x7E3, 0x7EB);
+pub const BMS: Module = Module::new("BMS", 0x7E4, 0x7EC);
+pub const OBC: Module = Module::new("OBC", 0x7E5, 0x7ED);
+pub const IEB: Module = Module::new("IEB", 0x7D1, 0x7D9);
+
+/// The transplanted modules whose DTCs are read
+pub const POWERTRAIN: &[Module] = &[VCU, MCU, BMS, OBC, IEB];
+
+/// Is this ID a response from one of the modules Fakon sends requests to?
+pub fn is_response(id: Id) -> bool {
+    POWERTRAIN.iter().any(|module| module.response == id)
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Error {
+    IsoTp(isotp::Error),
+    /// No response in time
+    Timeout,
+    /// Module responded with this Negative Response Code
+    Negative(u8),
+    /// Response was for a different service, or too short
+    Unexpected,
+}
+
+impl From<isotp::Error> for Error {
+    fn from(err: isotp::Error) -> Self {
+        Error::IsoTp(err)
+    }
+}
+
+/// A Diagnostic Trouble Code and its status, i.e. P1A6F00
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dtc {
+    /// 3 byte DTC: 2 bytes of code, and a failure type byte
+    pub code: u32,
+    /// Status bits as defined by ISO 14229
+    pub status: u8,
+}
+
+impl Dtc {
+    /// Has the most recent test failed?
+    pub fn is_active(&self) -> bool {
+        self.status & 0x01 != 0
+    }
+
+    /// Has the fault been confirmed, i.e. it would light a warning lamp?
+    pub fn is_confirmed(&self) -> bool {
+        self.status & 0x08 != 0
+    }
+
+    /// The code as a scan tool shows it, i.e. "P1A6F00"
+    pub fn name(&self) -> String<7> {
+        // The top two bits are the system, then the first digit of the code
+        let system = ['P', 'C', 'B', 'U'][(self.code >> 22) as usize & 0x3];
+        let mut name = String::new();
+        // Unwrap: always 7 characters
+        write!(name, "{}{:06X}", system, self.code & 0x3F_FFFF).unwrap();
+        name
+    }
+}
+
+impl Format for Dtc {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str} status {=u8:#04x}", self.name(), self.status);
+    }
+}
+
+/// The DTCs in a reportDTCByStatusMask response (without the service ID)
+fn parse_dtcs(response: &[u8]) -> Result<impl Iterator<Item = Dtc> + '_, Error> {
+    // Sub-function and status availability mask, then 4 bytes per DTC
+    let Some((&REPORT_DTC_BY_STATUS_MASK, rest)) = response.split_first() else {
+        return Err(Error::Unexpected);
+    };
+    Ok(rest
+        .get(1..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .map(|dtc| Dtc {
+            code: u32::from_be_bytes([0, dtc[0], dtc[1], dtc[2]]),
+            status: dtc[3],
+        }))
+}
+
+/// Send a request to a module and wait for its positive response, which is
+/// copied to `response` without the service ID. Returns the response length.
+pub async fn request<C, RX, MTX, TX>(
+    module: &Module,
+    request: &[u8],
+    rx: &mut RX,
+    tx: &mut MTX,
+    response: &mut [u8],
+) -> Result<usize, Error>
+where
+    C: Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let sid = request[0];
+    // Anything already received is left over from an earlier request (i.e. a
+    // response which arrived after it timed out), not the response to this
+    // one. The link is new, so it isn't part way through receiving either.
+    while rx.try_recv().is_some() {}
+    let mut link = IsoTp::new(isotp::Config::new(module.request, module.response));
+    link.send(request, C::now(), tx)?;
+    let mut deadline = C::now() + RESPONSE_TIMEOUT;
+
+    loop {
+        let wake = link
+            .next_deadline()
+            .map_or(deadline, |next| next.min(deadline));
+        let Some(frame) = time::timeout_at::<C, _>(wake, rx.recv()).await else {
+            link.poll(C::now(), tx)?;
+            // The response timer only runs until the response starts arriving
+            if C::now() >= deadline && !link.is_sending() && !link.is_receiving() {
+                return Err(Error::Timeout);
+            }
+            continue;
+        };
+
+        let now = C::now();
+        match link.on_frame(&frame, now, tx)? {
+            None => (),
+            Some(&[NEGATIVE_RESPONSE, nrc_sid, NRC_RESPONSE_PENDING]) if nrc_sid == sid => {
+                deadline = now + PENDING_TIMEOUT;
+            }
+            Some(&[NEGATIVE_RESPONSE, nrc_sid, nrc]) if nrc_sid == sid => {
+                return Err(Error::Negative(nrc));
+            }
+            Some([resp_sid, data @ ..]) if *resp_sid == sid | POSITIVE_RESPONSE => {
+                let len = data.len().min(response.len());
+                response[..len].copy_from_slice(&data[..len]);
+                return Ok(len);
+            }
+            Some(_) => return Err(Error::Unexpected),
+        }
+    }
+}
+
+/// Read a data identifier from a module. The data (without the identifier)
+/// is copied to `data`, returns its length.
+pub async fn read_data<C, RX, MTX, TX>(
+    module: &Module,
+    did: u16,
+    rx: &mut RX,
+    tx: &mut MTX,
+    data: &mut [u8],
+) -> Result<usize, Error>
+where
+    C: Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let mut response = [0u8; MAX_MESSAGE];
+    let [did_hi, did_lo] = did.to_be_bytes();
+    let req = [SID_READ_DATA_BY_IDENTIFIER, did_hi, did_lo];
+    let len = request::<C, _, _, _>(module, &req, rx, tx, &mut response).await?;
+
+    match &response[..len] {
+        [hi, lo, rest @ ..] if [*hi, *lo] == [did_hi, did_lo] => {
+            let len = rest.len().min(data.len());
+            data[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        }
+        _ => Err(Error::Unexpected),
+    }
+}
+
+/// Read every DTC a module has stored, and log them.
+pub async fn read_dtcs<C, RX, MTX, TX>(
+    module: &Module,
+    rx: &mut RX,
+    tx: &mut MTX,
+) -> Result<(), Error>
+where
+    C: Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let mut response = [0u8; MAX_MESSAGE];
+    let req = [
+        SID_READ_DTC_INFORMATION,
+        REPORT_DTC_BY_STATUS_MASK,
+        DTC_STATUS_ALL,
+    ];
+    let len = request::<C, _, _, _>(module, &req, rx, tx, &mut response).await?;
+
+    let mut count = 0;
+    for dtc in parse_dtcs(&response[..len])? {
+        if dtc.is_active() || dtc.is_confirmed() {
+            defmt::warn!("{} DTC {}", module.name, dtc);
+        } else {
+            defmt::info!("{} DTC {}", module.name, dtc);
+        }
+        count += 1;
+    }
+    if count == 0 {
+        defmt::info!("{} has no DTCs", module.name);
+    }
+    Ok(())
+}
+
+/// Clear every DTC a module has stored.
+pub async fn clear_dtcs<C, RX, MTX, TX>(
+    module: &Module,
+    rx: &mut RX,
+    tx: &mut MTX,
+) -> Result<(), Error>
+where
+    C: Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    // Group of DTCs: all groups
+    let req = [SID_CLEAR_DIAGNOSTIC_INFORMATION, 0xFF, 0xFF, 0xFF];
+    request::<C, _, _, _>(module, &req, rx, tx, &mut []).await?;
+    defmt::info!("{} DTCs cleared", module.name);
+    Ok(())
+}
+
+/// Fakon's diagnostic tester: reads the powertrain modules' DTCs each time
+/// the car turns on (so cycling the ignition is a way to ask for them) and
+/// every DTC_READ_PERIOD after that, and polls the BMS for cell level data.
+///
+/// This is one task so that only one request is in flight at a time, the
+/// responses all come through the same `rx`.
+///
+/// If `clear_at_startup` is set, the DTCs are cleared (after being read) the
+/// first time the car turns on after Fakon starts.
+pub async fn task_tester<C, MCAR, RX, MTX, TX>(
+    mut car: MCAR,
+    rx: &mut RX,
+    mut pcan_tx: MTX,
+    clear_at_startup: bool,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+{
+    let mut clear = clear_at_startup;
+    loop {
+        while car.lock(|car| car.ignition() == Ignition::Off) {
+            C::delay(100.millis()).await;
+        }
+        C::delay(WAKE_DELAY).await;
+
+        let mut next_dtc_read = C::now();
+        let mut next_bms_poll = C::now();
+        // Start again from reading DTCs when the car turns back on
+        while car.lock(|car| car.ignition() != Ignition::Off) {
+            if C::now() >= next_dtc_read {
+                for module in POWERTRAIN {
+                    if let Err(err) = read_dtcs::<C, _, _, _>(module, rx, &mut pcan_tx).await {
+                        defmt::warn!("{} DTC read failed: {}", module.name, err);
+                        continue;
+                    }
+                    if clear {
+                        if let Err(err) = clear_dtcs::<C, _, _, _>(module, rx, &mut pcan_tx).await {
+                            defmt::warn!("{} DTC clear failed: {}", module.name, err);
+                        }
+                    }
+                }
+                clear = false;
+                next_dtc_read = C::now() + DTC_READ_PERIOD;
+            }
+
+            if C::now() >= next_bms_poll {
+                if let Err(err) =
+                    bms::poll_detail::<C, _, _, _, _>(&mut car, rx, &mut pcan_tx).await
+                {
+                    defmt::warn!("BMS poll failed: {}", err);
+                }
+                next_bms_poll = C::now() + bms::POLL_PERIOD;
+            }
+
+            C::delay(100.millis()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::can::ClassicFrame;
+    use crate::test_util::{step_clock, Script, TestEcu};
+    use crate::time::StepClock;
+    use crate::Instant;
+    use core::pin::pin;
+    use embedded_can::Frame;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn bms(respond: impl FnMut(&[u8]) -> Script + 'static) -> TestEcu {
+        TestEcu::new(isotp::Config::new(BMS.response, BMS.request), respond)
+    }
+
+    fn after(ms: u32, message: &[u8]) -> (Duration, Vec<u8>) {
+        (Duration::millis(ms), message.to_vec())
+    }
+
+    /// Read DID 0x0101 from the BMS
+    fn read_0101(ecu: &mut TestEcu, data: &mut [u8]) -> Option<Result<usize, Error>> {
+        let mut rx = ecu.to_tester.clone();
+        let mut tx = ecu.from_tester.clone();
+        let read = pin!(read_data::<StepClock, _, _, _>(
+            &BMS, 0x0101, &mut rx, &mut tx, data
+        ));
+        ecu.run(read, Duration::secs(10))
+    }
+
+    #[test]
+    fn positive_response() {
+        let _clock = step_clock();
+        let mut response = vec![0x62, 0x01, 0x01];
+        response.extend(0..20);
+        let expected = response.clone();
+        let mut ecu = bms(move |_| vec![after(20, &expected)]);
+        let mut data = [0; 32];
+        assert_eq!(read_0101(&mut ecu, &mut data), Some(Ok(20)));
+        assert_eq!(data[..20], response[3..]);
+        assert_eq!(ecu.requests, vec![vec![0x22, 0x01, 0x01]]);
+    }
+
+    #[test]
+    fn negative_response() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| vec![after(20, &[0x7F, 0x22, 0x31])]);
+        assert_eq!(
+            read_0101(&mut ecu, &mut []),
+            Some(Err(Error::Negative(0x31)))
+        );
+    }
+
+    #[test]
+    fn response_pending() {
+        let _clock = step_clock();
+        // Much later than the usual response timeout, but in time after a
+        // response pending
+        let mut ecu = bms(|_| {
+            vec![
+                after(20, &[0x7F, 0x22, 0x78]),
+                after(4000, &[0x62, 0x01, 0x01, 0x42]),
+            ]
+        });
+        let mut data = [0; 1];
+        assert_eq!(read_0101(&mut ecu, &mut data), Some(Ok(1)));
+        assert_eq!(data, [0x42]);
+    }
+
+    #[test]
+    fn response_pending_timeout() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| vec![after(20, &[0x7F, 0x22, 0x78])]);
+        assert_eq!(read_0101(&mut ecu, &mut []), Some(Err(Error::Timeout)));
+        // The pending timeout starts when the response pending arrives
+        assert!(StepClock::now() >= Instant::from_ticks(5020));
+    }
+
+    #[test]
+    fn no_response() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| Vec::new());
+        assert_eq!(read_0101(&mut ecu, &mut []), Some(Err(Error::Timeout)));
+        assert!(StepClock::now() < Instant::from_ticks(200));
+    }
+
+    #[test]
+    fn response_for_other_did() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| vec![after(20, &[0x62, 0x01, 0x05, 0x42])]);
+        assert_eq!(
+            read_0101(&mut ecu, &mut [0; 1]),
+            Some(Err(Error::Unexpected))
+        );
+    }
+
+    #[test]
+    fn stale_frames_discarded() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| vec![after(20, &[0x62, 0x01, 0x01, 0x42])]);
+        // A late single frame response, and the start of a multi-frame one,
+        // from before this request
+        let stale = [
+            [0x04, 0x62, 0x01, 0x01, 0x99, 0xAA, 0xAA, 0xAA],
+            [0x10, 0x20, 0x62, 0x01, 0x01, 0x99, 0x99, 0x99],
+        ];
+        for data in stale {
+            ecu.to_tester
+                .push(ClassicFrame::new(BMS.response, &data).unwrap());
+        }
+
+        let mut data = [0; 1];
+        assert_eq!(read_0101(&mut ecu, &mut data), Some(Ok(1)));
+        assert_eq!(data, [0x42]);
+        // No flow control was sent for the stale first frame
+        assert_eq!(ecu.requests, vec![vec![0x22, 0x01, 0x01]]);
+        assert!(ecu.from_tester.lock(|tx| tx.frames.is_empty()));
+    }
+
+    #[test]
+    fn read_dtcs_response() {
+        let _clock = step_clock();
+        let mut ecu = bms(|_| {
+            vec![after(
+                20,
+                &[
+                    0x59, 0x02, 0xFF, 0x1A, 0x6F, 0x00, 0x09, 0xC1, 0x01, 0x87, 0x08,
+                ],
+            )]
+        });
+        let mut rx = ecu.to_tester.clone();
+        let mut tx = ecu.from_tester.clone();
+        let read = pin!(read_dtcs::<StepClock, _, _, _>(&BMS, &mut rx, &mut tx));
+        assert_eq!(ecu.run(read, Duration::secs(1)), Some(Ok(())));
+        assert_eq!(ecu.requests, vec![vec![0x19, 0x02, 0xFF]]);
+    }
+
+    #[test]
+    fn parse_dtc_list() {
+        let response = [
+            0x02, 0xFF, 0x1A, 0x6F, 0x00, 0x09, 0xC1, 0x01, 0x87, 0x08, 0x12,
+        ];
+        let dtcs: Vec<_> = parse_dtcs(&response).unwrap().collect();
+        // The incomplete DTC at the end is ignored
+        assert_eq!(
+            dtcs,
+            vec![
+                Dtc {
+                    code: 0x1A6F00,
+                    status: 0x09
+                },
+                Dtc {
+                    code: 0xC10187,
+                    status: 0x08
+                },
+            ]
+        );
+        assert!(dtcs[0].is_active() && dtcs[0].is_confirmed());
+        assert!(!dtcs[1].is_active() && dtcs[1].is_confirmed());
+
+        assert_eq!(parse_dtcs(&[0x02, 0xFF]).unwrap().count(), 0);
+        assert_eq!(parse_dtcs(&[0x02]).unwrap().count(), 0);
+        assert_eq!(parse_dtcs(&[]).err(), Some(Error::Unexpected));
+        assert_eq!(parse_dtcs(&[0x0A, 0xFF]).err(), Some(Error::Unexpected));
+    }
+
+    #[test]
+    fn dtc_name() {
+        let name = |code| Dtc { code, status: 0 }.name();
+        assert_eq!(name(0x1A6F00), "P1A6F00");
+        assert_eq!(name(0x400123), "C000123");
+        assert_eq!(name(0x8A1234), "B0A1234");
+        assert_eq!(name(0xC10187), "U010187");
+        assert_eq!(name(0xFFFFFF), "U3FFFFF");
+    }
+}
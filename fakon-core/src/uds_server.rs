@@ -0,0 +1,978 @@
+//! UDS server, so a running Fakon can be inspected with a standard scan tool.
+//!
+//! Fakon answers physically addressed requests on its own pair of IDs, which
+//! aren't used by any of the Kona modules. Supported services:
+//!
+//! - DiagnosticSessionControl: default and extended sessions. The extended
+//!   session times out without TesterPresent, as usual.
+//! - TesterPresent
+//! - ReadDataByIdentifier: the DID_ constants below, and F195 (version)
+//! - ReadDTCInformation (reportDTCByStatusMask) and
+//!   ClearDiagnosticInformation, for Fakon's own faults (DTC_ constants)
+//! - RoutineControl (start only, extended session): the ROUTINE_ constants
+//!
+//! Multi-byte values are big endian.
+//!
+//! The ISO-TP link and the session services are handled by `serve`, which
+//! is also used to emulate other modules' diagnostics (see igpm).
+use crate::can::{CanRx, CanTx};
+use crate::can_stats::Counters;
+use crate::car::{CarState, ChargeLock, Contactor, Gear, Ignition};
+use crate::fresh::IsFresh;
+use crate::isotp::{self, IsoTp, MAX_MESSAGE};
+use crate::shift_control::ActuatorState;
+use crate::time::{self, Clock};
+use crate::uds::{
+    NEGATIVE_RESPONSE, POSITIVE_RESPONSE, REPORT_DTC_BY_STATUS_MASK,
+    SID_CLEAR_DIAGNOSTIC_INFORMATION, SID_READ_DATA_BY_IDENTIFIER, SID_READ_DTC_INFORMATION,
+};
+use crate::{Duration, Instant};
+use embedded_can::{Id, StandardId};
+use rtic_core::Mutex;
+
+/// ID Fakon receives requests on
+pub const REQUEST_ID: Id = Id::Standard(StandardId::new(0x7F0).unwrap());
+/// ID Fakon responds on
+pub const RESPONSE_ID: Id = Id::Standard(StandardId::new(0x7F8).unwrap());
+
+const SID_DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+const SID_ROUTINE_CONTROL: u8 = 0x31;
+const SID_TESTER_PRESENT: u8 = 0x3E;
+
+/// Sub-function bit asking for no positive response
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+const SESSION_DEFAULT: u8 = 0x01;
+const SESSION_EXTENDED: u8 = 0x03;
+/// Non-default sessions end if there's no request for this long (S3)
+const SESSION_TIMEOUT: Duration = Duration::secs(5);
+
+const ROUTINE_START: u8 = 0x01;
+
+pub(crate) const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+pub(crate) const NRC_INCORRECT_LENGTH: u8 = 0x13;
+const NRC_RESPONSE_TOO_LONG: u8 = 0x14;
+const NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+pub(crate) const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+const NRC_SERVICE_NOT_SUPPORTED_IN_SESSION: u8 = 0x7F;
+
+/// Firmware version, ASCII
+pub const DID_VERSION: u16 = 0xF195;
+/// Ignition: 0 off, 1 IG3, 2 on
+pub const DID_IGNITION: u16 = 0x0100;
+/// Contactors: 0 open, 1 pre-charging, 2 closed, FF unknown
+pub const DID_CONTACTOR: u16 = 0x0101;
+/// Gear: 0 park, 1 neutral, 2 drive, 3 reverse, FF unknown
+pub const DID_GEAR: u16 = 0x0102;
+/// State of charge, u16 in 0.1%
+pub const DID_SOC: u16 = 0x0103;
+/// Charge port: 0 unlocked, 1 locked
+pub const DID_CHARGE_PORT: u16 = 0x0104;
+/// Emulated park actuator: 0 unlocked, 1 locked, FF unknown
+pub const DID_PARK_ACTUATOR: u16 = 0x0105;
+/// PCAN statistics: RX frames, TX frames, RX overruns, RX dropped, TX
+/// replaced, TX expired, TX dropped (u32 each), TX queue high water (u16),
+/// load % (u8), then bus state (0 error active, 1 error passive, 2 bus off),
+/// TEC, REC (u8 each) and the Bus Off count (u16)
+pub const DID_PCAN_STATS: u16 = 0x0110;
+
+/// Start moving the charge port lock to locked
+pub const ROUTINE_LOCK_CHARGE_PORT: u16 = 0x0201;
+/// Start moving the charge port lock to unlocked
+pub const ROUTINE_UNLOCK_CHARGE_PORT: u16 = 0x0202;
+/// Switch the IG3 relay off and on again. Ignition must be on.
+pub const ROUTINE_CYCLE_IG3: u16 = 0x0203;
+
+/// U0001-88, PCAN is Bus Off
+pub const DTC_PCAN_BUS_OFF: u32 = 0xC0_01_88;
+/// U0001-00, PCAN RX has been dropping frames
+pub const DTC_PCAN_RX_OVERLOAD: u32 = 0xC0_01_00;
+/// U0100-87, no PCAN frames received once the ignition has been on for
+/// PCAN_SILENT_GRACE
+pub const DTC_PCAN_SILENT: u32 = 0xC1_00_87;
+/// U3000-49, the CAN self-test failed at boot
+pub const DTC_SELF_TEST: u32 = 0xF0_00_49;
+
+const DTCS: [u32; 4] = [
+    DTC_PCAN_BUS_OFF,
+    DTC_PCAN_RX_OVERLOAD,
+    DTC_PCAN_SILENT,
+    DTC_SELF_TEST,
+];
+
+/// How long the ignition has to be on before PCAN being silent is a fault.
+/// The modules take a moment to start sending, and Fakon can boot with the
+/// ignition already on.
+const PCAN_SILENT_GRACE: Duration = Duration::secs(5);
+
+/// DTC status bits: testFailed and confirmedDTC, the only ones supported
+const DTC_TEST_FAILED: u8 = 0x01;
+const DTC_CONFIRMED: u8 = 0x08;
+
+/// The parts of the server which depend on the firmware
+pub trait Platform<TX> {
+    /// Firmware version string
+    fn version(&self) -> &'static str;
+
+    /// Did the CAN self-test pass at boot?
+    fn self_test_ok(&self) -> bool;
+
+    /// PCAN statistics, which are kept by the TX queue
+    fn pcan_counters(&self, tx: &TX) -> Counters;
+
+    /// Start moving the charge port lock. Returns false if it's already
+    /// moving.
+    fn move_charge_port(&mut self, direction: ChargeLock) -> bool;
+}
+
+/// Response being built, starting with the positive response service ID
+pub(crate) struct Response {
+    buf: [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+impl Response {
+    pub(crate) fn new(sid: u8) -> Self {
+        let mut buf = [0; MAX_MESSAGE];
+        buf[0] = sid | POSITIVE_RESPONSE;
+        Self { buf, len: 1 }
+    }
+
+    /// Append to the response. A request can ask for more than fits (e.g.
+    /// the same DID over and over), which is responseTooLong.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<(), u8> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(NRC_RESPONSE_TOO_LONG)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Outcome of a request: a response, a negative response code, or nothing
+/// (suppressed positive response)
+pub(crate) type Outcome = Result<Option<Response>, u8>;
+
+/// The services one server provides, on top of the ones `serve` handles
+pub(crate) trait Handler<MTX> {
+    /// Handle a request. `extended` is set in the extended session.
+    fn handle(&mut self, request: &[u8], extended: bool, tx: &mut MTX) -> Outcome;
+
+    /// Called at least every UPDATE_PERIOD, whether or not there are requests
+    fn update(&mut self) {}
+}
+
+/// How often `serve` calls Handler::update
+const UPDATE_PERIOD: Duration = Duration::millis(100);
+
+struct Server<MCAR, MPARK, P> {
+    car: MCAR,
+    park: MPARK,
+    platform: P,
+    /// Faults which have happened since the DTCs were last cleared, one bit
+    /// per entry in DTCS
+    confirmed: u8,
+    /// PCAN can go Bus Off and recover between updates, so count them too
+    bus_off_count: u16,
+    /// When the ignition was last seen to turn on, None while it's off
+    ignition_on_since: Option<Instant>,
+}
+
+impl<C, MCAR, MPARK, MTX, TX, P> Handler<MTX> for Server<MCAR, MPARK, P>
+where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+    P: Platform<TX>,
+{
+    fn handle(&mut self, request: &[u8], extended: bool, tx: &mut MTX) -> Outcome {
+        let (&sid, args) = request.split_first().ok_or(NRC_INCORRECT_LENGTH)?;
+        match sid {
+            SID_READ_DATA_BY_IDENTIFIER => {
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(NRC_INCORRECT_LENGTH);
+                }
+                let mut response = Response::new(sid);
+                for did in args.chunks_exact(2) {
+                    response.push(did)?;
+                    let did = u16::from_be_bytes([did[0], did[1]]);
+                    self.read_data(did, tx, &mut response)?;
+                }
+                Ok(Some(response))
+            }
+            SID_READ_DTC_INFORMATION => {
+                let &[REPORT_DTC_BY_STATUS_MASK, mask] = args else {
+                    return Err(match args.first() {
+                        Some(&REPORT_DTC_BY_STATUS_MASK) => NRC_INCORRECT_LENGTH,
+                        _ => NRC_SUB_FUNCTION_NOT_SUPPORTED,
+                    });
+                };
+                let mut response = Response::new(sid);
+                response.push(&[REPORT_DTC_BY_STATUS_MASK, DTC_TEST_FAILED | DTC_CONFIRMED])?;
+                let active = self.active_faults::<TX>();
+                for (i, dtc) in DTCS.iter().enumerate() {
+                    let mut status = 0;
+                    if active & 1 << i != 0 {
+                        status |= DTC_TEST_FAILED;
+                    }
+                    if self.confirmed & 1 << i != 0 {
+                        status |= DTC_CONFIRMED;
+                    }
+                    if status & mask != 0 {
+                        response.push(&dtc.to_be_bytes()[1..])?;
+                        response.push(&[status])?;
+                    }
+                }
+                Ok(Some(response))
+            }
+            SID_CLEAR_DIAGNOSTIC_INFORMATION => {
+                let &[0xFF, 0xFF, 0xFF] = args else {
+                    return Err(NRC_REQUEST_OUT_OF_RANGE);
+                };
+                defmt::info!("Fakon DTCs cleared");
+                // Faults which are still active come straight back
+                self.confirmed = 0;
+                Ok(Some(Response::new(sid)))
+            }
+            SID_ROUTINE_CONTROL => {
+                let &[sub, hi, lo] = args else {
+                    return Err(NRC_INCORRECT_LENGTH);
+                };
+                if !extended {
+                    return Err(NRC_SERVICE_NOT_SUPPORTED_IN_SESSION);
+                }
+                if sub & !SUPPRESS_POSITIVE_RESPONSE != ROUTINE_START {
+                    return Err(NRC_SUB_FUNCTION_NOT_SUPPORTED);
+                }
+                self.start_routine::<TX>(u16::from_be_bytes([hi, lo]))?;
+                let mut response = Response::new(sid);
+                response.push(&[ROUTINE_START, hi, lo])?;
+                Ok(suppressible(sub, response))
+            }
+            _ => Err(NRC_SERVICE_NOT_SUPPORTED),
+        }
+    }
+
+    // Latch faults
+    fn update(&mut self) {
+        self.confirmed |= self.active_faults::<TX>();
+        let bus_off_count = self.car.lock(|car| car.pcan_status().bus_off_count);
+        if bus_off_count != self.bus_off_count {
+            // DTC_PCAN_BUS_OFF is first in DTCS
+            self.confirmed |= 1;
+            self.bus_off_count = bus_off_count;
+        }
+    }
+}
+
+impl<C, MCAR, MPARK, P> Server<MCAR, MPARK, P>
+where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+{
+    fn read_data<MTX, TX>(
+        &mut self,
+        did: u16,
+        tx: &mut MTX,
+        response: &mut Response,
+    ) -> Result<(), u8>
+    where
+        MTX: Mutex<T = TX>,
+        TX: CanTx,
+        P: Platform<TX>,
+    {
+        match did {
+            DID_VERSION => response.push(self.platform.version().as_bytes())?,
+            DID_IGNITION => {
+                let ignition = self.car.lock(|car| car.ignition());
+                response.push(&[match ignition {
+                    Ignition::Off => 0,
+                    Ignition::IG3 => 1,
+                    Ignition::On => 2,
+                }])?;
+            }
+            DID_CONTACTOR => {
+                let contactor = self.car.lock(|car| car.contactor().get());
+                response.push(&[match contactor {
+                    Some(Contactor::Open) => 0,
+                    Some(Contactor::PreCharging) => 1,
+                    Some(Contactor::Closed) => 2,
+                    None => 0xFF,
+                }])?;
+            }
+            DID_GEAR => {
+                let gear = self.car.lock(|car| car.gear().get());
+                response.push(&[match gear {
+                    Some(Gear::Park) => 0,
+                    Some(Gear::Neutral) => 1,
+                    Some(Gear::Drive) => 2,
+                    Some(Gear::Reverse) => 3,
+                    None => 0xFF,
+                }])?;
+            }
+            DID_SOC => {
+                let soc = self.car.lock(|car| car.soc_batt());
+                response.push(&((soc * 10.0) as u16).to_be_bytes())?;
+            }
+            DID_CHARGE_PORT => {
+                let locked = self.car.lock(|car| car.charge_port().is_locked());
+                response.push(&[locked as u8])?;
+            }
+            DID_PARK_ACTUATOR => {
+                let locked = self.park.lock(|park| park.is_locked());
+                response.push(&[locked.map_or(0xFF, u8::from)])?;
+            }
+            DID_PCAN_STATS => {
+                let counters = tx.lock(|tx| self.platform.pcan_counters(tx));
+                let status = self.car.lock(|car| car.pcan_status());
+                for count in [
+                    counters.rx_frames,
+                    counters.tx_frames,
+                    counters.rx_overruns,
+                    counters.rx_dropped,
+                    counters.tx_replaced,
+                    counters.tx_expired,
+                    counters.tx_dropped,
+                ] {
+                    response.push(&count.to_be_bytes())?;
+                }
+                response.push(&counters.tx_queue_high_water.to_be_bytes())?;
+                response.push(&[
+                    counters.load_percent,
+                    status.state as u8,
+                    status.tec,
+                    status.rec,
+                ])?;
+                response.push(&status.bus_off_count.to_be_bytes())?;
+            }
+            _ => return Err(NRC_REQUEST_OUT_OF_RANGE),
+        }
+        Ok(())
+    }
+
+    fn start_routine<TX>(&mut self, routine: u16) -> Result<(), u8>
+    where
+        P: Platform<TX>,
+    {
+        let started = match routine {
+            ROUTINE_LOCK_CHARGE_PORT => self.platform.move_charge_port(ChargeLock::Locked),
+            ROUTINE_UNLOCK_CHARGE_PORT => self.platform.move_charge_port(ChargeLock::Unlocked),
+            ROUTINE_CYCLE_IG3 => self.car.lock(|car| {
+                let on = car.ignition() == Ignition::On;
+                if on {
+                    car.request_ig3_cycle();
+                }
+                on
+            }),
+            _ => return Err(NRC_REQUEST_OUT_OF_RANGE),
+        };
+        if !started {
+            return Err(NRC_CONDITIONS_NOT_CORRECT);
+        }
+        defmt::info!("UDS routine {=u16:#x} started", routine);
+        Ok(())
+    }
+
+    // Faults which are happening now, one bit per entry in DTCS
+    fn active_faults<TX>(&mut self) -> u8
+    where
+        P: Platform<TX>,
+    {
+        let (bus_off, overloaded, ignition_on, receiving) = self.car.lock(|car| {
+            (
+                car.pcan_bus_off(),
+                car.pcan_status().rx_overloaded,
+                car.ignition() == Ignition::On,
+                car.pcan_receiving(),
+            )
+        });
+        let now = C::now();
+        let on_since = ignition_on.then(|| *self.ignition_on_since.get_or_insert(now));
+        self.ignition_on_since = on_since;
+        let silent = !receiving && on_since.is_some_and(|since| now - since >= PCAN_SILENT_GRACE);
+        let self_test_failed = !self.platform.self_test_ok();
+        [bus_off, overloaded, silent, self_test_failed]
+            .iter()
+            .enumerate()
+            .fold(0, |faults, (i, &active)| faults | (active as u8) << i)
+    }
+}
+
+fn suppressible(sub: u8, response: Response) -> Option<Response> {
+    (sub & SUPPRESS_POSITIVE_RESPONSE == 0).then_some(response)
+}
+
+/// Answer diagnostic requests to Fakon. `rx` only needs to receive frames on
+/// REQUEST_ID.
+pub async fn task_server<C, MCAR, MPARK, RX, MTX, TX, P>(
+    car: MCAR,
+    park: MPARK,
+    rx: &mut RX,
+    pcan_tx: MTX,
+    platform: P,
+) where
+    C: Clock,
+    MCAR: Mutex<T = CarState<C>>,
+    MPARK: Mutex<T = ActuatorState>,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+    P: Platform<TX>,
+{
+    let mut server = Server {
+        car,
+        park,
+        platform,
+        confirmed: 0,
+        bus_off_count: 0,
+        ignition_on_since: None,
+    };
+    let config = isotp::Config::new(RESPONSE_ID, REQUEST_ID);
+    serve::<C, _, _, _, _>("Fakon", config, &mut server, rx, pcan_tx).await
+}
+
+/// Run a diagnostic server: receive requests over ISO-TP, handle
+/// DiagnosticSessionControl and TesterPresent, pass the rest to `handler`,
+/// and send the responses. `rx` only needs to receive frames on the
+/// config's rx_id. `name` is for logging.
+pub(crate) async fn serve<C, RX, MTX, TX, H>(
+    name: &str,
+    config: isotp::Config,
+    handler: &mut H,
+    rx: &mut RX,
+    mut tx: MTX,
+) where
+    C: Clock,
+    RX: CanRx,
+    MTX: Mutex<T = TX>,
+    TX: CanTx,
+    H: Handler<MTX>,
+{
+    let mut link = IsoTp::new(config);
+    let mut request = [0u8; MAX_MESSAGE];
+    let mut session = SESSION_DEFAULT;
+    let mut last_request = C::now();
+
+    loop {
+        let idle = C::now() + UPDATE_PERIOD;
+        let wake = link.next_deadline().map_or(idle, |next| next.min(idle));
+        let received = time::timeout_at::<C, _>(wake, rx.recv()).await;
+        let now = C::now();
+
+        let result = match received {
+            Some(frame) => link.on_frame(&frame, now, &mut tx).map(|msg| {
+                msg.map(|msg| {
+                    request[..msg.len()].copy_from_slice(msg);
+                    msg.len()
+                })
+            }),
+            None => link.poll(now, &mut tx).map(|()| None),
+        };
+        handler.update();
+        if session != SESSION_DEFAULT && now - last_request > SESSION_TIMEOUT {
+            defmt::info!("{} UDS session timed out", name);
+            session = SESSION_DEFAULT;
+        }
+
+        let len = match result {
+            Ok(Some(len)) if len > 0 => len,
+            Ok(_) => continue,
+            Err(err) => {
+                defmt::warn!("{} UDS server ISO-TP error {}", name, err);
+                continue;
+            }
+        };
+        last_request = now;
+
+        let sid = request[0];
+        let outcome = match (sid, &request[1..len]) {
+            (SID_DIAGNOSTIC_SESSION_CONTROL, &[sub]) => {
+                let new_session = sub & !SUPPRESS_POSITIVE_RESPONSE;
+                if new_session == SESSION_DEFAULT || new_session == SESSION_EXTENDED {
+                    if new_session != session {
+                        defmt::info!("{} UDS session {=u8:#x}", name, new_session);
+                        session = new_session;
+                    }
+                    let mut response = Response::new(sid);
+                    // P2 50ms, P2* 5000ms (in units of 10ms)
+                    response
+                        .push(&[new_session, 0x00, 0x32, 0x01, 0xF4])
+                        .map(|()| suppressible(sub, response))
+                } else {
+                    Err(NRC_SUB_FUNCTION_NOT_SUPPORTED)
+                }
+            }
+            (SID_TESTER_PRESENT, &[sub]) => {
+                if sub & !SUPPRESS_POSITIVE_RESPONSE == 0 {
+                    let mut response = Response::new(sid);
+                    response.push(&[0x00]).map(|()| suppressible(sub, response))
+                } else {
+                    Err(NRC_SUB_FUNCTION_NOT_SUPPORTED)
+                }
+            }
+            (SID_DIAGNOSTIC_SESSION_CONTROL | SID_TESTER_PRESENT, _) => Err(NRC_INCORRECT_LENGTH),
+            _ => handler.handle(&request[..len], session == SESSION_EXTENDED, &mut tx),
+        };
+
+        let sent = match outcome {
+            Ok(Some(response)) => link.send(response.data(), now, &mut tx),
+            Ok(None) => Ok(()),
+            Err(nrc) => {
+                defmt::debug!("{} UDS request {=u8:#x} rejected {=u8:#x}", name, sid, nrc);
+                link.send(&[NEGATIVE_RESPONSE, sid, nrc], now, &mut tx)
+            }
+        };
+        if let Err(err) = sent {
+            defmt::warn!("{} UDS response failed {}", name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::can::{BusStatus, ClassicFrame};
+    use crate::test_util::{poll_once, step_clock, RecordTx, Shared, TestRx};
+    use crate::time::StepClock;
+    use core::future::Future;
+    use core::pin::{pin, Pin};
+    use embedded_can::Frame;
+    use std::vec;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct TestPlatform {
+        self_test_failed: bool,
+        /// Charge port moves started
+        moves: Vec<ChargeLock>,
+    }
+
+    impl Platform<RecordTx> for TestPlatform {
+        fn version(&self) -> &'static str {
+            "test"
+        }
+
+        fn self_test_ok(&self) -> bool {
+            !self.self_test_failed
+        }
+
+        fn pcan_counters(&self, _tx: &RecordTx) -> Counters {
+            Counters::default()
+        }
+
+        fn move_charge_port(&mut self, direction: ChargeLock) -> bool {
+            self.moves.push(direction);
+            true
+        }
+    }
+
+    type TestServer = Server<Shared<CarState<StepClock>>, Shared<ActuatorState>, TestPlatform>;
+
+    fn server() -> TestServer {
+        Server {
+            car: Shared::new(CarState::new()),
+            park: Shared::new(ActuatorState::default()),
+            platform: TestPlatform::default(),
+            confirmed: 0,
+            bus_off_count: 0,
+            ignition_on_since: None,
+        }
+    }
+
+    fn handle(
+        server: &mut TestServer,
+        request: &[u8],
+        extended: bool,
+    ) -> Result<Option<Vec<u8>>, u8> {
+        let mut tx = Shared::new(RecordTx::default());
+        server
+            .handle(request, extended, &mut tx)
+            .map(|response| response.map(|response| response.data().to_vec()))
+    }
+
+    fn update(server: &mut TestServer) {
+        Handler::<Shared<RecordTx>>::update(server);
+    }
+
+    fn read_dids(server: &mut TestServer, count: usize) -> Outcome {
+        let mut request = Vec::from([SID_READ_DATA_BY_IDENTIFIER]);
+        for _ in 0..count {
+            request.extend_from_slice(&DID_PCAN_STATS.to_be_bytes());
+        }
+        let mut tx = Shared::new(RecordTx::default());
+        server.handle(&request, false, &mut tx)
+    }
+
+    /// Scan tool talking to a server which is running `serve`
+    struct Tester {
+        link: IsoTp,
+        tx: Shared<RecordTx>,
+        /// Pass clones to `serve`
+        server_rx: TestRx,
+        server_tx: Shared<RecordTx>,
+    }
+
+    impl Tester {
+        fn new() -> Self {
+            Self {
+                link: IsoTp::new(isotp::Config::new(REQUEST_ID, RESPONSE_ID)),
+                tx: Shared::new(RecordTx::default()),
+                server_rx: TestRx::default(),
+                server_tx: Shared::new(RecordTx::default()),
+            }
+        }
+
+        fn request<F: Future>(&mut self, server: Pin<&mut F>, request: &[u8]) -> Vec<u8> {
+            self.link
+                .send(request, StepClock::now(), &mut self.tx)
+                .unwrap();
+            self.run(server, Duration::secs(1)).unwrap()
+        }
+
+        /// Run the server for `duration`, or until a response arrives
+        fn run<F: Future>(
+            &mut self,
+            mut server: Pin<&mut F>,
+            duration: Duration,
+        ) -> Option<Vec<u8>> {
+            let end = StepClock::now() + duration;
+            loop {
+                let now = StepClock::now();
+                for (id, data) in self.tx.lock(|tx| core::mem::take(&mut tx.frames)) {
+                    self.server_rx.push(ClassicFrame::new(id, &data).unwrap());
+                }
+                assert!(poll_once(server.as_mut()).is_pending());
+                for (id, data) in self.server_tx.lock(|tx| core::mem::take(&mut tx.frames)) {
+                    let frame = ClassicFrame::new(id, &data).unwrap();
+                    if let Some(response) = self.link.on_frame(&frame, now, &mut self.tx).unwrap() {
+                        return Some(response.to_vec());
+                    }
+                }
+                self.link.poll(now, &mut self.tx).unwrap();
+                if now >= end {
+                    return None;
+                }
+                StepClock::step(Duration::millis(1));
+            }
+        }
+    }
+
+    const EXTENDED_SESSION: [u8; 2] = [SID_DIAGNOSTIC_SESSION_CONTROL, SESSION_EXTENDED];
+    const LOCK_CHARGE_PORT: [u8; 4] = [SID_ROUTINE_CONTROL, ROUTINE_START, 0x02, 0x01];
+    const LOCK_STARTED: [u8; 4] = [0x71, ROUTINE_START, 0x02, 0x01];
+    const NOT_IN_SESSION: [u8; 3] = [
+        NEGATIVE_RESPONSE,
+        SID_ROUTINE_CONTROL,
+        NRC_SERVICE_NOT_SUPPORTED_IN_SESSION,
+    ];
+
+    #[test]
+    fn session_control() {
+        let _clock = step_clock();
+        let mut tester = Tester::new();
+        let mut server = server();
+        let (mut rx, tx) = (tester.server_rx.clone(), tester.server_tx.clone());
+        let config = isotp::Config::new(RESPONSE_ID, REQUEST_ID);
+        let mut serve = pin!(serve::<StepClock, _, _, _, _>(
+            "Test",
+            config,
+            &mut server,
+            &mut rx,
+            tx
+        ));
+
+        // Routines need the extended session
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, NOT_IN_SESSION);
+        let response = tester.request(serve.as_mut(), &EXTENDED_SESSION);
+        assert_eq!(response, [0x50, SESSION_EXTENDED, 0x00, 0x32, 0x01, 0xF4]);
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, LOCK_STARTED);
+
+        let response = tester.request(serve.as_mut(), &[SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]);
+        assert_eq!(
+            response,
+            [NEGATIVE_RESPONSE, 0x10, NRC_SUB_FUNCTION_NOT_SUPPORTED]
+        );
+        let response = tester.request(serve.as_mut(), &[SID_DIAGNOSTIC_SESSION_CONTROL]);
+        assert_eq!(response, [NEGATIVE_RESPONSE, 0x10, NRC_INCORRECT_LENGTH]);
+
+        let response = tester.request(
+            serve.as_mut(),
+            &[SID_DIAGNOSTIC_SESSION_CONTROL, SESSION_DEFAULT],
+        );
+        assert_eq!(response[..2], [0x50, SESSION_DEFAULT]);
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, NOT_IN_SESSION);
+
+        // Suppressed positive response
+        let request = [
+            SID_DIAGNOSTIC_SESSION_CONTROL,
+            SESSION_EXTENDED | SUPPRESS_POSITIVE_RESPONSE,
+        ];
+        tester
+            .link
+            .send(&request, StepClock::now(), &mut tester.tx)
+            .unwrap();
+        assert_eq!(tester.run(serve.as_mut(), Duration::millis(500)), None);
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, LOCK_STARTED);
+    }
+
+    #[test]
+    fn session_timeout() {
+        let _clock = step_clock();
+        let mut tester = Tester::new();
+        let mut server = server();
+        let (mut rx, tx) = (tester.server_rx.clone(), tester.server_tx.clone());
+        let config = isotp::Config::new(RESPONSE_ID, REQUEST_ID);
+        let mut serve = pin!(serve::<StepClock, _, _, _, _>(
+            "Test",
+            config,
+            &mut server,
+            &mut rx,
+            tx
+        ));
+        tester.request(serve.as_mut(), &EXTENDED_SESSION);
+
+        // TesterPresent keeps the session going
+        for _ in 0..3 {
+            assert_eq!(tester.run(serve.as_mut(), Duration::secs(4)), None);
+            let response = tester.request(serve.as_mut(), &[SID_TESTER_PRESENT, 0x00]);
+            assert_eq!(response, [0x7E, 0x00]);
+        }
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, LOCK_STARTED);
+
+        // S3 timeout, back to the default session
+        assert_eq!(tester.run(serve.as_mut(), Duration::millis(5200)), None);
+        let response = tester.request(serve.as_mut(), &LOCK_CHARGE_PORT);
+        assert_eq!(response, NOT_IN_SESSION);
+    }
+
+    #[test]
+    fn read_dtcs_by_status_mask() {
+        let _clock = step_clock();
+        let mut server = server();
+        let request = [SID_READ_DTC_INFORMATION, REPORT_DTC_BY_STATUS_MASK, 0xFF];
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Ok(Some(vec![0x59, 0x02, 0x09]))
+        );
+
+        // Active straight away, confirmed after the next update
+        server.platform.self_test_failed = true;
+        let self_test = [0xF0, 0x00, 0x49];
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Ok(Some(
+                [&[0x59, 0x02, 0x09], &self_test[..], &[0x01]].concat()
+            ))
+        );
+        update(&mut server);
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Ok(Some(
+                [&[0x59, 0x02, 0x09], &self_test[..], &[0x09]].concat()
+            ))
+        );
+
+        // A Bus Off which has already recovered is confirmed, not active
+        server.car.lock(|car| {
+            car.set_pcan_status(BusStatus {
+                bus_off_count: 1,
+                ..BusStatus::new()
+            })
+        });
+        update(&mut server);
+        let bus_off = [0xC0, 0x01, 0x88, 0x08];
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Ok(Some(
+                [&[0x59, 0x02, 0x09], &bus_off[..], &self_test[..], &[0x09]].concat()
+            ))
+        );
+        // Only the DTCs whose status matches the mask
+        let active = [
+            SID_READ_DTC_INFORMATION,
+            REPORT_DTC_BY_STATUS_MASK,
+            DTC_TEST_FAILED,
+        ];
+        assert_eq!(
+            handle(&mut server, &active, false),
+            Ok(Some(
+                [&[0x59, 0x02, 0x09], &self_test[..], &[0x09]].concat()
+            ))
+        );
+
+        let request = [SID_READ_DTC_INFORMATION, 0x01, 0xFF];
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Err(NRC_SUB_FUNCTION_NOT_SUPPORTED)
+        );
+        let request = [SID_READ_DTC_INFORMATION, REPORT_DTC_BY_STATUS_MASK];
+        assert_eq!(
+            handle(&mut server, &request, false),
+            Err(NRC_INCORRECT_LENGTH)
+        );
+    }
+
+    #[test]
+    fn clear_dtcs() {
+        let _clock = step_clock();
+        let mut server = server();
+        server.platform.self_test_failed = true;
+        server.car.lock(|car| {
+            car.set_pcan_status(BusStatus {
+                bus_off_count: 1,
+                ..BusStatus::new()
+            })
+        });
+        update(&mut server);
+        assert_eq!(server.confirmed, 0b1001);
+
+        let clear = [SID_CLEAR_DIAGNOSTIC_INFORMATION, 0xFF, 0xFF, 0xFF];
+        assert_eq!(handle(&mut server, &clear, false), Ok(Some(vec![0x54])));
+        assert_eq!(server.confirmed, 0);
+        // The self-test failure is still active, so it comes back
+        update(&mut server);
+        assert_eq!(server.confirmed, 0b1000);
+
+        let group = [SID_CLEAR_DIAGNOSTIC_INFORMATION, 0x00, 0x01, 0x00];
+        assert_eq!(
+            handle(&mut server, &group, false),
+            Err(NRC_REQUEST_OUT_OF_RANGE)
+        );
+    }
+
+    #[test]
+    fn routine_control() {
+        let _clock = step_clock();
+        let mut server = server();
+        let routine = |sub: u8, routine: u16| {
+            let [hi, lo] = routine.to_be_bytes();
+            [SID_ROUTINE_CONTROL, sub, hi, lo]
+        };
+
+        let unlock = routine(ROUTINE_START, ROUTINE_UNLOCK_CHARGE_PORT);
+        assert_eq!(
+            handle(&mut server, &unlock, true),
+            Ok(Some(vec![0x71, ROUTINE_START, 0x02, 0x02]))
+        );
+        let lock = routine(
+            ROUTINE_START | SUPPRESS_POSITIVE_RESPONSE,
+            ROUTINE_LOCK_CHARGE_PORT,
+        );
+        assert_eq!(handle(&mut server, &lock, true), Ok(None));
+        assert_eq!(
+            server.platform.moves,
+            [ChargeLock::Unlocked, ChargeLock::Locked]
+        );
+
+        // Only with the ignition on
+        let cycle = routine(ROUTINE_START, ROUTINE_CYCLE_IG3);
+        assert_eq!(
+            handle(&mut server, &cycle, true),
+            Err(NRC_CONDITIONS_NOT_CORRECT)
+        );
+
+        // Stop and results aren't supported
+        let stop = routine(0x02, ROUTINE_LOCK_CHARGE_PORT);
+        assert_eq!(
+            handle(&mut server, &stop, true),
+            Err(NRC_SUB_FUNCTION_NOT_SUPPORTED)
+        );
+        let unknown = routine(ROUTINE_START, 0x0299);
+        assert_eq!(
+            handle(&mut server, &unknown, true),
+            Err(NRC_REQUEST_OUT_OF_RANGE)
+        );
+        assert_eq!(
+            handle(&mut server, &unlock, false),
+            Err(NRC_SERVICE_NOT_SUPPORTED_IN_SESSION)
+        );
+        assert_eq!(
+            handle(&mut server, &unlock[..3], true),
+            Err(NRC_INCORRECT_LENGTH)
+        );
+        assert_eq!(server.platform.moves.len(), 2);
+    }
+
+    #[test]
+    fn pcan_silent_after_grace() {
+        let _clock = step_clock();
+        let mut server = server();
+        // Fakon booting with the ignition already on
+        server.car.lock(|car| car.set_ignition(Ignition::On));
+        update(&mut server);
+        StepClock::step(PCAN_SILENT_GRACE - Duration::millis(1));
+        update(&mut server);
+        assert_eq!(server.confirmed, 0);
+        StepClock::step(Duration::millis(1));
+        update(&mut server);
+        assert_eq!(server.confirmed, 0b0100);
+
+        // Not active once PCAN is heard
+        server.confirmed = 0;
+        server
+            .car
+            .lock(|car| car.set_last_pcan_rx(StepClock::now()));
+        update(&mut server);
+        assert_eq!(server.confirmed, 0);
+
+        // The grace period starts again each time the ignition turns on
+        server.car.lock(|car| car.set_ignition(Ignition::Off));
+        StepClock::step(Duration::secs(10));
+        update(&mut server);
+        server.car.lock(|car| car.set_ignition(Ignition::On));
+        update(&mut server);
+        StepClock::step(PCAN_SILENT_GRACE - Duration::millis(1));
+        update(&mut server);
+        assert_eq!(server.confirmed, 0);
+        StepClock::step(Duration::millis(1));
+        update(&mut server);
+        assert_eq!(server.confirmed, 0b0100);
+    }
+
+    #[test]
+    fn unsupported_service() {
+        let mut server = server();
+        assert_eq!(
+            handle(&mut server, &[0x2E, 0x01, 0x00, 0x01], true),
+            Err(NRC_SERVICE_NOT_SUPPORTED)
+        );
+        assert_eq!(handle(&mut server, &[], true), Err(NRC_INCORRECT_LENGTH));
+    }
+
+    #[test]
+    fn response_push_full() {
+        let mut response = Response::new(SID_READ_DATA_BY_IDENTIFIER);
+        assert_eq!(response.push(&[0; MAX_MESSAGE - 1]), Ok(()));
+        assert_eq!(response.data().len(), MAX_MESSAGE);
+        assert_eq!(response.push(&[0]), Err(NRC_RESPONSE_TOO_LONG));
+        assert_eq!(response.data().len(), MAX_MESSAGE);
+    }
+
+    #[test]
+    fn read_data_response_too_long() {
+        // Each DID_PCAN_STATS is 38 bytes with its DID, so 13 fit after the
+        // SID and 14 don't
+        let mut server = server();
+        let response = read_dids(&mut server, 13).unwrap().unwrap();
+        assert_eq!(response.data().len(), 1 + 13 * 38);
+        assert_eq!(
+            read_dids(&mut server, 14).err(),
+            Some(NRC_RESPONSE_TOO_LONG)
+        );
+    }
+}
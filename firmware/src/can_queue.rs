@@ -1,49 +1,177 @@
 //! Module to support software TX and RX queued CAN on rtic
 //!
-//! TX side uses a binary heap to send out messages in priority order.
+//! TX side sends out messages in priority order. Queued frames have
+//! latest-value semantics: a newer frame with the same ID replaces the queued
+//! one, and frames which have waited longer than their lifetime are dropped.
 //! RX side writes messages into an RTIC channel in FIFO order, for processing by the app.
+//!
+//! Each CAN bus gets its own Control, Rx and Tx. The RX channel has to be made
+//! by the caller with make_channel!(), as that macro declares a static and can
+//! only run once per call site:
+//!
+//! ```ignore
+//! let (control, rx, tx) = can_queue::Control::init(
+//!     config,
+//!     &timing,
+//!     can_queue::FrameMode::Classic,
+//!     &can_queue::RxFilter::AcceptAll,
+//!     can_queue::BusMode::Normal,
+//!     make_channel!(can_queue::QueuedFrame, can_queue::RX_CAPACITY),
+//! );
+//! ```
 use can_bit_timings::CanBitTiming;
-use embedded_can::{Frame, Id, StandardId};
-use core::cmp::{min, Ordering};
-use fdcan::config::FrameTransmissionConfig::ClassicCanOnly;
-use fdcan::config::InterruptLine;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+use core::cmp::min;
+use core::convert::Infallible;
+use core::ops::RangeInclusive;
+use fakon_core::can::{BusState, BusStatus, CanTx};
+use fakon_core::can_stats::BusStats;
+use fakon_core::tx_queue::{Pending, TxQueue};
+use fakon_core::{Duration, Instant};
+use fdcan::config::{DataBitTiming, FrameTransmissionConfig};
+use fdcan::config::{
+    GlobalFilter, InterruptLine, NonMatchingFilter, TimestampPrescaler, TimestampSource,
+};
 use fdcan::interrupt::{Interrupt, Interrupts};
-use fdcan::{self, Fifo0, Mailbox, NormalOperationMode, ReceiveOverrun};
+use fdcan::{
+    self, BusMonitoringMode, ErrorCounters, Fifo0, Fifo1, InternalLoopbackMode, Mailbox,
+    NormalOperationMode, ProtocolStatus, ReceiveErrorOverflow, ReceiveOverrun,
+};
 use fdcan::{
     config::NominalBitTiming,
-    filter::{StandardFilter, StandardFilterSlot},
-    frame::{FramePriority, TxFrameHeader},
+    filter::{
+        Action, ExtendedFilter, ExtendedFilterSlot, FilterType, StandardFilter, StandardFilterSlot,
+    },
+    frame::{FrameFormat, RxFrameInfo, TxFrameHeader},
 };
-use heapless::binary_heap::Max;
-use heapless::BinaryHeap;
+use heapless::Vec;
 use rtic::Mutex;
-use rtic_sync::{channel, make_channel};
+use rtic_monotonics::Monotonic;
+
+use crate::hardware::{Mono, CAN_KERNEL_CLOCK_HZ};
+use crate::slcan_uart;
+use rtic_sync::channel::{self, TrySendError};
 
 /// Software RX queue size
-const RX_CAPACITY: usize = 16;
+pub const RX_CAPACITY: usize = 16;
+/// Number of priority frames which can be held back while the RX queue is full
+const RX_HELD_CAPACITY: usize = 4;
+/// If frames are dropped in this many RX_OVERLOAD_WINDOWs in a row, the bus
+/// status reports RX overload
+const RX_OVERLOAD_WINDOW: Duration = Duration::secs(1);
+const RX_OVERLOAD_WINDOWS: u8 = 3;
 /// Software TX queue size
 const TX_CAPACITY: usize = 32;
+/// Number of hardware TX buffers in the FDCAN peripheral
+const TX_BUFFERS: usize = 3;
+/// How long a frame can wait in the TX queue before it's dropped, unless the
+/// caller gives a lifetime. Long enough to ride out a short burst of higher
+/// priority traffic, short enough that stale state isn't sent.
+pub const TX_LIFETIME: Duration = Duration::millis(100);
+/// Number of different IDs to keep statistics for, per bus
+const STATS_IDS: usize = 64;
+
+pub type Stats = BusStats<STATS_IDS>;
 
 // Types for each end of the Rx rtic channel
 
 /// The receive end is the public interface to receive CAN frames
 pub type Rx = channel::Receiver<'static, QueuedFrame, RX_CAPACITY>;
 
-/// The send end is only for internal use
-type RxSender = channel::Sender<'static, QueuedFrame, RX_CAPACITY>;
+/// The send end is only used internally, after being passed to Control::init
+pub type RxSender = channel::Sender<'static, QueuedFrame, RX_CAPACITY>;
+
+/// First delay before restarting a bus after Bus Off. Doubles for each
+/// attempt which fails, up to BUS_OFF_MAX_BACKOFF.
+const BUS_OFF_MIN_BACKOFF: Duration = Duration::millis(50);
+const BUS_OFF_MAX_BACKOFF: Duration = Duration::secs(5);
+/// How long a restarted bus has to stay on to count as recovered
+const BUS_OFF_STABLE_TIME: Duration = Duration::secs(1);
+
+/// RX timestamp counter ticks every this many bit times. At 500kbit/s the
+/// 16 bit counter wraps every 2 seconds, far longer than a frame waits in
+/// the hardware FIFO.
+const TIMESTAMP_PRESCALER: u32 = 16;
+
+/// How many times the self-test polls for a looped back frame, and the CPU
+/// cycles between polls. About 10ms in total, a frame takes under 0.3ms.
+const SELF_TEST_POLLS: u32 = 1000;
+const SELF_TEST_POLL_CYCLES: u32 = 1000;
+
+/// Number of standard and extended ID filter slots in the FDCAN peripheral
+const STANDARD_FILTER_SLOTS: usize = 28;
+const EXTENDED_FILTER_SLOTS: usize = 8;
+
+/// Largest CAN FD payload
+pub const MAX_DATA_LEN: usize = 64;
+/// Payload lengths over 8 bytes which CAN FD can encode
+const FD_LENGTHS: [usize; 7] = [12, 16, 20, 24, 32, 48, 64];
+
+/// Frame formats used on a bus
+#[derive(Clone, Copy)]
+pub enum FrameMode<'a> {
+    /// Classic CAN only, up to 8 bytes. CAN FD frames received are errors,
+    /// and transmitting one is dropped.
+    Classic,
+    /// CAN FD frames up to 64 bytes, as well as classic frames. Bit rate
+    /// switching needs a data phase bit timing.
+    Fd {
+        data_bit_timings: Option<&'a CanBitTiming>,
+    },
+}
+
+/// Which received frames are passed to the app
+pub enum RxFilter<'a> {
+    /// Everything, into FIFO0
+    AcceptAll,
+    /// Only the IDs in these lists (duplicates are fine) into FIFO0, and the
+    /// standard diagnostic ID range into FIFO1. Everything else is rejected
+    /// in hardware.
+    ///
+    /// Diagnostic frames come in bursts, so a separate FIFO stops them
+    /// overrunning the frames the emulation needs.
+    Only {
+        ids: &'a [&'a [Id]],
+        diagnostic: Option<RangeInclusive<u16>>,
+    },
+}
+
+/// How the controller takes part in the bus
+#[derive(Clone, Copy, PartialEq)]
+pub enum BusMode {
+    Normal,
+    /// Bus Monitoring: receive every frame the filters accept, but never
+    /// drive the bus, not even ACKs or error frames. Anything transmitted is
+    /// dropped.
+    Monitoring,
+}
 
 /// Control struct is used when instantiating the queue, and
 /// by the interrupt handler function
 pub struct Control<I: fdcan::Instance> {
-    hw: fdcan::FdCanControl<I, fdcan::NormalOperationMode>,
-    hw_rx: fdcan::Rx<I, NormalOperationMode, Fifo0>,
+    hw: Hw<I>,
     rx_sender: RxSender,
+    status: BusStatus,
+    self_test: Result<(), SelfTestError>,
+    // Length of one timestamp counter tick
+    timestamp_tick_ns: u32,
+
+    // IDs which are held back rather than dropped if the RX queue is full
+    rx_priority: &'static [&'static [Id]],
+    rx_held: Vec<QueuedFrame, RX_HELD_CAPACITY>,
+    rx_window_start: Instant,
+    rx_window_dropped: u32,
+    rx_overload_windows: u8,
 }
 
 impl<I: fdcan::Instance> Control<I> {
     pub fn init(
         mut can: fdcan::FdCan<I, fdcan::ConfigMode>,
         bit_timings: &CanBitTiming,
+        mode: FrameMode,
+        filter: &RxFilter,
+        bus_mode: BusMode,
+        (rx_sender, rx_receiver): (RxSender, Rx),
     ) -> (Self, Rx, Tx<I>) {
         // Convert the generic bit timings to FDCAN bit timings
         defmt::debug!(
@@ -61,16 +189,57 @@ impl<I: fdcan::Instance> Control<I> {
         };
 
         can.set_nominal_bit_timing(btr);
-        // Currently only supports receiving into FIFO0, assume software can keep up
-        can.set_standard_filter(
-            StandardFilterSlot::_0,
-            StandardFilter::accept_all_into_fifo0(),
-        );
-        can.set_frame_transmit(ClassicCanOnly); // Currently no FD long frame support
+        set_filters(&mut can, filter);
+        let fd = match mode {
+            FrameMode::Classic => {
+                can.set_frame_transmit(FrameTransmissionConfig::ClassicCanOnly);
+                false
+            }
+            FrameMode::Fd {
+                data_bit_timings: None,
+            } => {
+                can.set_frame_transmit(FrameTransmissionConfig::AllowFdCan);
+                true
+            }
+            FrameMode::Fd {
+                data_bit_timings: Some(data_timings),
+            } => {
+                defmt::debug!(
+                    "CAN FD data prescaler {} bs1 {} bs2 {} sjw {}",
+                    data_timings.prescaler,
+                    data_timings.bs1,
+                    data_timings.bs2,
+                    data_timings.sjw
+                );
+                can.set_data_bit_timing(DataBitTiming {
+                    // Needed for data rates over 1Mbit/s, harmless below
+                    transceiver_delay_compensation: true,
+                    prescaler: data_timings.prescaler.try_into().unwrap(),
+                    seg1: data_timings.bs1.try_into().unwrap(),
+                    seg2: data_timings.bs2.try_into().unwrap(),
+                    sync_jump_width: data_timings.sjw.try_into().unwrap(),
+                });
+                can.set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS);
+                true
+            }
+        };
+        can.set_timestamp_counter_source(TimestampSource::Prescaler(TimestampPrescaler::_16));
+
+        // Before joining the bus, check frames make it through the peripheral
+        let mut loopback = can.into_internal_loopback();
+        let self_test = self_test(&mut loopback, filter);
+        match self_test {
+            Ok(()) => defmt::info!("CAN self-test passed"),
+            Err(err) => defmt::error!("CAN self-test failed: {}", err),
+        }
+        let mut can = loopback.into_config_mode();
+        // Don't leave any interrupts from the test pending
+        can.clear_interrupts(Interrupts::all());
 
         can.enable_interrupt_line(InterruptLine::_1, true); // Swapped in crate, this is line 0
         can.enable_interrupts(
             Interrupts::RX_FIFO0_NEW_MSG
+                | Interrupts::RX_FIFO1_NEW_MSG
                 | Interrupts::ERR_PASSIVE
                 | Interrupts::BUS_OFF
                 | Interrupts::TX_COMPLETE,
@@ -78,80 +247,376 @@ impl<I: fdcan::Instance> Control<I> {
         //can.enable_transmission_interrupts(Mailboxes::all());
         defmt::info!("Configuring fdcan...");
 
-        // Make the RTIC channel for received messages
-        //
-        // (Hoping using static here will allow at most one channel
-        // per CAN bus type as this function is generic over the fdcan Instance...)
-        let (rx_sender, rx_receiver) = make_channel!(QueuedFrame, RX_CAPACITY);
-
-        // Start the CAN peripheral and split it
-        let (hw, hw_tx, hw_rx, _hw_rx1) = can.into_normal().split();
+        // Start the CAN peripheral. Monitoring mode is set before leaving
+        // init, so the controller never joins the bus.
+        let (hw, hw_tx) = match bus_mode {
+            BusMode::Normal => {
+                let (control, tx, rx0, rx1) = can.into_normal().split();
+                (Hw::Normal { control, rx0, rx1 }, Some(tx))
+            }
+            BusMode::Monitoring => (Hw::Monitoring(can.into_bus_monitoring()), None),
+        };
 
         (
             Self {
                 hw,
-                hw_rx,
                 rx_sender,
+                status: BusStatus::new(),
+                self_test,
+                timestamp_tick_ns: TIMESTAMP_PRESCALER * (1_000_000_000 / bitrate(bit_timings)),
+                rx_priority: &[],
+                rx_held: Vec::new(),
+                rx_window_start: Instant::from_ticks(0),
+                rx_window_dropped: 0,
+                rx_overload_windows: 0,
             },
             rx_receiver,
-            Tx::new(hw_tx),
+            Tx::new(hw_tx, bitrate(bit_timings), fd),
         )
     }
 
-    pub fn on_irq<M, F>(&mut self, mut m_tx: M, flag_bus_off: F)
+    /// Result of the loopback self-test run by init(). If it failed, frames
+    /// may not be sent or received correctly, and the app shouldn't rely on
+    /// this bus.
+    pub fn self_test_result(&self) -> Result<(), SelfTestError> {
+        self.self_test
+    }
+
+    /// Set the IDs to keep if the RX queue fills up. Other frames are dropped
+    /// until there's space, but these are held back and passed on in order
+    /// (only the latest of each ID.)
+    pub fn set_rx_priority(&mut self, ids: &'static [&'static [Id]]) {
+        self.rx_priority = ids;
+    }
+
+    /// Interrupt handler for this bus. on_status is called whenever the
+    /// controller goes Error Passive or Bus Off, or back again, and when RX
+    /// overload starts or stops.
+    ///
+    /// While the bus is Bus Off, the Tx side drops all frames. The caller
+    /// should run recover_bus_off() to restart it.
+    pub fn on_irq<M, F>(&mut self, mut m_tx: M, on_status: F)
     where
         M: Mutex<T = Tx<I>>,
-        F: FnOnce(),
+        F: FnOnce(BusStatus),
     {
+        let mut status_changed = false;
+
         // This is kind of annoying that we have to poll the
         // interrupt register on each check
         if self.hw.has_interrupt(Interrupt::RxFifo0NewMsg) {
             self.hw.clear_interrupt(Interrupt::RxFifo0NewMsg);
-            self.on_rx_irq();
+            let mut data = [0u8; MAX_DATA_LEN];
+            let result = self.hw.receive(0, &mut data);
+            self.on_rx_irq(result, &data, &mut m_tx);
         }
+        if self.hw.has_interrupt(Interrupt::RxFifo1NewMsg) {
+            self.hw.clear_interrupt(Interrupt::RxFifo1NewMsg);
+            let mut data = [0u8; MAX_DATA_LEN];
+            let result = self.hw.receive(1, &mut data);
+            self.on_rx_irq(result, &data, &mut m_tx);
+        }
+        status_changed |= self.update_rx_overload();
         if self.hw.has_interrupt(Interrupt::TxComplete) {
             self.hw.clear_interrupt(Interrupt::TxComplete);
             m_tx.lock(|tx| {
-                if let Some(msg) = tx.queue.pop() {
-                    tx.transmit(&msg);
+                if let Some(pending) = tx.pop_next() {
+                    // Not tx.transmit(), this frame was already counted
+                    tx.transmit_frame(pending);
                 }
             });
         }
+        // Both of these interrupts fire on entering and leaving the state
         if self.hw.has_interrupt(Interrupt::ErrPassive) {
             self.hw.clear_interrupt(Interrupt::ErrPassive);
-            defmt::error!("CAN peripheral in Error Passive"); // TODO: how to recover?
+            status_changed = true;
         }
         if self.hw.has_interrupt(Interrupt::BusOff) {
             self.hw.clear_interrupt(Interrupt::BusOff);
-            defmt::error!("CAN peripheral in Bus Off");
-            flag_bus_off();
+            status_changed = true;
+        }
+        if status_changed {
+            let status = self.read_status();
+            if status.state == BusState::BusOff {
+                defmt::error!("CAN peripheral in Bus Off {}", status);
+            } else {
+                defmt::warn!("CAN peripheral {}", status);
+            }
+            m_tx.lock(|tx| {
+                let bus_off = status.state == BusState::BusOff;
+                if bus_off && !tx.bus_off {
+                    // Everything queued will be stale by the time the bus is back
+                    tx.flush();
+                }
+                tx.bus_off = bus_off;
+            });
+            on_status(status);
+        }
+    }
+
+    /// Read the controller's current state and error counters
+    fn read_status(&mut self) -> BusStatus {
+        let (protocol, counters) = self.hw.status();
+        let state = if protocol.bus_off_status {
+            BusState::BusOff
+        } else if protocol.error_passive_state {
+            BusState::ErrorPassive
+        } else {
+            BusState::ErrorActive
+        };
+        if state == BusState::BusOff && self.status.state != BusState::BusOff {
+            self.status.bus_off_count = self.status.bus_off_count.saturating_add(1);
         }
+        self.status.state = state;
+        self.status.tec = counters.transmit_err;
+        self.status.rec = match counters.receive_err {
+            ReceiveErrorOverflow::Normal(rec) => rec,
+            ReceiveErrorOverflow::Overflow(_) => u8::MAX,
+        };
+        self.status
     }
 
-    fn on_rx_irq(&mut self) {
-        let frame = match self.hw_rx.receive_frame() {
-            Ok(ReceiveOverrun::NoOverrun(frame)) => frame,
-            Ok(ReceiveOverrun::Overrun(frame)) => {
+    // Both FIFOs feed the same software queue, the app sorts frames by ID
+    fn on_rx_irq<E, M>(
+        &mut self,
+        result: Result<ReceiveOverrun<RxFrameInfo>, E>,
+        data: &[u8],
+        m_tx: &mut M,
+    ) where
+        E: core::fmt::Debug,
+        M: Mutex<T = Tx<I>>,
+    {
+        let (info, overrun) = match result {
+            Ok(ReceiveOverrun::NoOverrun(info)) => (info, false),
+            Ok(ReceiveOverrun::Overrun(info)) => {
                 defmt::error!("CAN RX overrun reported");
-                frame
+                (info, true)
             }
             // Shouldn't happen unless RX IRQ fired without anything received
-            Err(err) => panic!("CAN internal error {err:?}"),
+            Err(err) => {
+                defmt::error!("CAN internal error {}", defmt::Debug2Format(&err));
+                return;
+            }
         };
-        match self.rx_sender.try_send(frame) {
-            Ok(_) => (),
-            // Can probably leave this as panic for now, as app should be able to handle max incoming CAN rate
-            Err(err) => panic!("CAN RX queue overflow {err:?}"),
+        let rx_time = self.rx_time(info.time_stamp);
+        let frame = QueuedFrame::from_rx(info, data, rx_time);
+        m_tx.lock(|tx| {
+            if overrun {
+                tx.stats.on_rx_overrun();
+            }
+            tx.stats.on_rx(frame.id(), frame.dlc(), rx_time);
+        });
+
+        // Anything held back is older than this frame, so goes first
+        while let Some(held) = self.rx_held.first() {
+            if self.rx_sender.try_send(held.clone()).is_err() {
+                break;
+            }
+            self.rx_held.remove(0);
+        }
+
+        let frame = match self.rx_sender.try_send(frame) {
+            Ok(_) => return,
+            Err(TrySendError::Full(frame)) | Err(TrySendError::NoReceiver(frame)) => frame,
+        };
+        // The app isn't keeping up. Keep the latest of each priority ID, as
+        // losing those matters more than a late one.
+        let id = frame.id();
+        if self.rx_priority.iter().any(|ids| ids.contains(&id)) {
+            if let Some(held) = self.rx_held.iter_mut().find(|h| h.id() == id) {
+                *held = frame;
+                return;
+            }
+            if self.rx_held.push(frame).is_ok() {
+                return;
+            }
+        }
+        if self.rx_window_dropped == 0 {
+            defmt::warn!("CAN RX queue full, dropping frames");
+        }
+        self.rx_window_dropped += 1;
+        m_tx.lock(|tx| tx.stats.on_rx_dropped());
+    }
+
+    // Convert a frame's RX timestamp to a monotonic time, by how long ago the
+    // timestamp counter was at that value
+    fn rx_time(&self, time_stamp: u16) -> Instant {
+        let now = Mono::now();
+        let ticks = self.hw.timestamp().wrapping_sub(time_stamp);
+        // In u64, as a slow bit timing can make the tick length over 65536ns
+        let age_us = u64::from(ticks) * u64::from(self.timestamp_tick_ns) / 1000;
+        let age = Duration::micros(age_us as u32);
+        now.checked_sub_duration(age).unwrap_or(now)
+    }
+
+    // Track whether frames keep being dropped. Returns true if the
+    // rx_overloaded status changed.
+    fn update_rx_overload(&mut self) -> bool {
+        let now = Mono::now();
+        if now - self.rx_window_start < RX_OVERLOAD_WINDOW {
+            return false;
         }
+        self.rx_window_start = now;
+        if self.rx_window_dropped > 0 {
+            self.rx_overload_windows = self.rx_overload_windows.saturating_add(1);
+        } else {
+            self.rx_overload_windows = 0;
+        }
+        self.rx_window_dropped = 0;
+
+        let overloaded = self.rx_overload_windows >= RX_OVERLOAD_WINDOWS;
+        let changed = overloaded != self.status.rx_overloaded;
+        self.status.rx_overloaded = overloaded;
+        changed
     }
 }
 
+/// The peripheral in the mode it was started in. fdcan can only split a
+/// controller which can transmit, so in Bus Monitoring mode it's kept whole.
+enum Hw<I: fdcan::Instance> {
+    Normal {
+        control: fdcan::FdCanControl<I, NormalOperationMode>,
+        rx0: fdcan::Rx<I, NormalOperationMode, Fifo0>,
+        rx1: fdcan::Rx<I, NormalOperationMode, Fifo1>,
+    },
+    Monitoring(fdcan::FdCan<I, BusMonitoringMode>),
+}
+
+impl<I: fdcan::Instance> Hw<I> {
+    fn has_interrupt(&mut self, interrupt: Interrupt) -> bool {
+        match self {
+            Hw::Normal { control, .. } => control.has_interrupt(interrupt),
+            Hw::Monitoring(can) => can.has_interrupt(interrupt),
+        }
+    }
+
+    fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        match self {
+            Hw::Normal { control, .. } => control.clear_interrupt(interrupt),
+            Hw::Monitoring(can) => can.clear_interrupt(interrupt),
+        }
+    }
+
+    // Receive from RX FIFO 0 or 1
+    fn receive(
+        &mut self,
+        fifo: u8,
+        data: &mut [u8],
+    ) -> nb::Result<ReceiveOverrun<RxFrameInfo>, Infallible> {
+        match (self, fifo) {
+            (Hw::Normal { rx0, .. }, 0) => rx0.receive(data),
+            (Hw::Normal { rx1, .. }, _) => rx1.receive(data),
+            (Hw::Monitoring(can), 0) => can.receive0(data),
+            (Hw::Monitoring(can), _) => can.receive1(data),
+        }
+    }
+
+    fn status(&mut self) -> (ProtocolStatus, ErrorCounters) {
+        match self {
+            Hw::Normal { control, .. } => (control.get_protocol_status(), control.error_counters()),
+            Hw::Monitoring(can) => (can.get_protocol_status(), can.error_counters()),
+        }
+    }
+
+    fn timestamp(&self) -> u16 {
+        match self {
+            Hw::Normal { control, .. } => control.timestamp(),
+            Hw::Monitoring(can) => can.timestamp(),
+        }
+    }
+}
+
+fn bitrate(bit_timings: &CanBitTiming) -> u32 {
+    let bit_quanta = 1 + bit_timings.bs1 as u32 + bit_timings.bs2 as u32;
+    CAN_KERNEL_CLOCK_HZ / (bit_timings.prescaler as u32 * bit_quanta)
+}
+
+fn set_filters<I: fdcan::Instance>(can: &mut fdcan::FdCan<I, fdcan::ConfigMode>, filter: &RxFilter) {
+    let RxFilter::Only { ids, diagnostic } = filter else {
+        can.set_standard_filter(
+            StandardFilterSlot::_0,
+            StandardFilter::accept_all_into_fifo0(),
+        );
+        return;
+    };
+
+    let mut std_ids: heapless::Vec<u16, { STANDARD_FILTER_SLOTS * 2 }> = heapless::Vec::new();
+    let mut ext_ids: heapless::Vec<u32, { EXTENDED_FILTER_SLOTS * 2 }> = heapless::Vec::new();
+    for id in ids.iter().flat_map(|ids| ids.iter()) {
+        match id {
+            Id::Standard(sid) => {
+                if !std_ids.contains(&sid.as_raw()) {
+                    std_ids.push(sid.as_raw()).expect("too many CAN RX IDs");
+                }
+            }
+            Id::Extended(eid) => {
+                if !ext_ids.contains(&eid.as_raw()) {
+                    ext_ids.push(eid.as_raw()).expect("too many extended CAN RX IDs");
+                }
+            }
+        }
+    }
+    std_ids.sort_unstable();
+    ext_ids.sort_unstable();
+
+    // Each slot matches two IDs. If there's an odd number, the last slot
+    // matches the same ID twice.
+    let mut slot = 0;
+    for pair in std_ids.chunks(2) {
+        let (a, b) = (pair[0], *pair.last().unwrap());
+        can.set_standard_filter(
+            StandardFilterSlot::from(slot),
+            StandardFilter {
+                filter: FilterType::DedicatedDual(a, b),
+                action: Action::StoreInFifo0,
+            },
+        );
+        slot += 1;
+    }
+    if let Some(range) = diagnostic {
+        can.set_standard_filter(
+            StandardFilterSlot::from(slot),
+            StandardFilter {
+                filter: FilterType::Range {
+                    from: *range.start(),
+                    to: *range.end(),
+                },
+                action: Action::StoreInFifo1,
+            },
+        );
+        slot += 1;
+    }
+    assert!(slot as usize <= STANDARD_FILTER_SLOTS, "too many CAN RX filters");
+    defmt::debug!("CAN RX filters {} IDs in {} slots", std_ids.len(), slot);
+
+    for (ext_slot, pair) in ext_ids.chunks(2).enumerate() {
+        let (a, b) = (pair[0], *pair.last().unwrap());
+        can.set_extended_filter(
+            ExtendedFilterSlot::from(ext_slot as u8),
+            ExtendedFilter {
+                filter: FilterType::DedicatedDual(a, b),
+                action: Action::StoreInFifo0,
+            },
+        );
+    }
+    if !ext_ids.is_empty() {
+        defmt::debug!("CAN RX filters {} extended IDs", ext_ids.len());
+    }
+
+    can.set_global_filter(
+        GlobalFilter::default()
+            .set_handle_standard_frames(NonMatchingFilter::Reject)
+            .set_handle_extended_frames(NonMatchingFilter::Reject),
+    );
+}
+
 // Public struct for a Queued CAN frame in either direction. Access as an embedded_can::Frame
 // (FDCAN doesn't have a struct for this, it splits headers and their data up.)
 #[derive(Clone, Debug)]
 pub struct QueuedFrame {
     header: TxFrameHeader, // Note: TxFrameHeader is used for TX and RX directions
-    data: [u8; 8],         // Fixed size array, see header.len for 'real' length
+    data: [u8; MAX_DATA_LEN], // Fixed size array, see header.len for 'real' length
+    rx_time: Option<Instant>, // When a received frame arrived, from the hardware timestamp
 }
 
 impl defmt::Format for QueuedFrame {
@@ -159,7 +624,8 @@ impl defmt::Format for QueuedFrame {
         // format the bitfields of the register as struct fields
         defmt::write!(
             f,
-            "CAN Frame (id={=u32:#x}, dlen={}, data={=[u8]:#04x})",
+            "CAN{} Frame (id={=u32:#x}, dlen={}, data={=[u8]:#04x})",
+            if self.is_fd() { " FD" } else { "" },
             match self.header.id.into() {
                 Id::Standard(sid) => sid.as_raw().into(),
                 Id::Extended(eid) => eid.as_raw(),
@@ -170,22 +636,11 @@ impl defmt::Format for QueuedFrame {
     }
 }
 
-impl Ord for QueuedFrame {
-    fn cmp(&self, other: &Self) -> Ordering {
-        FramePriority::from(self.header.id).cmp(&other.header.id.into())
-    }
-}
-
-impl PartialOrd for QueuedFrame {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-// This seems wrong!
+// Two frames are equal if they'd look the same on the bus. Transmit order
+// is decided by TxQueue, not here.
 impl PartialEq for QueuedFrame {
     fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
+        self.id() == other.id() && self.data[..self.dlc()] == other.data[..other.dlc()]
     }
 }
 
@@ -206,28 +661,87 @@ impl QueuedFrame {
         )
     }
 
+    // Create a new QueuedFrame with an extended (29 bit) CAN ID
+    pub fn new_ext(id_raw: u32, data: &[u8]) -> Self {
+        let id = ExtendedId::new(id_raw).unwrap();
+        QueuedFrame::new(id, data).unwrap()
+    }
+
+    /// Create a CAN FD frame, with bit rate switching if `brs`. Data which
+    /// isn't a valid FD length is padded with zeroes to the next one. None if
+    /// the data is over 64 bytes.
+    pub fn new_fd(id: impl Into<Id>, data: &[u8], brs: bool) -> Option<Self> {
+        let len = if data.len() <= 8 {
+            data.len()
+        } else {
+            *FD_LENGTHS.iter().find(|&&len| len >= data.len())?
+        };
+        let mut padded = [0_u8; MAX_DATA_LEN];
+        padded[..data.len()].copy_from_slice(data);
+        Some(Self::new_tx(
+            TxFrameHeader {
+                id: id.into().into(),
+                len: len as u8,
+                frame_format: FrameFormat::Fdcan,
+                bit_rate_switching: brs,
+                marker: None,
+            },
+            &padded[..len],
+        ))
+    }
+
+    pub fn is_fd(&self) -> bool {
+        matches!(self.header.frame_format, FrameFormat::Fdcan)
+    }
+
+    /// Is the data phase of this FD frame sent at the faster bit rate?
+    pub fn is_brs(&self) -> bool {
+        self.header.bit_rate_switching
+    }
+
     pub fn from_frame<F>(frame: F) -> Self where F: Frame {
         QueuedFrame::new(frame.id(), frame.data()).unwrap()
     }
 
+    /// When this frame was received from the bus, as opposed to when it came
+    /// out of the queue. None for frames being transmitted.
+    pub fn rx_time(&self) -> Option<Instant> {
+        self.rx_time
+    }
+
     // Internal constructor for re-queue on transmit
     fn new_tx(header: TxFrameHeader, tx_data: &[u8]) -> Self {
-        let mut data = [0_u8; 8];
-        let dlen = min(header.len, 8) as usize;
+        let mut data = [0_u8; MAX_DATA_LEN];
+        let dlen = min(min(header.len as usize, tx_data.len()), MAX_DATA_LEN);
         data[..dlen].copy_from_slice(&tx_data[..dlen]);
-        Self { header, data }
+        Self {
+            header,
+            data,
+            rx_time: None,
+        }
+    }
+
+    // Internal constructor for a received frame
+    fn from_rx(info: RxFrameInfo, rx_data: &[u8], rx_time: Instant) -> Self {
+        let mut frame = Self::new_tx(info.to_tx_header(None), rx_data);
+        frame.rx_time = Some(rx_time);
+        frame
     }
 
     // Function to pass to transmit_pending(), dequeues a pending
     // message as QueuedFrame
     fn from_pending_transmit(_: fdcan::Mailbox, header: TxFrameHeader, data32: &[u32]) -> Self {
         // Awkward workaround for fdcan passing the data here as &[u32]
-        let mut data = [0_u8; 8];
-        let dlen = min(min(header.len as usize, core::mem::size_of_val(data32)), 8);
+        let mut data = [0_u8; MAX_DATA_LEN];
+        let dlen = min(min(header.len as usize, core::mem::size_of_val(data32)), MAX_DATA_LEN);
         unsafe {
             data[..dlen].copy_from_slice(&data32.align_to::<u8>().1[..dlen]);
         }
-        QueuedFrame { header, data }
+        QueuedFrame {
+            header,
+            data,
+            rx_time: None,
+        }
     }
 }
 
@@ -242,7 +756,7 @@ impl Frame for QueuedFrame
             TxFrameHeader {
                 id: id.into().into(), // urgh!
                 len: len as u8,
-                // No FD-CAN features supported for embedded_can::Frame
+                // embedded_can::Frame is classic CAN only, see new_fd()
                 frame_format: fdcan::frame::FrameFormat::Standard,
                 bit_rate_switching: false,
                 marker: None,
@@ -270,12 +784,13 @@ impl Frame for QueuedFrame
         self.header.id.into()
     }
 
+    // For FD frames this is the length in bytes, not the DLC code
     fn dlc(&self) -> usize {
         self.header.len as usize
     }
 
     fn data(&self) -> &[u8] {
-        &self.data
+        &self.data[..self.dlc()]
     }
 }
 
@@ -283,55 +798,427 @@ impl Eq for QueuedFrame {}
 
 // Public struct for the Tx side. Unlike Rx this isn't an RTIC queue
 // and doesn't block
+//
+// Also holds the bus statistics, as this is the part shared between the
+// interrupt handler and the tasks.
 pub struct Tx<I: fdcan::Instance> {
-    can: fdcan::Tx<I, NormalOperationMode>,
-    queue: BinaryHeap<QueuedFrame, Max, TX_CAPACITY>,
+    // None in Bus Monitoring mode, when everything transmitted is dropped
+    can: Option<fdcan::Tx<I, NormalOperationMode>>,
+    queue: TxQueue<QueuedFrame, TX_CAPACITY, TX_BUFFERS>,
+    bus_off: bool,
+    // Bus is configured for CAN FD frames
+    fd: bool,
+    // If set, CanTx::transmit() only sends these IDs, see restrict_can_tx()
+    can_tx_ids: Option<&'static [Id]>,
+    // Copies of transmitted frames go here, see set_echo()
+    echo: Option<slcan_uart::Sender>,
+    stats: Stats,
 }
 
 impl<I: fdcan::Instance> Tx<I> {
-    fn new(can: fdcan::Tx<I, NormalOperationMode>) -> Self {
+    fn new(can: Option<fdcan::Tx<I, NormalOperationMode>>, bitrate: u32, fd: bool) -> Self {
         Self {
             can,
-            queue: BinaryHeap::new(),
+            fd,
+            queue: TxQueue::new(),
+            bus_off: false,
+            can_tx_ids: None,
+            echo: None,
+            stats: Stats::new(bitrate),
         }
     }
 
-    #[inline]
-    pub fn transmit(&mut self, msg: &impl Frame) {
-        // Convert to a QueuedFrame here, to minimise monomorphisation
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
 
-        // Panic: TODO unsure what to do about unwrap here?
-        self.transmit_frame(QueuedFrame::new(msg.id(), msg.data()).unwrap());
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
     }
 
-    fn transmit_frame(&mut self, frame: QueuedFrame) {
-        let maybe_queue = match self.can.transmit_preserve_frame(
-            &frame,
-            &mut QueuedFrame::from_pending_transmit,
-        ) {
+    /// Has this bus gone Bus Off? Frames transmitted after this are dropped.
+    pub fn is_bus_off(&self) -> bool {
+        self.bus_off
+    }
+
+    /// Drop all queued frames, including any waiting in hardware
+    fn flush(&mut self) {
+        self.queue.clear();
+        if let Some(can) = self.can.as_mut() {
+            can.abort(Mailbox::_0);
+            can.abort(Mailbox::_1);
+            can.abort(Mailbox::_2);
+        }
+    }
+
+    // Forget the IDs of hardware TX buffers which have been sent or aborted
+    fn sync_hw_ids(&mut self) {
+        // Safety: TXBRP is only read, the controller sets and clears it
+        let pending = unsafe { (*I::REGISTERS).txbrp.read().trp().bits() };
+        self.queue.on_pending(pending.into());
+    }
+
+    // Hardware TX buffer the next frame is written to, if there's a free one.
+    // In queue mode this is the lowest numbered free buffer.
+    fn hw_put_index(&self) -> usize {
+        // Safety: TXFQS is read only
+        unsafe { (*I::REGISTERS).txfqs.read().tfqpi().bits() as usize }
+    }
+
+    /// Restart the controller after Bus Off. It rejoins the bus after it has
+    /// seen 129 occurrences of 11 recessive bits, and the Bus Off interrupt
+    /// then reports the new state.
+    fn restart(&mut self) {
+        // Safety: The controller sets CCCR.INIT itself when it goes Bus Off,
+        // clearing it is the documented recovery and doesn't touch any
+        // configuration. fdcan has no API for this from normal mode.
+        unsafe {
+            (*I::REGISTERS).cccr.modify(|_, w| w.init().clear_bit());
+        }
+    }
+
+    /// Only send frames with these IDs through CanTx, and silently drop the
+    /// rest. transmit_queued() isn't affected.
+    ///
+    /// Lets log playback send most of the bus from a capture, while the
+    /// emulation tasks keep running and send only the IDs being tested.
+    pub fn restrict_can_tx(&mut self, ids: &'static [Id]) {
+        self.can_tx_ids = Some(ids);
+    }
+
+    /// Send a copy of each frame transmitted from now on to SLCAN, or stop
+    /// if None. Frames are copied when they're queued, so one which is
+    /// replaced or expires before it's sent is still copied.
+    pub fn set_echo(&mut self, echo: Option<slcan_uart::Sender>) {
+        self.echo = echo;
+    }
+
+    /// Receive frames which don't match the RX filter into FIFO1, or go back
+    /// to rejecting them. Only for a bus set up with RxFilter::Only, to
+    /// stream all of it over SLCAN.
+    ///
+    /// The global filter can only be changed in init, so the controller
+    /// stops for a moment. Frames on the bus meanwhile are missed, and any
+    /// waiting in the hardware TX buffers may be lost.
+    pub fn set_rx_accept_all(&mut self, accept: bool) {
+        // RXGFC.ANFS/ANFE for non-matching frames: 1 into FIFO1, 2 reject
+        let non_matching = if accept { 1 } else { 2 };
+        // Safety: INIT and CCE are set to write RXGFC, as the reference
+        // manual requires. The rest of the configuration isn't touched, and
+        // clearing INIT puts the controller back in the mode it was in.
+        unsafe {
+            let regs = &*I::REGISTERS;
+            regs.cccr.modify(|_, w| w.init().set_bit());
+            while regs.cccr.read().init().bit_is_clear() {}
+            regs.cccr.modify(|_, w| w.cce().set_bit());
+            regs.rxgfc
+                .modify(|_, w| w.anfs().bits(non_matching).anfe().bits(non_matching));
+            regs.cccr.modify(|_, w| w.init().clear_bit());
+            while regs.cccr.read().init().bit_is_set() {}
+        }
+    }
+
+    /// Transmit a QueuedFrame, which unlike CanTx::transmit() can be a CAN FD
+    /// frame. FD frames are dropped if the bus is classic CAN only.
+    pub fn transmit_queued(&mut self, frame: QueuedFrame, lifetime: Duration) {
+        self.submit(frame, lifetime, false, true);
+    }
+
+    /// Transmit a frame from the SLCAN host. The same as transmit_queued(),
+    /// but not echoed back to it.
+    pub fn transmit_injected(&mut self, frame: QueuedFrame, lifetime: Duration) {
+        self.submit(frame, lifetime, false, false);
+    }
+
+    fn submit(&mut self, frame: QueuedFrame, lifetime: Duration, in_order: bool, echo: bool) {
+        if self.can.is_none() {
+            return;
+        }
+        if frame.is_fd() && !self.fd {
+            defmt::error!("Can't send CAN FD frame on classic CAN bus {}", frame);
+            return;
+        }
+        if let Some(sender) = self.echo.as_mut().filter(|_| echo) {
+            // Dropped if the UART can't keep up, the same as received frames
+            let _ = sender.try_send(slcan_uart::Output::Frame(frame.clone()));
+        }
+        let now = Mono::now();
+        self.stats.on_tx(frame.id(), frame.dlc(), now);
+        self.sync_hw_ids();
+        let expires = now + lifetime;
+        if let Some(pending) = self
+            .queue
+            .submit(frame, expires, in_order, now, &mut self.stats)
+        {
+            self.transmit_frame(pending);
+        }
+    }
+
+    fn transmit_frame(&mut self, pending: Pending<QueuedFrame>) {
+        if self.bus_off {
+            return;
+        }
+        let put_index = self.hw_put_index();
+        let Some(can) = self.can.as_mut() else {
+            return;
+        };
+        let id = pending.frame().id();
+        let mut replaced_index = None;
+        // With the header rather than as an embedded_can::Frame, which would
+        // send CAN FD frames as classic ones
+        let result = can.transmit_preserve(
+            pending.frame().header,
+            pending.frame().data(),
+            &mut |mailbox, header, data| {
+                replaced_index = Some(mailbox as usize);
+                QueuedFrame::from_pending_transmit(mailbox, header, data)
+            },
+        );
+        let now = Mono::now();
+        match result {
             // Happy path, there was an empty hardware TX buffer
-            Ok(None) => None,
-            // Preserve the pending TX message that was replaced in hardware
-            Ok(Some(dequeued)) => Some(dequeued),
+            Ok(None) => self.queue.on_write(put_index, id),
+            // Preserve the lower priority frame which was replaced in hardware
+            Ok(Some(dequeued)) => {
+                if let Some(index) = replaced_index {
+                    self.queue.on_write(index, id);
+                }
+                let expires = now + TX_LIFETIME;
+                self.queue
+                    .on_replaced(dequeued, expires, now, &mut self.stats);
+            }
             // Rather than blocking on fdcan, queue this message for later transmit
-            Err(nb::Error::WouldBlock) => Some(frame),
+            Err(nb::Error::WouldBlock) => self.queue.requeue(pending, now, &mut self.stats),
             // Can be replaced once never_patterns stabilises
             Err(nb::Error::Other(_)) => unreachable!("Result is Infallible"),
-        };
-        if let Some(to_queue) = maybe_queue {
-            match self.queue.push(to_queue) {
-                Ok(_) => (),
-                _ => {
-                    defmt::error!("TX queue overflow");
-                    // Generally all the data we send is only useful if fresh, so
-                    // clear the transmit queue if it seems like the bus is offline
-                    self.queue.clear();
-                    // Clear the hardware TX mailboxes as well
-                    self.can.abort(Mailbox::_0);
-                    self.can.abort(Mailbox::_1);
-                    self.can.abort(Mailbox::_2);
+        }
+    }
+
+    // Take the next frame to write to hardware from the software queue
+    fn pop_next(&mut self) -> Option<Pending<QueuedFrame>> {
+        self.sync_hw_ids();
+        self.queue.pop_next(Mono::now(), &mut self.stats)
+    }
+}
+
+impl<I: fdcan::Instance> CanTx for Tx<I> {
+    #[inline]
+    fn transmit(&mut self, msg: &impl Frame) {
+        self.transmit_within(msg, TX_LIFETIME);
+    }
+
+    #[inline]
+    fn transmit_within(&mut self, msg: &impl Frame, lifetime: Duration) {
+        if self.can_tx_ids.is_some_and(|ids| !ids.contains(&msg.id())) {
+            return;
+        }
+        // Convert to a QueuedFrame here, to minimise monomorphisation
+        // Panic: TODO unsure what to do about unwrap here?
+        self.transmit_queued(QueuedFrame::new(msg.id(), msg.data()).unwrap(), lifetime);
+    }
+
+    #[inline]
+    fn transmit_in_order(&mut self, msg: &impl Frame) {
+        if self.can_tx_ids.is_some_and(|ids| !ids.contains(&msg.id())) {
+            return;
+        }
+        let frame = QueuedFrame::new(msg.id(), msg.data()).unwrap();
+        self.submit(frame, TX_LIFETIME, true, true);
+    }
+}
+
+/// Restart a bus which has gone Bus Off, and keep restarting it with an
+/// increasing delay until it stays on. Returns once the bus has recovered.
+///
+/// Spawn this from the on_status callback of Control::on_irq.
+pub async fn recover_bus_off<I, M>(mut m_tx: M)
+where
+    I: fdcan::Instance,
+    M: Mutex<T = Tx<I>>,
+{
+    let mut backoff = BUS_OFF_MIN_BACKOFF;
+    loop {
+        Mono::delay(backoff).await;
+        defmt::info!("Restarting CAN after Bus Off");
+        m_tx.lock(|tx| tx.restart());
+
+        Mono::delay(BUS_OFF_STABLE_TIME).await;
+        if !m_tx.lock(|tx| tx.is_bus_off()) {
+            defmt::info!("CAN recovered from Bus Off");
+            return;
+        }
+        backoff = min(backoff * 2, BUS_OFF_MAX_BACKOFF);
+    }
+}
+
+/// Ways the boot time loopback self-test can fail
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+pub enum SelfTestError {
+    /// Couldn't queue a frame for transmit
+    TxFull,
+    /// A frame which the filters accept never arrived
+    NotReceived { id: u16 },
+    /// A frame arrived with different contents to what was sent
+    Corrupted { id: u16 },
+    /// A frame arrived in the wrong RX FIFO
+    WrongFifo { id: u16 },
+    /// A frame which the filters should reject was received
+    NotRejected { id: u16 },
+}
+
+/// Send frames through the peripheral in internal loopback mode: check they
+/// come back intact, land in the FIFO the filters say, and that queued
+/// frames are sent in priority order.
+///
+/// Only a frame which is lost, corrupted or filtered wrongly is an error.
+/// Frames sent out of priority order are logged, as the bus still works.
+///
+/// This doesn't touch the bus, so it can't check the transceiver or that
+/// the bit timing matches the rest of the car.
+fn self_test<I: fdcan::Instance>(
+    can: &mut fdcan::FdCan<I, InternalLoopbackMode>,
+    filter: &RxFilter,
+) -> Result<(), SelfTestError> {
+    // Pick IDs which should be accepted, and one which shouldn't
+    let mut accepted: heapless::Vec<u16, 2> = heapless::Vec::new();
+    let mut diagnostic_id = None;
+    let mut rejected_id = None;
+    match filter {
+        RxFilter::AcceptAll => {
+            let _ = accepted.push(0x100);
+            let _ = accepted.push(0x200);
+        }
+        RxFilter::Only { ids, diagnostic } => {
+            let is_listed = |raw: u16| {
+                ids.iter()
+                    .flat_map(|ids| ids.iter())
+                    .any(|id| matches!(id, Id::Standard(sid) if sid.as_raw() == raw))
+            };
+            let is_accepted = |raw: u16| {
+                is_listed(raw)
+                    || diagnostic
+                        .as_ref()
+                        .is_some_and(|range| range.contains(&raw))
+            };
+            let mut std_ids = ids.iter().flat_map(|ids| ids.iter()).filter_map(|id| match id {
+                Id::Standard(sid) => Some(sid.as_raw()),
+                Id::Extended(_) => None,
+            });
+            if let Some(first) = std_ids.next() {
+                let _ = accepted.push(first);
+                if let Some(second) = std_ids.find(|&id| id != first) {
+                    let _ = accepted.push(second);
                 }
             }
+            // A listed ID in the diagnostic range goes to FIFO0, so isn't
+            // a test of the range filter
+            diagnostic_id = diagnostic
+                .as_ref()
+                .and_then(|range| range.clone().find(|&raw| !is_listed(raw)));
+            rejected_id = (1..=StandardId::MAX.as_raw()).find(|&raw| !is_accepted(raw));
+        }
+    }
+
+    // RX path, with the filters
+    for (n, &id) in accepted.iter().enumerate() {
+        self_test_send(can, id, n as u8)?;
+        self_test_expect(can, id, n as u8, 0)?;
+    }
+    if let Some(id) = diagnostic_id {
+        self_test_send(can, id, 0xD1)?;
+        self_test_expect(can, id, 0xD1, 1)?;
+    }
+    if let Some(id) = rejected_id {
+        self_test_send(can, id, 0xEE)?;
+        if self_test_receive(can).is_some() {
+            return Err(SelfTestError::NotRejected { id });
+        }
+    }
+
+    // TX priority. All three hardware buffers are filled while the controller
+    // is stopped, so once it's released it only picks between them by
+    // priority: the lower ID first, then the two with the same ID in buffer
+    // order.
+    if let [a, b] = accepted[..] {
+        let (high, low) = (min(a, b), a.max(b));
+        set_init::<I>(true);
+        let sent = [(low, 0x01), (low, 0x02), (high, 0x03)]
+            .into_iter()
+            .try_for_each(|(id, marker)| self_test_send(can, id, marker));
+        set_init::<I>(false);
+        sent?;
+
+        let mut in_order = true;
+        for (id, marker) in [(high, 0x03), (low, 0x01), (low, 0x02)] {
+            let (_, frame) = self_test_receive(can).ok_or(SelfTestError::NotReceived { id })?;
+            in_order &= raw_std_id(&frame) == Some(id) && frame.data()[0] == marker;
+        }
+        if !in_order {
+            defmt::warn!("CAN self-test: frames weren't sent in priority order");
+        }
+    }
+    Ok(())
+}
+
+// Stop or restart the controller without changing its mode. While INIT is
+// set nothing is sent, but frames can still be added to the TX buffers.
+fn set_init<I: fdcan::Instance>(init: bool) {
+    // Safety: INIT can be written without CCE, and doesn't touch any
+    // configuration. fdcan only has an API for it when changing mode.
+    unsafe {
+        let regs = &*I::REGISTERS;
+        regs.cccr.modify(|_, w| w.init().bit(init));
+        while regs.cccr.read().init().bit() != init {}
+    }
+}
+
+fn self_test_send<I: fdcan::Instance>(
+    can: &mut fdcan::FdCan<I, InternalLoopbackMode>,
+    id: u16,
+    marker: u8,
+) -> Result<(), SelfTestError> {
+    let frame = QueuedFrame::new_std(id, &[marker, 0x55, 0xAA, marker]);
+    can.transmit(frame.header, frame.data())
+        .map(|_| ())
+        .map_err(|_| SelfTestError::TxFull)
+}
+
+fn self_test_expect<I: fdcan::Instance>(
+    can: &mut fdcan::FdCan<I, InternalLoopbackMode>,
+    id: u16,
+    marker: u8,
+    fifo: u8,
+) -> Result<(), SelfTestError> {
+    let (rx_fifo, frame) = self_test_receive(can).ok_or(SelfTestError::NotReceived { id })?;
+    if raw_std_id(&frame) != Some(id) || frame.data() != [marker, 0x55, 0xAA, marker] {
+        Err(SelfTestError::Corrupted { id })
+    } else if rx_fifo != fifo {
+        Err(SelfTestError::WrongFifo { id })
+    } else {
+        Ok(())
+    }
+}
+
+// Wait for a looped back frame from either FIFO, returns the FIFO number too
+fn self_test_receive<I: fdcan::Instance>(
+    can: &mut fdcan::FdCan<I, InternalLoopbackMode>,
+) -> Option<(u8, QueuedFrame)> {
+    for _ in 0..SELF_TEST_POLLS {
+        let mut data = [0u8; MAX_DATA_LEN];
+        if let Ok(rx) = can.receive0(&mut data) {
+            return Some((0, QueuedFrame::from_rx(rx.unwrap(), &data, Instant::from_ticks(0))));
+        }
+        if let Ok(rx) = can.receive1(&mut data) {
+            return Some((1, QueuedFrame::from_rx(rx.unwrap(), &data, Instant::from_ticks(0))));
         }
+        cortex_m::asm::delay(SELF_TEST_POLL_CYCLES);
+    }
+    None
+}
+
+fn raw_std_id(frame: &QueuedFrame) -> Option<u16> {
+    match frame.id() {
+        Id::Standard(sid) => Some(sid.as_raw()),
+        Id::Extended(_) => None,
     }
 }
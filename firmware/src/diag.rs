@@ -0,0 +1,100 @@
+//! Diagnostic frames received on PCAN, passed from the RX task to the tasks
+//! which talk UDS (see fakon_core::uds and fakon_core::uds_server).
+use crate::can_queue::{QueuedFrame, Tx};
+use crate::hardware::PCAN;
+use fakon_core::can::CanRx;
+use fakon_core::can_stats::Counters;
+use fakon_core::car::ChargeLock;
+use fakon_core::uds_server;
+use rtic_sync::channel;
+
+/// VIN reported by the emulated IGPM, from FAKON_VIN at build time. Set it to
+/// the VIN the other modules were coded with. The default is only a
+/// placeholder in the format of a 2020 Kona Electric's, with a valid check
+/// digit.
+pub const VIN: &str = match option_env!("FAKON_VIN") {
+    Some(vin) => vin,
+    None => "KM8K53AG0LU000001",
+};
+
+const _: () = assert!(VIN.len() == 17, "FAKON_VIN must be 17 characters");
+
+/// Variant coding reported by the emulated IGPM, from FAKON_IGPM_CODING at
+/// build time (16 hex digits). There's no default, as the other modules check
+/// it against their own coding: it has to be the coding read from the car's
+/// own IGPM.
+pub const IGPM_CODING: [u8; 8] = parse_coding(env!(
+    "FAKON_IGPM_CODING",
+    "set FAKON_IGPM_CODING to the variant coding read from the car's IGPM (16 hex digits)"
+));
+
+const fn parse_coding(hex: &str) -> [u8; 8] {
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 16, "FAKON_IGPM_CODING must be 16 hex digits");
+    let mut coding = [0; 8];
+    let mut i = 0;
+    while i < hex.len() {
+        let digit = match hex[i] {
+            b'0'..=b'9' => hex[i] - b'0',
+            b'a'..=b'f' => hex[i] - b'a' + 10,
+            b'A'..=b'F' => hex[i] - b'A' + 10,
+            _ => panic!("FAKON_IGPM_CODING must be 16 hex digits"),
+        };
+        coding[i / 2] = coding[i / 2] << 4 | digit;
+        i += 1;
+    }
+    coding
+}
+
+/// Frames waiting for a diagnostic task. Enough for a burst of consecutive
+/// frames, ISO-TP flow control stops the sender getting further ahead.
+pub const CAPACITY: usize = 16;
+
+pub type Sender = channel::Sender<'static, QueuedFrame, CAPACITY>;
+
+/// Receiving end, for a task in fakon_core
+pub struct Receiver(pub channel::Receiver<'static, QueuedFrame, CAPACITY>);
+
+impl CanRx for Receiver {
+    type Frame = QueuedFrame;
+
+    async fn recv(&mut self) -> QueuedFrame {
+        // Unwrap: the sender is owned by pcan_rx, which never exits
+        self.0.recv().await.unwrap()
+    }
+
+    fn try_recv(&mut self) -> Option<QueuedFrame> {
+        self.0.try_recv().ok()
+    }
+}
+
+/// Pass a diagnostic frame on. Dropped if the task isn't keeping up, ISO-TP
+/// will time out and the request fails.
+pub fn forward(sender: &mut Sender, frame: &QueuedFrame) {
+    if sender.try_send(frame.clone()).is_err() {
+        defmt::warn!("Diagnostic RX full, dropped {}", frame);
+    }
+}
+
+/// The firmware's side of the UDS server
+pub struct Platform {
+    pub self_test_ok: bool,
+}
+
+impl uds_server::Platform<Tx<PCAN>> for Platform {
+    fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    fn self_test_ok(&self) -> bool {
+        self.self_test_ok
+    }
+
+    fn pcan_counters(&self, tx: &Tx<PCAN>) -> Counters {
+        tx.stats().counters()
+    }
+
+    fn move_charge_port(&mut self, direction: ChargeLock) -> bool {
+        crate::app::task_lock_charge_port::spawn(direction).is_ok()
+    }
+}
@@ -1,11 +1,15 @@
 // "Board level" hardware abstractions, ie pin assignments, etc.
 
+use core::future::Future;
 use defmt::info;
+use fakon_core::time::Clock;
+use fakon_core::{Duration, Instant};
 use fdcan::ConfigMode;
 use fdcan::FdCan;
 use fugit::ExtU32;
 use fugit::RateExtU32;
 use hal::gpio::gpioa;
+use hal::gpio::Alternate;
 use hal::gpio::gpiob;
 use hal::gpio::gpioc;
 use hal::gpio::Floating;
@@ -26,10 +30,25 @@ use stm32g4xx_hal::pwr::PwrExt;
 use stm32g4xx_hal::syscfg::SysCfgExt;
 use stm32g4xx_hal::rcc;
 use stm32g4xx_hal::rcc::{PllConfig, RccExt};
+use stm32g4xx_hal::serial::{self, FullConfig, NoDMA, SerialExt};
+use stm32g4xx_hal::time::U32Ext;
 use stm32g4xx_hal::stm32;
 
+/// FDCAN kernel clock (PCLK1), checked in init()
+pub const CAN_KERNEL_CLOCK_HZ: u32 = 64_000_000;
+
 // Type aliases for hardware peripherals
 pub type PCAN = hal::can::Can<hal::stm32::FDCAN1>;
+// Compressor CAN, for the A/C compressor and PTC heater
+pub type COMPCAN = hal::can::Can<hal::stm32::FDCAN2>;
+pub type SPARECAN = hal::can::Can<hal::stm32::FDCAN3>;
+
+// SLCAN interface, LPUART1 is the Nucleo's ST-LINK virtual COM port
+pub type SlcanSerialTx = serial::Tx<stm32::LPUART1, gpioa::PA2<Alternate<12>>, NoDMA>;
+pub type SlcanSerialRx = serial::Rx<stm32::LPUART1, gpioa::PA3<Alternate<12>>, NoDMA>;
+
+/// Fast enough for PCAN at around 40% load
+const SLCAN_BAUD: u32 = 921_600;
 
 // Type aliases for I/O pins
 pub type AcuCrashOutput = InvertedPin<gpioa::PA4<Output<PushPull>>>;
@@ -60,6 +79,10 @@ pub type ChargeLockSensorInput = gpioc::PC11<Input<Floating>>;
 // Struct to encompass all the board resources, as their functions
 pub struct Board {
     pub pcan_config: FdCan<PCAN, ConfigMode>,
+    pub compcan_config: FdCan<COMPCAN, ConfigMode>,
+    pub sparecan_config: FdCan<SPARECAN, ConfigMode>,
+    pub slcan_serial_tx: SlcanSerialTx,
+    pub slcan_serial_rx: SlcanSerialRx,
     pub srs_crash_out: AcuCrashOutput,
     pub can_timing_500kbps: can_bit_timings::CanBitTiming,
     pub brake_input: BrakeInput,
@@ -83,6 +106,34 @@ pub struct Standby {
 pub const MONOTONIC_FREQUENCY: u32 = 1_000;
 rtic_monotonics::systick_monotonic!(Mono, MONOTONIC_FREQUENCY);
 
+/// Clock for the fakon_core logic, backed by Mono
+#[derive(Clone, Copy, defmt::Format)]
+pub struct MonoClock;
+
+impl Clock for MonoClock {
+    fn now() -> Instant {
+        Mono::now()
+    }
+
+    fn delay(duration: Duration) -> impl Future<Output = ()> {
+        Mono::delay(duration)
+    }
+
+    fn delay_until(instant: Instant) -> impl Future<Output = ()> {
+        Mono::delay_until(instant)
+    }
+}
+
+/// Enable or disable the SLCAN UART's TX empty interrupt. (The HAL only has
+/// this on the Serial, which is split at init.)
+pub fn listen_slcan_tx(enable: bool) {
+    // Safety: After init only TXEIE is changed, and only from the LPUART1
+    // interrupt handler, so this read-modify-write can't race
+    unsafe {
+        (*stm32::LPUART1::ptr()).cr1.modify(|_, w| w.txeie().bit(enable));
+    }
+}
+
 // Hardware init function
 pub fn init(core: cortex_m::Peripherals, mut dp: stm32::Peripherals) -> Board {
     info!("hardware init");
@@ -132,7 +183,7 @@ pub fn init(core: cortex_m::Peripherals, mut dp: stm32::Peripherals) -> Board {
     let gpioc = dp.GPIOC.split(&mut rcc);
     let gpiod = dp.GPIOD.split(&mut rcc);
 
-    assert!(rcc.clocks.apb1_clk.to_MHz() == 64); // Macro requires literal
+    assert!(rcc.clocks.apb1_clk.to_Hz() == CAN_KERNEL_CLOCK_HZ); // Macro requires literal
     let can_timing_500kbps = can_bit_timings::can_timings!(64.mhz(), 500.khz());
 
     // CAN1
@@ -143,19 +194,29 @@ pub fn init(core: cortex_m::Peripherals, mut dp: stm32::Peripherals) -> Board {
     };
 
     // CAN2
-    let _can2_config = {
+    let can2_config = {
         let rx = gpiob.pb12.into_alternate().set_speed(Speed::VeryHigh);
         let tx = gpiob.pb13.into_alternate().set_speed(Speed::VeryHigh);
         dp.FDCAN2.fdcan(tx, rx, &rcc)
     };
 
     // CAN3
-    let _can3_config = {
+    let can3_config = {
         let rx = gpiob.pb3.into_alternate().set_speed(Speed::VeryHigh);
         let tx = gpiob.pb4.into_alternate().set_speed(Speed::VeryHigh);
         dp.FDCAN3.fdcan(tx, rx, &rcc)
     };
 
+    // LPUART1, for SLCAN
+    let (slcan_serial_tx, slcan_serial_rx) = {
+        let tx = gpioa.pa2.into_alternate();
+        let rx = gpioa.pa3.into_alternate();
+        let config = FullConfig::default().baudrate(SLCAN_BAUD.bps());
+        let mut serial = dp.LPUART1.usart(tx, rx, config, &mut rcc).unwrap();
+        serial.listen(serial::Event::Rxne);
+        serial.split()
+    };
+
     // GPIOs from the dev board assignments
 
     // Signal Inputs
@@ -244,6 +305,10 @@ pub fn init(core: cortex_m::Peripherals, mut dp: stm32::Peripherals) -> Board {
 
     Board {
         pcan_config: can1_config,
+        compcan_config: can2_config,
+        sparecan_config: can3_config,
+        slcan_serial_tx,
+        slcan_serial_rx,
         srs_crash_out,
         can_timing_500kbps,
         ig1_on_input,
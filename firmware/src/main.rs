@@ -10,22 +10,12 @@ use stm32g4xx_hal as _; // memory layout
 
 use rtic_monotonics::Monotonic;
 
-mod airbag_control;
 mod can_queue;
-mod car;
-mod dbc;
-mod fresh;
+mod diag;
 mod hardware;
-mod ieb;
-mod igpm;
-mod repeater;
-mod shift_control;
-
-// Make some common type aliases for fugit Duration, Instance and Rate
-// based on our firmware's 1ms tick period
-type Duration = fugit::Duration<u32, 1, 1000>;
-type Instant = fugit::Instant<u32, 1, 1000>;
-type Rate = fugit::Rate<u32, 1, 1000>;
+#[cfg(feature = "playback")]
+mod playback;
+mod slcan_uart;
 
 #[rtic::app(
     device = stm32g4xx_hal::stm32,
@@ -33,45 +23,57 @@ type Rate = fugit::Rate<u32, 1, 1000>;
 )]
 mod app {
     use crate::can_queue;
-    use crate::car;
-    use crate::car::Ignition;
-    use crate::dbc::pcan;
+    use crate::diag;
     use crate::hardware;
-    use crate::hardware::Mono;
-    use crate::shift_control;
+    use crate::hardware::{Mono, MonoClock};
+    use crate::slcan_uart;
     use car::ChargeLock;
-    use debouncr::debounce_stateful_12;
-    use debouncr::debounce_stateful_3;
-    use debouncr::debounce_stateful_5;
-    use debouncr::Edge::Falling;
-    use debouncr::Edge::Rising;
     use defmt::Debug2Format;
     use embedded_can::Frame;
+    use fakon_core::can::{BusState, BusStatus};
+    use fakon_core::dbc::pcan;
+    use fakon_core::gateway::{self, Gateway};
+    use fakon_core::slcan::Slcan;
+    use fakon_core::{airbag_control, car, ieb, igpm, inputs, shift_control, uds, uds_server};
     use embedded_can::Id;
     use fugit::ExtU32;
     use rtic_monotonics::Monotonic;
-    use stm32g4xx_hal::hal::digital::v2::OutputPin;
+    use rtic_sync::make_channel;
+    use stm32g4xx_hal::gpio::ExtiPin;
     use stm32g4xx_hal::prelude::InputPin;
 
-    // Task functions
-    use crate::airbag_control::task_airbag_control;
-    use crate::ieb::task_ieb;
-    use crate::igpm::{self, task_igpm, task_lock_charge_port};
-    use crate::shift_control::task_scu_can_tx;
-    use crate::shift_control::task_scu_pwm_rx;
-    use crate::shift_control::task_scu_pwm_tx;
-
     #[shared]
     struct Shared {
         pcan_tx: can_queue::Tx<hardware::PCAN>,
-        car: car::CarState,
+        compcan_tx: can_queue::Tx<hardware::COMPCAN>,
+        sparecan_tx: can_queue::Tx<hardware::SPARECAN>,
+        car: car::CarState<MonoClock>,
         park_actuator: shift_control::ActuatorState,
+        slcan: Slcan,
+        pcan_gateway: Gateway,
+        compcan_gateway: Gateway,
     }
 
     #[local]
     struct Local {
         pcan_control: can_queue::Control<hardware::PCAN>,
         pcan_rx: can_queue::Rx,
+        compcan_control: can_queue::Control<hardware::COMPCAN>,
+        compcan_rx: can_queue::Rx,
+        sparecan_control: can_queue::Control<hardware::SPARECAN>,
+        sparecan_rx: can_queue::Rx,
+        slcan_sender: slcan_uart::Sender,
+        slcan_irq_sender: slcan_uart::Sender,
+        slcan_receiver: slcan_uart::Receiver,
+        slcan_bytes: slcan_uart::ByteSender,
+        slcan_writer: slcan_uart::UartWriter,
+        slcan_serial_rx: hardware::SlcanSerialRx,
+        diag_sender: diag::Sender,
+        diag_receiver: diag::Receiver,
+        diag_server_sender: diag::Sender,
+        diag_server_receiver: diag::Receiver,
+        diag_igpm_sender: diag::Sender,
+        diag_igpm_receiver: diag::Receiver,
         brake_input: hardware::BrakeInput,
         ig1_on_input: hardware::IG1OnInput,
         relay_ig3: hardware::RelayIG3Output,
@@ -92,6 +94,10 @@ mod app {
 
         let hardware::Board {
             pcan_config,
+            compcan_config,
+            sparecan_config,
+            slcan_serial_tx,
+            slcan_serial_rx,
             srs_crash_out,
             can_timing_500kbps,
             brake_input,
@@ -107,32 +113,149 @@ mod app {
             standby,
         } = hardware::init(cx.core, cx.device);
 
-        let (pcan_control, pcan_rx, pcan_tx) =
-            can_queue::Control::init(pcan_config, &can_timing_500kbps);
+        let pcan_gateway = Gateway::new(gateway::PCAN_TO_COMPCAN);
+        let compcan_gateway = Gateway::new(gateway::COMPCAN_TO_PCAN);
+        let pcan_gateway_ids: heapless::Vec<Id, { gateway::MAX_ROUTES }> =
+            pcan_gateway.ids().collect();
+
+        // Only receive what the emulation and gateway use, plus diagnostics.
+        // CarState::pcan_receiving() (which decides when to go to standby)
+        // only sees car::CAN_RX_IDS as a result, see there. SLCAN opens the
+        // filter up to everything while it's in use.
+        let pcan_filter = can_queue::RxFilter::Only {
+            ids: &[
+                car::CAN_RX_IDS,
+                shift_control::CAN_RX_IDS,
+                igpm::CAN_RX_IDS,
+                &pcan_gateway_ids,
+            ],
+            diagnostic: Some(0x700..=0x7FF),
+        };
+        // A sniffer must never drive the bus, not even to ACK a frame
+        let bus_mode = if cfg!(feature = "sniffer") {
+            can_queue::BusMode::Monitoring
+        } else {
+            can_queue::BusMode::Normal
+        };
+        let (mut pcan_control, pcan_rx, pcan_tx) = can_queue::Control::init(
+            pcan_config,
+            &can_timing_500kbps,
+            can_queue::FrameMode::Classic,
+            &pcan_filter,
+            bus_mode,
+            make_channel!(can_queue::QueuedFrame, can_queue::RX_CAPACITY),
+        );
+        pcan_control.set_rx_priority(&[car::CAN_RX_PRIORITY_IDS, shift_control::CAN_RX_PRIORITY_IDS]);
+        let (compcan_control, compcan_rx, compcan_tx) = can_queue::Control::init(
+            compcan_config,
+            &can_timing_500kbps,
+            can_queue::FrameMode::Classic,
+            &can_queue::RxFilter::AcceptAll,
+            bus_mode,
+            make_channel!(can_queue::QueuedFrame, can_queue::RX_CAPACITY),
+        );
+        let (sparecan_control, sparecan_rx, sparecan_tx) = can_queue::Control::init(
+            sparecan_config,
+            &can_timing_500kbps,
+            can_queue::FrameMode::Classic,
+            &can_queue::RxFilter::AcceptAll,
+            bus_mode,
+            make_channel!(can_queue::QueuedFrame, can_queue::RX_CAPACITY),
+        );
+
+        // Nothing is emulated on the other buses yet, so their self-test
+        // results are only logged
+        let pcan_ok = pcan_control.self_test_result().is_ok();
+        if !pcan_ok {
+            defmt::error!("PCAN failed self-test, not starting emulation");
+        }
+
+        if cfg!(feature = "sniffer") {
+            defmt::warn!("Sniffer build: listening only, no emulation");
+        }
+
+        let (slcan_sender, slcan_receiver) =
+            make_channel!(slcan_uart::Output, slcan_uart::CAPACITY);
+        let slcan_irq_sender = slcan_sender.clone();
+        let (slcan_bytes, slcan_bytes_receiver) = make_channel!(u8, slcan_uart::BYTES_CAPACITY);
+        let slcan_writer = slcan_uart::UartWriter::new(slcan_serial_tx, slcan_bytes_receiver);
+
+        let (diag_sender, diag_receiver) = make_channel!(can_queue::QueuedFrame, diag::CAPACITY);
+        let diag_receiver = diag::Receiver(diag_receiver);
+        let (diag_server_sender, diag_server_receiver) =
+            make_channel!(can_queue::QueuedFrame, diag::CAPACITY);
+        let diag_server_receiver = diag::Receiver(diag_server_receiver);
+        let (diag_igpm_sender, diag_igpm_receiver) =
+            make_channel!(can_queue::QueuedFrame, diag::CAPACITY);
+        let diag_igpm_receiver = diag::Receiver(diag_igpm_receiver);
 
         let car = car::CarState::new();
 
         let park_actuator = shift_control::ActuatorState::default();
 
         pcan_rx::spawn().unwrap();
+        compcan_rx::spawn().unwrap();
+        sparecan_rx::spawn().unwrap();
         poll_slow_inputs::spawn().unwrap();
-        task_airbag_control::spawn().unwrap();
-        task_ieb::spawn().unwrap();
-        task_igpm::spawn().unwrap();
-        task_scu_can_tx::spawn().unwrap();
-        task_scu_pwm_tx::spawn().unwrap();
+        #[cfg(feature = "playback")]
+        let pcan_tx = {
+            let mut pcan_tx = pcan_tx;
+            if pcan_ok {
+                defmt::warn!("Playback build: PCAN is mostly played back from a log");
+                pcan_tx.restrict_can_tx(crate::playback::CONFIG.emulated);
+                task_playback::spawn().unwrap();
+            }
+            pcan_tx
+        };
+        if pcan_ok && !cfg!(feature = "sniffer") {
+            task_airbag_control::spawn().unwrap();
+            task_ieb::spawn().unwrap();
+            task_igpm::spawn().unwrap();
+            task_igpm_diag::spawn().unwrap();
+            task_scu_can_tx::spawn().unwrap();
+            task_scu_pwm_tx::spawn().unwrap();
+            task_uds_tester::spawn().unwrap();
+        }
+        if !cfg!(feature = "sniffer") {
+            // Runs even if the self-test failed, to report it
+            task_uds_server::spawn(pcan_ok).unwrap();
+        }
         log_info::spawn().unwrap();
+        slcan_out::spawn().unwrap();
+        // Still runs in a sniffer build, the car can't wake up without the
+        // IG3 relay
         ignition_sequence::spawn().unwrap();
 
         (
             Shared {
                 pcan_tx,
+                compcan_tx,
+                sparecan_tx,
                 car,
                 park_actuator,
+                slcan: Slcan::new(),
+                pcan_gateway,
+                compcan_gateway,
             },
             Local {
                 pcan_control,
                 pcan_rx,
+                compcan_control,
+                compcan_rx,
+                sparecan_control,
+                sparecan_rx,
+                slcan_sender,
+                slcan_irq_sender,
+                slcan_receiver,
+                slcan_bytes,
+                slcan_writer,
+                slcan_serial_rx,
+                diag_sender,
+                diag_receiver,
+                diag_server_sender,
+                diag_server_receiver,
+                diag_igpm_sender,
+                diag_igpm_receiver,
                 brake_input,
                 srs_crash_out,
                 ig1_on_input,
@@ -149,6 +272,10 @@ mod app {
         )
     }
 
+    /// IDs the emulation handles from PCAN, the same as in the RX filter
+    const PCAN_EMULATION_IDS: [&[Id]; 3] =
+        [car::CAN_RX_IDS, shift_control::CAN_RX_IDS, igpm::CAN_RX_IDS];
+
     fn is_diagnostic(id: Id) -> bool {
         match id {
             Id::Standard(std) => std.as_raw() >= 0x700,
@@ -156,17 +283,44 @@ mod app {
         }
     }
 
-    #[task(local = [pcan_rx], shared = [car, park_actuator], priority = 4)]
+    #[task(
+        local = [pcan_rx, slcan_sender, diag_sender, diag_server_sender, diag_igpm_sender],
+        shared = [car, park_actuator, slcan, pcan_gateway, compcan_tx],
+        priority = 4
+    )]
     async fn pcan_rx(cx: pcan_rx::Context) {
         let pcan_rx = cx.local.pcan_rx;
         let mut car = cx.shared.car;
         let mut park_actuator = cx.shared.park_actuator;
+        let mut slcan = cx.shared.slcan;
+        let mut gateway = cx.shared.pcan_gateway;
+        let mut compcan_tx = cx.shared.compcan_tx;
 
         loop {
             let frame = pcan_rx.recv().await.unwrap();
+            // Frames from the can_queue always have a timestamp
+            let rx_time = frame.rx_time().unwrap_or_else(Mono::now);
+            slcan_uart::forward(cx.local.slcan_sender, &mut slcan, &frame);
+            gateway.lock(|gateway| gateway.on_rx(&frame, rx_time, &mut compcan_tx));
             if is_diagnostic(frame.id()) {
-                // Skip diagnostic IDs for now (can change to be a hw filter, or
-                // possibly will add diagnostic handlers elsewhere
+                // Diagnostic IDs arrive via RX FIFO1. Only responses to
+                // Fakon's own requests, and requests to Fakon or the
+                // emulated IGPM are handled, the rest is a scan tool talking
+                // to the Kona modules.
+                if uds::is_response(frame.id()) {
+                    diag::forward(cx.local.diag_sender, &frame);
+                } else if frame.id() == uds_server::REQUEST_ID {
+                    diag::forward(cx.local.diag_server_sender, &frame);
+                } else if frame.id() == igpm::DIAG_REQUEST_ID {
+                    diag::forward(cx.local.diag_igpm_sender, &frame);
+                }
+                continue;
+            }
+            if !PCAN_EMULATION_IDS
+                .iter()
+                .any(|ids| ids.contains(&frame.id()))
+            {
+                // Only received for the gateway, or for SLCAN
                 continue;
             }
             let msg = pcan::Messages::from_can_message(frame.id(), frame.data());
@@ -182,207 +336,269 @@ mod app {
                     // msg implements Format but reporting it here results in RX overruns
                     defmt::trace!("PCAN RX {:?}", frame);
 
-                    car.lock(|car| car.update_state(&msg));
+                    car.lock(|car| car.update_state(&msg, rx_time));
 
                     shift_control::on_can_rx(&msg, &mut park_actuator);
 
-                    igpm::on_can_rx(&msg);
+                    if let Some(direction) = igpm::on_can_rx(&msg) {
+                        if cfg!(feature = "sniffer") {
+                            defmt::info!("Charge port {} requested (sniffer, ignored)", direction);
+                        } else {
+                            // Result: Ignoring result because an existing lock/unlock may be in progress
+                            let _ = task_lock_charge_port::spawn(direction);
+                        }
+                    }
                 }
             }
         }
     }
 
-    // Task declarations all extern-ed to split the firmware up into modules
-    extern "Rust" {
-        #[task(shared = [pcan_tx, car], local = [srs_crash_out], priority = 3)]
-        async fn task_airbag_control(cx: task_airbag_control::Context);
+    // Nothing is emulated on COMP CAN or the spare bus yet, so only log what
+    // arrives and pass on anything the gateway routes to PCAN
+    #[task(local = [compcan_rx], shared = [compcan_gateway, pcan_tx], priority = 4)]
+    async fn compcan_rx(cx: compcan_rx::Context) {
+        let mut gateway = cx.shared.compcan_gateway;
+        let mut pcan_tx = cx.shared.pcan_tx;
+        loop {
+            let frame = cx.local.compcan_rx.recv().await.unwrap();
+            defmt::trace!("COMP CAN RX {:?}", frame);
+            let rx_time = frame.rx_time().unwrap_or_else(Mono::now);
+            gateway.lock(|gateway| gateway.on_rx(&frame, rx_time, &mut pcan_tx));
+        }
+    }
 
-        #[task(shared = [pcan_tx, car], priority = 3)]
-        async fn task_ieb(cx: task_ieb::Context);
+    #[task(local = [sparecan_rx], priority = 4)]
+    async fn sparecan_rx(cx: sparecan_rx::Context) {
+        loop {
+            let frame = cx.local.sparecan_rx.recv().await.unwrap();
+            defmt::trace!("Spare CAN RX {:?}", frame);
+        }
+    }
 
-        #[task(shared = [pcan_tx, car], priority = 3)]
-        async fn task_igpm(cx: task_igpm::Context);
+    // Task wrappers only hand over RTIC resources, the task logic lives in
+    // the modules so the firmware is split up (and can run outside of RTIC)
+    #[task(shared = [pcan_tx, car], local = [srs_crash_out], priority = 3)]
+    async fn task_airbag_control(cx: task_airbag_control::Context) {
+        airbag_control::task_airbag_control(cx.shared.car, cx.shared.pcan_tx, cx.local.srs_crash_out)
+            .await
+    }
 
-        #[task(shared = [car], local=[charge_lock_drive, charge_lock_dir], priority = 2)]
-        async fn task_lock_charge_port(cx: task_lock_charge_port::Context, direction: ChargeLock);
+    #[task(shared = [pcan_tx, car], priority = 3)]
+    async fn task_ieb(cx: task_ieb::Context) {
+        ieb::task_ieb(cx.shared.car, cx.shared.pcan_tx).await
+    }
 
-        #[task(shared = [pcan_tx, car, park_actuator], priority = 3)]
-        async fn task_scu_can_tx(cx: task_scu_can_tx::Context);
+    #[task(shared = [pcan_tx, car], priority = 3)]
+    async fn task_igpm(cx: task_igpm::Context) {
+        igpm::task_igpm(cx.shared.car, cx.shared.pcan_tx).await
+    }
 
-        #[task(shared = [car, park_actuator], local = [scu_park_tx], priority = 6)]
-        async fn task_scu_pwm_tx(cx: task_scu_pwm_tx::Context);
+    // Other modules expect a quick response, so above the Fakon UDS tasks
+    #[task(shared = [pcan_tx], local = [diag_igpm_receiver], priority = 2)]
+    async fn task_igpm_diag(cx: task_igpm_diag::Context) {
+        igpm::task_diag::<MonoClock, _, _, _>(
+            diag::VIN,
+            diag::IGPM_CODING,
+            cx.local.diag_igpm_receiver,
+            cx.shared.pcan_tx,
+        )
+        .await
+    }
 
-        #[task(binds = EXTI2, shared = [car, park_actuator], local = [scu_park_rx], priority = 6)]
-        fn task_scu_pwm_rx(cx: task_scu_pwm_rx::Context);
+    #[cfg(feature = "playback")]
+    #[task(shared = [pcan_tx], priority = 3)]
+    async fn task_playback(cx: task_playback::Context) {
+        use crate::playback;
+        playback::task_playback(playback::LOG, &playback::CONFIG, cx.shared.pcan_tx).await
     }
 
-    // FDCAN_INTR0_IT and FDCAN_INTR1_IT are swapped, until stm32g4 crate
-    // updates to include https://github.com/stm32-rs/stm32-rs/pull/996
-    #[task(binds = FDCAN1_INTR1_IT, shared = [car, pcan_tx], local=[pcan_control], priority = 6)]
-    fn pcan_irq(mut cx: pcan_irq::Context) {
-        let set_bus_off = || { cx.shared.car.lock(|car| car.set_pcan_bus_off()) };
-        cx.local.pcan_control.on_irq(cx.shared.pcan_tx, set_bus_off);
+    // Above slcan_out, so a busy SLCAN host doesn't delay the ISO-TP flow
+    // control past the modules' timeouts
+    #[task(shared = [car, pcan_tx], local = [diag_receiver], priority = 2)]
+    async fn task_uds_tester(cx: task_uds_tester::Context) {
+        uds::task_tester(
+            cx.shared.car,
+            cx.local.diag_receiver,
+            cx.shared.pcan_tx,
+            cfg!(feature = "clear-dtcs"),
+        )
+        .await
     }
 
-    // Power state changes are slow, so poll them in a timed loop with some debounce logic
-    #[task(shared = [car], local = [brake_input, ev_ready, charge_lock_sensor], priority = 5)]
-    async fn poll_slow_inputs(mut cx: poll_slow_inputs::Context) {
-        // Time base for the debouncing delays
-        let PERIOD = 10.millis();
-        // Debouncers. Each debounce period is (_N * PERIOD)
-        let mut brakes_on = debounce_stateful_3(false);
-        let mut ev_ready = debounce_stateful_5(false);
-        // Note: we track the charge port lock state here not from task_lock_charge_port as it
-        // can also be manually unlocked at any time
-        let mut charge_lock = debounce_stateful_12(false);
-
-        let mut next = Mono::now() + PERIOD;
-        loop {
-            Mono::delay_until(next).await;
-            next += PERIOD;
+    // Above slcan_out, the same as the tester
+    #[task(
+        shared = [car, park_actuator, pcan_tx],
+        local = [diag_server_receiver],
+        priority = 2
+    )]
+    async fn task_uds_server(cx: task_uds_server::Context, pcan_ok: bool) {
+        uds_server::task_server(
+            cx.shared.car,
+            cx.shared.park_actuator,
+            cx.local.diag_server_receiver,
+            cx.shared.pcan_tx,
+            diag::Platform { self_test_ok: pcan_ok },
+        )
+        .await
+    }
 
-            let brakes_edge = brakes_on.update(cx.local.brake_input.is_high().unwrap());
-            let ready_edge = ev_ready.update(cx.local.ev_ready.is_high().unwrap());
-            let charge_lock_edge = charge_lock.update(cx.local.charge_lock_sensor.is_high().unwrap());
+    #[task(shared = [car], local=[charge_lock_drive, charge_lock_dir], priority = 2)]
+    async fn task_lock_charge_port(cx: task_lock_charge_port::Context, direction: ChargeLock) {
+        igpm::task_lock_charge_port(
+            cx.shared.car,
+            cx.local.charge_lock_drive,
+            cx.local.charge_lock_dir,
+            direction,
+        )
+        .await
+    }
 
-            if [brakes_edge, ready_edge, charge_lock_edge]
-                .into_iter()
-                .any(|o| o.is_some())
-            {
-                cx.shared.car.lock(|car| {
-                    if let Some(edge) = brakes_edge {
-                        car.set_is_braking(edge == Rising);
-                    }
-                    if let Some(edge) = ready_edge {
-                        car.set_ev_ready_input(edge == Rising);
-                    }
-                    if let Some(edge) = charge_lock_edge {
-                        car.set_charge_port(match edge {
-                            Rising => ChargeLock::Unlocked,
-                            Falling => ChargeLock::Locked,
-                        });
-                    }
-                });
-            }
-        }
+    #[task(shared = [pcan_tx, car, park_actuator], priority = 3)]
+    async fn task_scu_can_tx(cx: task_scu_can_tx::Context) {
+        shift_control::task_scu_can_tx(cx.shared.car, cx.shared.park_actuator, cx.shared.pcan_tx)
+            .await
     }
 
-    /// Tracks the ignition state of the vehicle by monitoring:
-    /// - IG1 power on input
-    /// - CAN messages sent when IG3 powered on (may change to hard-wired in future)
-    /// - Any CAN messages received at all
-    ///
-    /// Will go to Standby (pending a reset) if rest of vehicle is asleep.
-    #[task(shared = [car], local = [ig1_on_input, led_ignition, relay_ig3, standby], priority = 4)]
-    async fn ignition_sequence(cx: ignition_sequence::Context) {
-        let mut car = cx.shared.car;
-        let mut ig1_on = debounce_stateful_5(false);
-        let mut idle_count = 0;
+    #[task(shared = [car, park_actuator], local = [scu_park_tx], priority = 6)]
+    async fn task_scu_pwm_tx(cx: task_scu_pwm_tx::Context) {
+        shift_control::task_scu_pwm_tx(cx.shared.car, cx.shared.park_actuator, cx.local.scu_park_tx)
+            .await
+    }
 
-        loop {
-            let ignition = car.lock(|car| car.ignition());
+    // Pin interrupt for edge transitions of SCU RX PWM signal
+    #[task(binds = EXTI2, shared = [car, park_actuator], local = [scu_park_rx], priority = 6)]
+    fn task_scu_pwm_rx(cx: task_scu_pwm_rx::Context) {
+        let now = Mono::now();
+        let rising = cx.local.scu_park_rx.is_high().unwrap();
+        shift_control::on_scu_pwm_edge(cx.shared.car, cx.shared.park_actuator, rising, now);
+        cx.local.scu_park_rx.clear_interrupt_pending_bit();
+    }
 
-            let ig1_edge = ig1_on.update(cx.local.ig1_on_input.is_high().unwrap());
+    #[task(
+        binds = LPUART1,
+        shared = [slcan, pcan_tx],
+        local = [slcan_serial_rx, slcan_irq_sender, slcan_writer],
+        priority = 5
+    )]
+    fn slcan_irq(cx: slcan_irq::Context) {
+        slcan_uart::on_uart_irq(
+            cx.local.slcan_serial_rx,
+            cx.local.slcan_irq_sender,
+            cx.shared.slcan,
+            cx.shared.pcan_tx,
+        );
+        cx.local.slcan_writer.on_irq();
+    }
 
-            let ig3_alive = car.lock(|car| car.ig3_appears_powered());
+    #[task(shared = [slcan], local = [slcan_receiver, slcan_bytes], priority = 1)]
+    async fn slcan_out(cx: slcan_out::Context) {
+        slcan_uart::task_slcan_out(cx.local.slcan_receiver, cx.local.slcan_bytes, cx.shared.slcan)
+            .await
+    }
 
-            let pcan_bus_off = car.lock(|car| car.pcan_bus_off());
+    // FDCAN_INTR0_IT and FDCAN_INTR1_IT are swapped, until stm32g4 crate
+    // updates to include https://github.com/stm32-rs/stm32-rs/pull/996
+    #[task(binds = FDCAN1_INTR1_IT, shared = [car, pcan_tx], local=[pcan_control], priority = 6)]
+    fn pcan_irq(mut cx: pcan_irq::Context) {
+        let on_status = |status: BusStatus| {
+            cx.shared.car.lock(|car| car.set_pcan_status(status));
+            if status.state == BusState::BusOff {
+                // Result: Ignoring result as recovery may already be running
+                let _ = pcan_bus_off_recovery::spawn();
+            }
+        };
+        cx.local.pcan_control.on_irq(cx.shared.pcan_tx, on_status);
+    }
 
-            // PCAN Bus should be functioning *unless* ignition is off
-            // and we're about to go to standby anyway
-            //
-            // TODO: handle this in a better way than panicking
-            assert!(!pcan_bus_off || ignition == Ignition::Off);
+    #[task(shared = [pcan_tx], priority = 2)]
+    async fn pcan_bus_off_recovery(cx: pcan_bus_off_recovery::Context) {
+        can_queue::recover_bus_off(cx.shared.pcan_tx).await
+    }
 
-            let next_ignition = match ignition {
-                Ignition::Off => {
-                    if ig1_edge == Some(Rising) {
-                        Some(Ignition::On)
-                    }
-                    else if ig3_alive {
-                        Some(Ignition::IG3)
-                    }
-                    else if pcan_bus_off || !car.lock(|car| car.pcan_receiving()) {
-                        // FIXME: Hacky check while we don't have wakeup sources
-                        // to automatically get back out of standby mode: wait
-                        // at least 10 more seconds before going into standby to
-                        // give the rest of the vehicle a chance to start up if
-                        // we just powered on
-                        idle_count += 1;
-                        if idle_count > 500 {
-                            cx.local.standby.enter_standby_mode().await
-                        } else {
-                            None
-                        }
-                    }
-                    else {
-                        idle_count = 0;
-                        None // No change
-                    }
-                },
-                Ignition::IG3 => {
-                    if ig1_edge == Some(Rising) {
-                        Some(Ignition::On)
-                    }
-                    else if !ig3_alive {
-                        Some(Ignition::Off)
-                    }
-                    else {
-                        None // No change
-                    }
+    #[task(binds = FDCAN2_INTR1_IT, shared = [compcan_tx], local=[compcan_control], priority = 6)]
+    fn compcan_irq(cx: compcan_irq::Context) {
+        cx.local.compcan_control.on_irq(cx.shared.compcan_tx, |status| {
+            if status.state == BusState::BusOff {
+                let _ = compcan_bus_off_recovery::spawn();
+            }
+        });
+    }
 
-                },
-                Ignition::On => {
-                    if ig1_edge == Some(Falling) {
-                        if ig3_alive {
-                            Some(Ignition::IG3)
-                        } else {
-                            // This starts a shutdown sequence that last
-                            // slightly over 3 minutes before the VCU and other
-                            // modules stop sending messages (if ignition stays off).
-                            //
-                            // Can possibly make this time out faster by telling it the
-                            // doors are locked, or stopping some of our own IGPM messages.
-                            Some(Ignition::Off)
-                        }
-                    }
-                    else {
-                        None // No change
-                    }
-                },
-            };
-
-            if let Some(next) = next_ignition {
-                car.lock(|car| car.set_ignition(next));
-
-                // Update the IG3 external power on relay, if needed
-                if next == Ignition::On {
-                    cx.local.relay_ig3.set_high().unwrap();
-                    cx.local.led_ignition.set_high().unwrap();
-                } else {
-                    cx.local.relay_ig3.set_low().unwrap();
-                    cx.local.led_ignition.set_low().unwrap();
-                }
+    #[task(binds = FDCAN3_INTR1_IT, shared = [sparecan_tx], local=[sparecan_control], priority = 6)]
+    fn sparecan_irq(cx: sparecan_irq::Context) {
+        cx.local.sparecan_control.on_irq(cx.shared.sparecan_tx, |status| {
+            if status.state == BusState::BusOff {
+                let _ = sparecan_bus_off_recovery::spawn();
             }
+        });
+    }
 
-            Mono::delay(20.millis()).await;
-        }
+    #[task(shared = [compcan_tx], priority = 2)]
+    async fn compcan_bus_off_recovery(cx: compcan_bus_off_recovery::Context) {
+        can_queue::recover_bus_off(cx.shared.compcan_tx).await
+    }
+
+    #[task(shared = [sparecan_tx], priority = 2)]
+    async fn sparecan_bus_off_recovery(cx: sparecan_bus_off_recovery::Context) {
+        can_queue::recover_bus_off(cx.shared.sparecan_tx).await
+    }
+
+    // Power state changes are slow, so poll them in a timed loop with some debounce logic
+    #[task(shared = [car], local = [brake_input, ev_ready, charge_lock_sensor], priority = 5)]
+    async fn poll_slow_inputs(cx: poll_slow_inputs::Context) {
+        inputs::poll_slow_inputs(
+            cx.shared.car,
+            cx.local.brake_input,
+            cx.local.ev_ready,
+            cx.local.charge_lock_sensor,
+        )
+        .await
+    }
+
+    /// Tracks the ignition state of the vehicle, see inputs::ignition_sequence.
+    ///
+    /// Will go to Standby (pending a reset) if rest of vehicle is asleep.
+    #[task(shared = [car], local = [ig1_on_input, led_ignition, relay_ig3, standby], priority = 4)]
+    async fn ignition_sequence(cx: ignition_sequence::Context) {
+        inputs::ignition_sequence(
+            cx.shared.car,
+            cx.local.ig1_on_input,
+            cx.local.relay_ig3,
+            cx.local.led_ignition,
+        )
+        .await;
+        cx.local.standby.enter_standby_mode().await
     }
 
-    #[task(shared = [car], priority = 0)]
+    #[task(shared = [car, pcan_tx, pcan_gateway, compcan_gateway], priority = 0)]
     async fn log_info(mut cx: log_info::Context) {
+        let mut count = 0u32;
         loop {
             Mono::delay(2.secs()).await;
+            count += 1;
+
+            cx.shared.pcan_tx.lock(|tx| {
+                let stats = tx.stats_mut();
+                stats.log_summary("PCAN", Mono::now());
+                // Per-ID detail is a lot of log, so only every 30s
+                if count % 15 == 0 {
+                    stats.log_ids("PCAN");
+                    stats.clear_periods();
+                }
+            });
+            cx.shared.pcan_gateway.lock(|gateway| gateway.log_summary("PCAN"));
+            cx.shared.compcan_gateway.lock(|gateway| gateway.log_summary("COMP CAN"));
 
             cx.shared.car.lock(|car| {
                 defmt::info!(
-                    "Ign: {:?} Gear: {:?} Con: {:?} Batt: {:05}% Inv: {:?}V RPM: {:?}",
+                    "Ign: {:?} Gear: {:?} Con: {:?} Batt: {:05}% Inv: {:?}V RPM: {:?} PCAN: {:?}",
                     car.ignition(),
                     car.gear(),
                     car.contactor(),
                     car.soc_batt(),
                     car.v_inverter(),
                     car.motor_rpm(),
+                    car.pcan_status(),
                 );
             });
         }
@@ -0,0 +1,59 @@
+//! Play back a CAN log from flash onto PCAN, with its original timing.
+//!
+//! For working out which of the unknown messages the VCU actually needs:
+//! play back a capture from a real car, then move IDs from the log to
+//! Fakon's emulation one at a time (or filter them out altogether) and see
+//! what changes.
+//!
+//! The log is built in from the file named by the FAKON_PLAYBACK_LOG
+//! environment variable, which is made with `fakon-sim --convert-log`. Edit
+//! CONFIG below to set up the experiment.
+use crate::can_queue::{QueuedFrame, Tx};
+use crate::hardware::Mono;
+use embedded_can::{Frame, Id};
+use fakon_core::can_log::{self, PlaybackConfig};
+use fakon_core::dbc::pcan;
+use rtic::Mutex;
+use rtic_monotonics::Monotonic;
+
+pub static LOG: &[u8] = include_bytes!(env!("FAKON_PLAYBACK_LOG"));
+
+pub const CONFIG: PlaybackConfig<'static> = PlaybackConfig {
+    only: None,
+    emulated: EMULATED,
+    repeat: true,
+};
+
+/// IDs sent by Fakon's emulation instead of the log, e.g. to check it
+/// matches what the VCU expects from the real module
+const EMULATED: &[Id] = &[pcan::Cgw5b3::MESSAGE_ID, pcan::Cgw588::MESSAGE_ID];
+
+/// A queued frame is replaced by the next one with its ID anyway, so this
+/// only needs to cover the longest period in the log
+const LIFETIME: fakon_core::Duration = fakon_core::Duration::millis(1000);
+
+pub async fn task_playback<I, MTX>(log: &'static [u8], config: &PlaybackConfig<'_>, mut pcan_tx: MTX)
+where
+    I: fdcan::Instance,
+    MTX: Mutex<T = Tx<I>>,
+{
+    defmt::info!("Playing back {} byte CAN log", log.len());
+    loop {
+        let start = Mono::now();
+        let mut played = 0u32;
+        for record in can_log::records(log) {
+            if !config.plays(record.id) {
+                continue;
+            }
+            Mono::delay_until(start + record.offset).await;
+            // Unwrap: records can only hold classic CAN frames
+            let frame = QueuedFrame::new(record.id, record.data).unwrap();
+            pcan_tx.lock(|tx| tx.transmit_queued(frame, LIFETIME));
+            played += 1;
+        }
+        defmt::info!("CAN log finished, {} frames played back", played);
+        if !config.repeat || played == 0 {
+            return;
+        }
+    }
+}
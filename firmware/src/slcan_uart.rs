@@ -0,0 +1,170 @@
+//! SLCAN interface for PC tools, over the LPUART which is wired to the
+//! Nucleo's ST-LINK virtual COM port. See fakon_core::slcan for the protocol.
+//!
+//! While the channel is open all of PCAN is streamed to the host: the RX
+//! filter is opened up to pass every frame (the ones the emulation doesn't
+//! use go into FIFO1 with the diagnostics), and Fakon's own transmitted
+//! frames are echoed. Frames from the host are transmitted on PCAN, but not
+//! echoed back to it.
+//!
+//! Opening and closing the channel stops PCAN for a moment to change the
+//! filter. While it's open the extra frames load the CPU, and a busy bus can
+//! overrun FIFO1 and drop diagnostic frames. Frames are dropped if the UART
+//! can't keep up.
+//!
+//! Output to the host is written from the UART interrupt as the UART has
+//! room, so nothing waits on it.
+use crate::can_queue::{QueuedFrame, Tx, TX_LIFETIME};
+use crate::hardware::{self, Mono, SlcanSerialRx, SlcanSerialTx};
+use fakon_core::slcan::{Slcan, MAX_LINE};
+use rtic::Mutex;
+use rtic_monotonics::Monotonic;
+use rtic_sync::channel::{self, TrySendError};
+use stm32g4xx_hal::hal::serial::{Read, Write};
+use stm32g4xx_hal::stm32::Interrupt;
+
+/// Lines waiting to be written to the host
+pub const CAPACITY: usize = 32;
+/// Bytes waiting for the UART, a couple of lines
+pub const BYTES_CAPACITY: usize = 64;
+
+pub enum Output {
+    /// Received or transmitted CAN frame
+    Frame(QueuedFrame),
+    /// Reply to a command
+    Reply(&'static [u8]),
+}
+
+pub type Sender = channel::Sender<'static, Output, CAPACITY>;
+pub type Receiver = channel::Receiver<'static, Output, CAPACITY>;
+
+pub type ByteSender = channel::Sender<'static, u8, BYTES_CAPACITY>;
+pub type ByteReceiver = channel::Receiver<'static, u8, BYTES_CAPACITY>;
+
+/// Pass a received PCAN frame on to the host, if it's listening. Frames are
+/// dropped if the UART can't keep up with the bus.
+pub fn forward<MS>(sender: &mut Sender, slcan: &mut MS, frame: &QueuedFrame)
+where
+    MS: Mutex<T = Slcan>,
+{
+    if slcan.lock(|slcan| slcan.is_open()) {
+        let _ = sender.try_send(Output::Frame(frame.clone()));
+    }
+}
+
+/// UART interrupt handler, decodes commands from the host. See also
+/// UartWriter::on_irq().
+pub fn on_uart_irq<I, MS, MTX>(
+    serial_rx: &mut SlcanSerialRx,
+    sender: &mut Sender,
+    mut slcan: MS,
+    mut pcan_tx: MTX,
+) where
+    I: fdcan::Instance,
+    MS: Mutex<T = Slcan>,
+    MTX: Mutex<T = Tx<I>>,
+{
+    loop {
+        let byte = match serial_rx.read() {
+            Ok(byte) => byte,
+            Err(nb::Error::WouldBlock) => return,
+            Err(nb::Error::Other(err)) => {
+                // Reading clears the error, there may be more data after it
+                defmt::warn!("SLCAN UART error {}", defmt::Debug2Format(&err));
+                continue;
+            }
+        };
+        let (response, was_open, open) = slcan.lock(|slcan| {
+            let was_open = slcan.is_open();
+            (slcan.on_byte(byte), was_open, slcan.is_open())
+        });
+        if open != was_open {
+            let echo = open.then(|| sender.clone());
+            pcan_tx.lock(|tx| {
+                tx.set_rx_accept_all(open);
+                tx.set_echo(echo);
+            });
+        }
+        if let Some(response) = response {
+            if let Some(frame) = response.transmit {
+                // Not through CanTx, so a restricted Tx still sends it
+                let frame = QueuedFrame::from_frame(frame);
+                pcan_tx.lock(|tx| tx.transmit_injected(frame, TX_LIFETIME));
+            }
+            if sender.try_send(Output::Reply(response.reply)).is_err() {
+                defmt::warn!("SLCAN output full, reply dropped");
+            }
+        }
+    }
+}
+
+/// Encode replies and received frames for the host, in order, and pass them
+/// to the UartWriter a byte at a time.
+pub async fn task_slcan_out<MS>(receiver: &mut Receiver, bytes: &mut ByteSender, mut slcan: MS)
+where
+    MS: Mutex<T = Slcan>,
+{
+    let mut line = [0u8; MAX_LINE];
+    while let Ok(output) = receiver.recv().await {
+        let line_bytes = match &output {
+            Output::Reply(reply) => *reply,
+            Output::Frame(frame) => {
+                let rx_time = frame.rx_time().unwrap_or_else(Mono::now);
+                match slcan.lock(|slcan| slcan.encode(frame, rx_time, &mut line)) {
+                    Some(len) => &line[..len],
+                    None => continue, // Closed since the frame was queued
+                }
+            }
+        };
+        for &byte in line_bytes {
+            if let Err(TrySendError::Full(byte)) = bytes.try_send(byte) {
+                // Make sure the UART is draining it before waiting for room
+                rtic::pend(Interrupt::LPUART1);
+                // Result: the receiver is owned by the UartWriter, which is
+                // never dropped
+                let _ = bytes.send(byte).await;
+            }
+        }
+        rtic::pend(Interrupt::LPUART1);
+    }
+}
+
+/// Writes the bytes from task_slcan_out() to the UART, from the UART
+/// interrupt. The TX empty interrupt is only enabled while there's more to
+/// write.
+pub struct UartWriter {
+    serial_tx: SlcanSerialTx,
+    bytes: ByteReceiver,
+    // Taken from the channel but the UART wasn't ready for it
+    held: Option<u8>,
+}
+
+impl UartWriter {
+    pub fn new(serial_tx: SlcanSerialTx, bytes: ByteReceiver) -> Self {
+        Self {
+            serial_tx,
+            bytes,
+            held: None,
+        }
+    }
+
+    /// Write as much as the UART takes. Call from the UART interrupt.
+    pub fn on_irq(&mut self) {
+        loop {
+            let Some(byte) = self.held.take().or_else(|| self.bytes.try_recv().ok()) else {
+                hardware::listen_slcan_tx(false);
+                return;
+            };
+            match self.serial_tx.write(byte) {
+                Ok(()) => (),
+                Err(nb::Error::WouldBlock) => {
+                    self.held = Some(byte);
+                    hardware::listen_slcan_tx(true);
+                    return;
+                }
+                // Can be replaced once never_patterns stabilises
+                Err(nb::Error::Other(_)) => unreachable!("Result is Infallible"),
+            }
+        }
+    }
+}
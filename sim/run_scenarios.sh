@@ -0,0 +1,23 @@
+#!/bin/sh
+# Run the simulator's unit tests, every scenario in scenarios/ with the
+# simulated powertrain, and replay every capture in captures/ against its
+# saved timeline. Fail if any of them fail. Used by CI.
+set -e
+cd "$(dirname "$0")"
+cargo build --quiet
+cargo test --quiet
+failed=0
+for scenario in scenarios/*.txt; do
+    echo "== $scenario"
+    if ! cargo run --quiet -- --powertrain --duration 120 "$scenario" > /dev/null; then
+        failed=1
+    fi
+done
+for capture in captures/*.log captures/*.asc; do
+    [ -e "$capture" ] || continue
+    echo "== $capture"
+    if ! cargo run --quiet -- --replay "$capture" --expect "${capture%.*}.timeline"; then
+        failed=1
+    fi
+done
+exit $failed
@@ -0,0 +1,41 @@
+//! Host stand-in for the firmware's can_queue module.
+//!
+//! The virtual bus has no arbitration or mailboxes, frames are collected in
+//! the order they were transmitted for the simulator to print and forward.
+use embedded_can::{Frame, Id};
+use fakon_core::can::CanTx;
+use std::collections::VecDeque;
+
+/// A CAN frame on the virtual bus
+#[derive(Clone, Debug)]
+pub struct SimFrame {
+    pub id: Id,
+    pub data: Vec<u8>,
+}
+
+impl SimFrame {
+    pub fn from_frame(frame: &impl Frame) -> Self {
+        Self {
+            id: frame.id(),
+            data: frame.data().to_vec(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Tx {
+    pending: VecDeque<SimFrame>,
+}
+
+impl Tx {
+    /// Take all frames transmitted since the last call, in order
+    pub fn drain(&mut self) -> impl Iterator<Item = SimFrame> + '_ {
+        self.pending.drain(..)
+    }
+}
+
+impl CanTx for Tx {
+    fn transmit(&mut self, msg: &impl Frame) {
+        self.pending.push_back(SimFrame::from_frame(msg));
+    }
+}
@@ -0,0 +1,52 @@
+//! Minimal single threaded executor standing in for RTIC.
+//!
+//! Every task is polled once per virtual time tick. There is no preemption and
+//! tasks never run concurrently, so shared resources are plain RefCells.
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use rtic_core::Mutex;
+use std::rc::Rc;
+
+/// Stand-in for an RTIC shared resource. Clones refer to the same value.
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Rc::new(RefCell::new(value)))
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Mutex for Shared<T> {
+    type T = T;
+
+    fn lock<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.0.borrow_mut())
+    }
+}
+
+#[derive(Default)]
+pub struct Executor {
+    tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl Executor {
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(task));
+    }
+
+    /// Poll each task once at the current virtual time, and drop any which
+    /// have run to completion.
+    pub fn poll_all(&mut self) {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        self.tasks
+            .retain_mut(|task| task.as_mut().poll(&mut cx).is_pending());
+    }
+}
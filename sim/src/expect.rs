@@ -0,0 +1,232 @@
+//! Expectations in simulator scripts, so a script can be run as a test.
+//!
+//! Each expectation starts at its time in the script and has to be met within
+//! a window of milliseconds after that (0 means at that exact tick):
+//!
+//! ```text
+//! # time_ms  expect  window  what
+//! 1000       expect  200     tx   BodyState ignition_sw=On ign1=true
+//! 1000       expect  200     tx   Cgw5b3 power_state=On
+//! 1200       expect  0       car  ignition On
+//! 1600       expect  50      out  charge_lock_drive low
+//! ```
+//!
+//! `tx` matches a frame transmitted by Fakon, by message name and any number
+//! of `signal=value` pairs. Values are as printed in the simulator output.
+//!
+//! `car` matches a CarState field: ignition, contactor, gear, ev_ready,
+//! braking, charge_port, evse_detected or pcan_receiving. Stale values read
+//! as `Stale`.
+//!
+//! `out` matches the logical level of one of Fakon's output pins.
+use crate::replay::fresh_str;
+use crate::timestamp;
+use fakon_core::car::CarState;
+use fakon_core::fresh::IsFresh;
+use fakon_core::time::Clock;
+use fakon_core::{Duration, Instant};
+
+const CAR_FIELDS: &[&str] = &[
+    "ignition",
+    "contactor",
+    "gear",
+    "ev_ready",
+    "braking",
+    "charge_port",
+    "evse_detected",
+    "pcan_receiving",
+];
+
+#[derive(Clone, Debug)]
+pub enum Check {
+    Tx {
+        message: String,
+        signals: Vec<(String, String)>,
+    },
+    Car {
+        field: String,
+        value: String,
+    },
+    Out {
+        pin: String,
+        level: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Expect {
+    /// Script line, for reporting
+    pub line: usize,
+    pub window: Duration,
+    pub check: Check,
+}
+
+impl Expect {
+    /// Parse the fields after "expect"
+    pub fn parse(line: usize, fields: &[&str]) -> Result<Self, String> {
+        let window = fields
+            .first()
+            .and_then(|f| f.parse::<u32>().ok())
+            .map(Duration::millis)
+            .ok_or("expected window in milliseconds")?;
+
+        let check = match fields.get(1..) {
+            Some(["tx", message, signals @ ..]) => Check::Tx {
+                message: message.to_string(),
+                signals: signals
+                    .iter()
+                    .map(|s| {
+                        s.split_once('=')
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .ok_or(format!("expected signal=value, not '{s}'"))
+                    })
+                    .collect::<Result<_, _>>()?,
+            },
+            Some(["car", field, value]) => {
+                if !CAR_FIELDS.contains(field) {
+                    return Err(format!("unknown car field '{field}'"));
+                }
+                Check::Car {
+                    field: field.to_string(),
+                    value: value.to_string(),
+                }
+            }
+            Some(["out", pin, level]) => Check::Out {
+                pin: pin.to_string(),
+                level: match *level {
+                    "high" | "1" => true,
+                    "low" | "0" => false,
+                    _ => return Err("expected high or low".to_string()),
+                },
+            },
+            _ => return Err("expected tx, car or out check".to_string()),
+        };
+
+        Ok(Self {
+            line,
+            window,
+            check,
+        })
+    }
+}
+
+/// Tracks the expectations which have started, until they're met or their
+/// window runs out.
+#[derive(Default)]
+pub struct Checker {
+    pending: Vec<(Instant, Expect)>, // (Deadline, Expectation)
+    failures: Vec<String>,
+    passed: usize,
+}
+
+impl Checker {
+    pub fn start(&mut self, now: Instant, expect: Expect) {
+        self.pending.push((now + expect.window, expect));
+    }
+
+    /// Check pending `tx` expectations against a transmitted message, given
+    /// its Debug output.
+    pub fn on_tx(&mut self, msg_debug: &str) {
+        self.pending.retain(|(_, expect)| {
+            let met = match &expect.check {
+                Check::Tx { message, signals } => {
+                    msg_debug
+                        .strip_prefix(message.as_str())
+                        .is_some_and(|rest| rest.starts_with('('))
+                        && signals.iter().all(|(k, v)| {
+                            msg_debug.contains(&format!("{k}: {v},"))
+                                || msg_debug.contains(&format!("{k}: {v} }}"))
+                        })
+                }
+                _ => false,
+            };
+            if met {
+                self.passed += 1;
+            }
+            !met
+        });
+    }
+
+    /// Check pending `car` and `out` expectations against the current state,
+    /// then fail any which have passed their deadline. `output` looks up an
+    /// output pin level by name.
+    pub fn on_tick<C: Clock>(
+        &mut self,
+        now: Instant,
+        car: &CarState<C>,
+        output: impl Fn(&str) -> Option<bool>,
+    ) {
+        let mut failures = Vec::new();
+        self.pending.retain(|(deadline, expect)| {
+            let met = match &expect.check {
+                Check::Car { field, value } => car_field(car, field) == *value,
+                Check::Out { pin, level } => match output(pin) {
+                    Some(actual) => actual == *level,
+                    None => {
+                        failures.push(format!("line {}: unknown output '{pin}'", expect.line));
+                        return false;
+                    }
+                },
+                Check::Tx { .. } => false,
+            };
+            if met {
+                self.passed += 1;
+                false
+            } else if now >= *deadline {
+                let actual = match &expect.check {
+                    Check::Car { field, .. } => format!(", was {}", car_field(car, field)),
+                    _ => String::new(),
+                };
+                failures.push(format!(
+                    "line {}: {} not met by {}{}",
+                    expect.line,
+                    describe(&expect.check),
+                    timestamp(*deadline),
+                    actual
+                ));
+                false
+            } else {
+                true
+            }
+        });
+        self.failures.append(&mut failures);
+    }
+
+    /// Fail anything still pending at the end of the run, and return all
+    /// the failures (empty if every expectation passed).
+    pub fn finish(mut self) -> (usize, Vec<String>) {
+        for (_, expect) in self.pending.drain(..) {
+            self.failures.push(format!(
+                "line {}: {} still pending at end of simulation",
+                expect.line,
+                describe(&expect.check)
+            ));
+        }
+        (self.passed, self.failures)
+    }
+}
+
+fn describe(check: &Check) -> String {
+    match check {
+        Check::Tx { message, signals } => {
+            let signals: Vec<String> = signals.iter().map(|(k, v)| format!(" {k}={v}")).collect();
+            format!("tx {message}{}", signals.concat())
+        }
+        Check::Car { field, value } => format!("car {field} {value}"),
+        Check::Out { pin, level } => format!("out {pin} {}", if *level { "high" } else { "low" }),
+    }
+}
+
+fn car_field<C: Clock>(car: &CarState<C>, field: &str) -> String {
+    match field {
+        "ignition" => format!("{:?}", car.ignition()),
+        "contactor" => fresh_str(car.contactor().get()),
+        "gear" => fresh_str(car.gear().get()),
+        "ev_ready" => car.ev_ready().to_string(),
+        "braking" => car.is_braking().to_string(),
+        "charge_port" => format!("{:?}", car.charge_port()),
+        "evse_detected" => fresh_str(car.evse_detected().get()),
+        "pcan_receiving" => car.pcan_receiving().to_string(),
+        _ => unreachable!("checked when parsing"),
+    }
+}
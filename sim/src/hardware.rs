@@ -0,0 +1,54 @@
+//! Host stand-ins for the firmware's "board level" hardware abstractions.
+//!
+//! The simulator's clock is fakon_core's StepClock, which only moves when the
+//! simulator advances it, so a simulation runs as fast as the host allows and
+//! gives the same result every time.
+use core::cell::Cell;
+use core::convert::Infallible;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use std::rc::Rc;
+
+pub use fakon_core::time::StepClock as SimClock;
+
+/// Simulated GPIO pin. Clones share the same level, so the simulator can keep
+/// one handle and give the other to a task as an input or output.
+///
+/// Levels are logical, i.e. after any InvertedPin in the firmware.
+#[derive(Clone, Default)]
+pub struct SimPin(Rc<Cell<bool>>);
+
+impl SimPin {
+    pub fn set(&self, level: bool) {
+        self.0.set(level);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.get()
+    }
+}
+
+impl InputPin for SimPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.get())
+    }
+}
+
+impl OutputPin for SimPin {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.set(true);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.set(false);
+        Ok(())
+    }
+}
@@ -0,0 +1,595 @@
+//! Fakon host simulator.
+//!
+//! Runs the firmware's emulation tasks (IGPM, IEB, airbag control, SCU and
+//! the ignition state machine) against a virtual PCAN bus in virtual time,
+//! with the hard wired inputs driven from a script. Each frame Fakon transmits
+//! is printed decoded, one per line, so the output of two commits can be
+//! diffed.
+//!
+//! The emulation comes from fakon-core, the modules in this crate stand in for
+//! the firmware modules which talk to the hardware.
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::Mutex as StdMutex;
+
+use embedded_can::Id;
+use fakon_core::dbc::pcan;
+use fakon_core::time::Clock;
+use fakon_core::{airbag_control, can_log, car, ieb, igpm, inputs, shift_control, Instant};
+use fugit::ExtU32;
+use rtic_core::Mutex;
+
+mod can_queue;
+mod executor;
+mod expect;
+mod hardware;
+mod powertrain;
+mod replay;
+mod script;
+#[cfg(feature = "vcan")]
+mod vcan;
+
+use executor::{Executor, Shared};
+use hardware::{SimClock, SimPin};
+use script::{Action, Input};
+
+const USAGE: &str = "\
+Usage: fakon-sim [options] [SCRIPT]
+       fakon-sim --replay LOG [--expect FILE]
+       fakon-sim --convert-log LOG OUT
+       fakon-sim --powertrain-only --vcan IFACE [SCRIPT]
+
+Options:
+  --duration SECS    Stop after this much virtual time (default 60)
+  --vcan IFACE       Also send and receive frames on a SocketCAN interface
+                     (runs in real time, needs the 'vcan' feature)
+  --defmt-log FILE   Write the firmware's defmt log frames to FILE, for
+                     decoding with 'defmt-print -e <fakon-sim binary>'
+  --powertrain       Also simulate the Kona VCU, BMS, OBC and inverter
+  --powertrain-only  Only simulate the Kona powertrain, not Fakon. Use with
+                     --vcan to bench test the firmware on a real bus
+  --replay LOG       Instead of running the tasks, feed a candump or ASC
+                     capture through CarState and print the state timeline
+  --expect FILE      Compare the --replay timeline with FILE, and fail if it
+                     differs
+  --convert-log LOG  Convert a candump or ASC capture to a binary log for the
+                     firmware's playback feature, written to OUT
+";
+
+struct Args {
+    script: Option<String>,
+    duration_secs: u32,
+    vcan: Option<String>,
+    defmt_log: Option<String>,
+    replay: Option<String>,
+    expect: Option<String>,
+    convert_log: Option<(String, String)>,
+    powertrain: bool,
+    powertrain_only: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        script: None,
+        duration_secs: 60,
+        vcan: None,
+        defmt_log: None,
+        replay: None,
+        expect: None,
+        convert_log: None,
+        powertrain: false,
+        powertrain_only: false,
+    };
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--duration" => {
+                args.duration_secs = value()?
+                    .parse()
+                    .map_err(|_| "invalid duration".to_string())?
+            }
+            "--vcan" => args.vcan = Some(value()?),
+            "--defmt-log" => args.defmt_log = Some(value()?),
+            "--replay" => args.replay = Some(value()?),
+            "--expect" => args.expect = Some(value()?),
+            "--convert-log" => {
+                let log = value()?;
+                args.convert_log = Some((log, value()?));
+            }
+            "--powertrain" => args.powertrain = true,
+            "--powertrain-only" => {
+                args.powertrain = true;
+                args.powertrain_only = true;
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => args.script = Some(arg),
+        }
+    }
+    if args.expect.is_some() && args.replay.is_none() {
+        return Err("--expect needs --replay".to_string());
+    }
+    Ok(args)
+}
+
+/// All the simulated pins, as seen from the simulator side
+#[derive(Default)]
+struct Pins {
+    ig1_on: SimPin,
+    brake: SimPin,
+    ev_ready: SimPin,
+    charge_lock_sensor: SimPin,
+    relay_ig3: SimPin,
+    led_ignition: SimPin,
+    srs_crash_out: SimPin,
+    scu_park_tx: SimPin,
+    charge_lock_drive: SimPin,
+    charge_lock_dir: SimPin,
+}
+
+impl Pins {
+    fn output(&self, name: &str) -> Option<&SimPin> {
+        Some(match name {
+            "relay_ig3" => &self.relay_ig3,
+            "led_ignition" => &self.led_ignition,
+            "srs_crash_out" => &self.srs_crash_out,
+            "scu_park_tx" => &self.scu_park_tx,
+            "charge_lock_drive" => &self.charge_lock_drive,
+            "charge_lock_dir" => &self.charge_lock_dir,
+            _ => return None,
+        })
+    }
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("{msg}");
+            }
+            eprint!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = &args.defmt_log {
+        let file = File::create(path).expect("failed to create defmt log");
+        *DEFMT_LOG.lock().unwrap() = Some(file);
+    }
+
+    if let Some(path) = &args.replay {
+        run_replay(path, args.expect.as_deref());
+        return;
+    }
+
+    if let Some((path, out)) = &args.convert_log {
+        convert_log(path, out);
+        return;
+    }
+
+    let events = match &args.script {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).expect("failed to read script");
+            script::parse(&text).unwrap_or_else(|e| {
+                eprintln!("{path}: {e}");
+                std::process::exit(2);
+            })
+        }
+        None => Vec::new(),
+    };
+    if !args.powertrain && events.iter().any(|e| matches!(e.action, Action::Powertrain(_))) {
+        eprintln!("script has plug, gear or rpm events, these need --powertrain");
+        std::process::exit(2);
+    }
+
+    #[cfg(feature = "vcan")]
+    let mut vcan = args
+        .vcan
+        .as_ref()
+        .map(|iface| vcan::Vcan::open(iface).expect("failed to open SocketCAN interface"));
+    #[cfg(not(feature = "vcan"))]
+    if args.vcan.is_some() {
+        eprintln!("--vcan needs fakon-sim built with --features vcan");
+        std::process::exit(2);
+    }
+    let realtime = args.vcan.is_some();
+
+    let pins = Pins::default();
+    let car = Shared::new(car::CarState::<SimClock>::new());
+    let park_actuator = Shared::new(shift_control::ActuatorState::default());
+    let pcan_tx = Shared::new(can_queue::Tx::default());
+    let standby = Rc::new(Cell::new(false));
+    let lock_busy = Rc::new(Cell::new(false));
+
+    let mut powertrain = args
+        .powertrain
+        .then(|| powertrain::Powertrain::new(pins.ev_ready.clone()));
+    let mut powertrain_state = powertrain::State::Asleep;
+
+    let mut executor = Executor::default();
+    if !args.powertrain_only {
+        spawn_tasks(&mut executor, &pins, &car, &park_actuator, &pcan_tx, &standby);
+    }
+
+    let start = std::time::Instant::now();
+    let end = Instant::from_ticks(0) + args.duration_secs.secs();
+    let mut events = events.into_iter().peekable();
+    let mut outputs = OutputLog::default();
+    let mut scu_pwm = ScuPwmRx::default();
+    let mut now = Instant::from_ticks(0);
+    let mut powertrain_tx = Vec::new();
+    let has_expects = events.clone().any(|e| matches!(e.action, Action::Expect(_)));
+    let mut checker = expect::Checker::default();
+
+    loop {
+        SimClock::advance_to(now);
+
+        let mut received = Vec::new();
+        while let Some(event) = events.next_if(|e| e.at <= now) {
+            match event.action {
+                Action::SetInput(input, level) => {
+                    println!("{} IN  {:?} => {}", timestamp(now), input, level);
+                    match input {
+                        Input::Ig1 => &pins.ig1_on,
+                        Input::Brake => &pins.brake,
+                        Input::EvReady => &pins.ev_ready,
+                        Input::ChargeLockSensor => &pins.charge_lock_sensor,
+                    }
+                    .set(level);
+                }
+                Action::Receive(frame) => received.push(frame),
+                Action::ScuPwm(duty) => {
+                    println!("{} IN  ScuPwm => {}%", timestamp(now), duty);
+                    scu_pwm.next_duty = duty;
+                }
+                Action::Expect(expect) => checker.start(now, expect),
+                Action::Powertrain(event) => {
+                    println!("{} IN  {:?}", timestamp(now), event);
+                    if let Some(msg) = powertrain.as_mut().and_then(|p| p.apply(now, event)) {
+                        println!("{} PT  {}", timestamp(now), msg);
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "vcan")]
+        if let Some(vcan) = vcan.as_mut() {
+            while let Some(frame) = vcan.recv() {
+                received.push(frame);
+            }
+        }
+
+        // Equivalent of the firmware's task_scu_pwm_rx pin interrupt
+        if let Some(rising) = scu_pwm.tick(now) {
+            shift_control::on_scu_pwm_edge(car.clone(), park_actuator.clone(), rising, now);
+        }
+
+        // Frames from the simulated powertrain arrive a tick after they're sent
+        received.append(&mut powertrain_tx);
+
+        if let Some(powertrain) = powertrain.as_mut() {
+            // Without Fakon running, whatever is received is from the firmware
+            if args.powertrain_only {
+                for frame in &received {
+                    if let Ok(msg) = pcan::Messages::from_can_message(frame.id, &frame.data) {
+                        powertrain.on_can_rx(now, &msg);
+                    }
+                }
+                received.clear();
+            }
+        }
+
+        // Equivalent of the firmware's pcan_rx task
+        for frame in received {
+            let msg = match pcan::Messages::from_can_message(frame.id, &frame.data) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    println!("{} RX  {} (unknown) {:02X?}", timestamp(now), id_str(frame.id), frame.data);
+                    continue;
+                }
+            };
+            car.clone().lock(|car| car.update_state(&msg, now));
+            shift_control::on_can_rx(&msg, &mut park_actuator.clone());
+            if let Some(direction) = igpm::on_can_rx(&msg) {
+                // Same as RTIC, a spawn while the task is already running fails
+                if !lock_busy.replace(true) {
+                    let (car, mut drive, mut dir, busy) = (
+                        car.clone(),
+                        pins.charge_lock_drive.clone(),
+                        pins.charge_lock_dir.clone(),
+                        lock_busy.clone(),
+                    );
+                    executor.spawn(async move {
+                        igpm::task_lock_charge_port(car, &mut drive, &mut dir, direction).await;
+                        busy.set(false);
+                    });
+                }
+            }
+        }
+
+        executor.poll_all();
+
+        if powertrain.is_some() && pins.charge_lock_drive.get() {
+            // Simulated charge port actuator, the sensor is high when unlocked
+            pins.charge_lock_sensor.set(!pins.charge_lock_dir.get());
+        }
+
+        for frame in pcan_tx.clone().lock(|tx| tx.drain().collect::<Vec<_>>()) {
+            match pcan::Messages::from_can_message(frame.id, &frame.data) {
+                Ok(msg) => {
+                    let msg_debug = format!("{msg:?}");
+                    println!("{} TX  {} {}", timestamp(now), id_str(frame.id), msg_debug);
+                    checker.on_tx(&msg_debug);
+                    if let Some(powertrain) = powertrain.as_mut() {
+                        powertrain.on_can_rx(now, &msg);
+                    }
+                }
+                Err(_) => println!("{} TX  {} {:02X?}", timestamp(now), id_str(frame.id), frame.data),
+            }
+            #[cfg(feature = "vcan")]
+            if let Some(vcan) = vcan.as_mut() {
+                vcan.send(&frame);
+            }
+        }
+
+        if let Some(powertrain) = powertrain.as_mut() {
+            for frame in powertrain.poll(now) {
+                #[cfg(feature = "vcan")]
+                if let Some(vcan) = vcan.as_mut() {
+                    vcan.send(&frame);
+                }
+                if !args.powertrain_only {
+                    powertrain_tx.push(frame);
+                }
+            }
+            if powertrain.state() != powertrain_state {
+                powertrain_state = powertrain.state();
+                println!("{} PT  {:?}", timestamp(now), powertrain_state);
+            }
+        }
+
+        outputs.update(now, &pins);
+        car.clone().lock(|car| {
+            checker.on_tick(now, car, |name| pins.output(name).map(SimPin::get))
+        });
+
+        if standby.get() {
+            println!("{} Standby", timestamp(now));
+            break;
+        }
+        if now >= end {
+            break;
+        }
+
+        now += 1.millis();
+
+        if realtime {
+            let elapsed = std::time::Duration::from_millis(now.ticks().into());
+            if let Some(wait) = elapsed.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+
+    if has_expects {
+        let (passed, failures) = checker.finish();
+        for failure in &failures {
+            eprintln!("FAIL {failure}");
+        }
+        eprintln!("{} expectations passed, {} failed", passed, failures.len());
+        if !failures.is_empty() {
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Spawn the emulation tasks, the equivalent of the firmware's init
+fn spawn_tasks(
+    executor: &mut Executor,
+    pins: &Pins,
+    car: &Shared<car::CarState<SimClock>>,
+    park_actuator: &Shared<shift_control::ActuatorState>,
+    pcan_tx: &Shared<can_queue::Tx>,
+    standby: &Rc<Cell<bool>>,
+) {
+    {
+        let (car, mut brake, mut ev_ready, mut lock) = (
+            car.clone(),
+            pins.brake.clone(),
+            pins.ev_ready.clone(),
+            pins.charge_lock_sensor.clone(),
+        );
+        executor.spawn(async move {
+            inputs::poll_slow_inputs(car, &mut brake, &mut ev_ready, &mut lock).await
+        });
+    }
+    {
+        let (car, mut ig1, mut relay, mut led, standby) = (
+            car.clone(),
+            pins.ig1_on.clone(),
+            pins.relay_ig3.clone(),
+            pins.led_ignition.clone(),
+            standby.clone(),
+        );
+        executor.spawn(async move {
+            inputs::ignition_sequence(car, &mut ig1, &mut relay, &mut led).await;
+            standby.set(true);
+        });
+    }
+    {
+        let (car, tx, mut out) = (car.clone(), pcan_tx.clone(), pins.srs_crash_out.clone());
+        executor.spawn(async move { airbag_control::task_airbag_control(car, tx, &mut out).await });
+    }
+    executor.spawn(ieb::task_ieb(car.clone(), pcan_tx.clone()));
+    executor.spawn(igpm::task_igpm(car.clone(), pcan_tx.clone()));
+    executor.spawn(shift_control::task_scu_can_tx(
+        car.clone(),
+        park_actuator.clone(),
+        pcan_tx.clone(),
+    ));
+    {
+        let (car, actuator, mut out) =
+            (car.clone(), park_actuator.clone(), pins.scu_park_tx.clone());
+        executor.spawn(async move { shift_control::task_scu_pwm_tx(car, actuator, &mut out).await });
+    }
+}
+
+fn run_replay(path: &str, expect: Option<&str>) {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        std::process::exit(2);
+    });
+    let frames = replay::parse_log(&text).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        std::process::exit(2);
+    });
+    let timeline = replay::timeline(&frames);
+
+    let Some(expect) = expect else {
+        for line in timeline {
+            println!("{line}");
+        }
+        return;
+    };
+
+    let expected = std::fs::read_to_string(expect).unwrap_or_else(|e| {
+        eprintln!("{expect}: {e}");
+        std::process::exit(2);
+    });
+    let expected: Vec<&str> = expected.lines().collect();
+    let mismatch = (0..timeline.len().max(expected.len()))
+        .find(|&i| timeline.get(i).map(String::as_str) != expected.get(i).copied());
+    if let Some(i) = mismatch {
+        eprintln!("{path}: timeline differs from {expect} at line {}", i + 1);
+        eprintln!("  expected: {}", expected.get(i).unwrap_or(&"<end>"));
+        eprintln!("  actual:   {}", timeline.get(i).map_or("<end>", String::as_str));
+        eprintln!("(run without --expect and redirect the output to update it)");
+        std::process::exit(1);
+    }
+}
+
+fn convert_log(path: &str, out: &str) {
+    let text = std::fs::read_to_string(path).expect("failed to read capture");
+    let frames = replay::parse_log(&text).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        std::process::exit(2);
+    });
+    let mut log = Vec::new();
+    let mut count = 0;
+    for (at, frame) in &frames {
+        if frame.data.len() > 8 {
+            eprintln!("{path}: skipping CAN FD frame at {}", timestamp(*at));
+            continue;
+        }
+        let record = can_log::Record {
+            offset: *at - Instant::from_ticks(0),
+            id: frame.id,
+            data: &frame.data,
+        };
+        can_log::push_record(&mut log, &record);
+        count += 1;
+    }
+    std::fs::write(out, &log).expect("failed to write log");
+    eprintln!("{out}: {count} frames, {} bytes", log.len());
+}
+
+pub(crate) fn timestamp(now: Instant) -> String {
+    format!("{:>4}.{:03}", now.ticks() / 1000, now.ticks() % 1000)
+}
+
+fn id_str(id: Id) -> String {
+    match id {
+        Id::Standard(id) => format!("{:03X}", id.as_raw()),
+        Id::Extended(id) => format!("{:08X}", id.as_raw()),
+    }
+}
+
+/// Prints a line whenever one of the slow changing outputs changes level.
+/// (The soft PWM outputs aren't printed, they change too often.)
+#[derive(Default)]
+struct OutputLog {
+    last: Option<[bool; 4]>,
+}
+
+impl OutputLog {
+    fn update(&mut self, now: Instant, pins: &Pins) {
+        const NAMES: [&str; 4] = ["relay_ig3", "led_ignition", "charge_lock_drive", "charge_lock_dir"];
+        let levels = [
+            pins.relay_ig3.get(),
+            pins.led_ignition.get(),
+            pins.charge_lock_drive.get(),
+            pins.charge_lock_dir.get(),
+        ];
+        let last = self.last.unwrap_or([false; 4]);
+        for ((name, level), last) in NAMES.iter().zip(levels).zip(last) {
+            if level != last {
+                println!("{} OUT {} => {}", timestamp(now), name, level);
+            }
+        }
+        self.last = Some(levels);
+    }
+}
+
+/// The VCU's PWM signal on the SCU RX input. Each 100ms cycle starts with a
+/// falling edge, and the line goes high for the last `duty` percent of it.
+#[derive(Default)]
+struct ScuPwmRx {
+    duty: u32,
+    /// Takes effect at the start of the next cycle
+    next_duty: u32,
+    level: bool,
+}
+
+impl ScuPwmRx {
+    /// Returns the new level if the line changed level this tick
+    fn tick(&mut self, now: Instant) -> Option<bool> {
+        let in_cycle = now.ticks() % 100;
+        if in_cycle == 0 {
+            self.duty = self.next_duty;
+        }
+        let level = self.duty > 0 && in_cycle >= 100 - self.duty;
+        (level != self.level).then(|| {
+            self.level = level;
+            level
+        })
+    }
+}
+
+// The firmware modules log with defmt. Frames are only kept if --defmt-log is
+// passed, and can then be decoded with the symbols in this binary.
+static DEFMT_LOG: StdMutex<Option<File>> = StdMutex::new(None);
+static DEFMT_ENCODER: StdMutex<defmt::Encoder> = StdMutex::new(defmt::Encoder::new());
+
+#[defmt::global_logger]
+struct SimLogger;
+
+fn defmt_write(bytes: &[u8]) {
+    if let Some(file) = DEFMT_LOG.lock().unwrap().as_mut() {
+        let _ = file.write_all(bytes);
+    }
+}
+
+unsafe impl defmt::Logger for SimLogger {
+    fn acquire() {
+        DEFMT_ENCODER.lock().unwrap().start_frame(defmt_write);
+    }
+
+    unsafe fn flush() {
+        if let Some(file) = DEFMT_LOG.lock().unwrap().as_mut() {
+            let _ = file.flush();
+        }
+    }
+
+    unsafe fn release() {
+        DEFMT_ENCODER.lock().unwrap().end_frame(defmt_write);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        DEFMT_ENCODER.lock().unwrap().write(bytes, defmt_write);
+    }
+}
+
+defmt::timestamp!("{=u32}", { SimClock::now().ticks() });
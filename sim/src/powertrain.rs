@@ -0,0 +1,275 @@
+//! Simulated Kona powertrain: the VCU, BMS, OBC and inverter (MCU) that Fakon
+//! talks to on PCAN.
+//!
+//! This is the other side of the firmware. It watches Fakon's IGPM traffic
+//! for the key position, and plays out a simplified version of what the real
+//! modules send: wake, precharge, contactors closed, EV Ready, gear changes,
+//! charge port lock requests on plug in, and shutdown.
+//!
+//! The sequencing follows the logs, but the timings are shortened and the
+//! message periods are approximate. Signals which aren't modelled are left as
+//! zero.
+use crate::can_queue::SimFrame;
+use crate::hardware::SimPin;
+use fakon_core::car::Gear;
+use fakon_core::dbc::pcan::{
+    BattHvStatus, BattHvStatusPrechargeRelay, Bms542, Bms5a3, InverterStatus, Messages, Obc58e,
+    Vcu109, Vcu109ParkActuatorRequest, Vcu200, Vcu200CurrentGear,
+};
+use fakon_core::{Duration, Instant};
+use fugit::ExtU32;
+
+/// Battery pack voltage, roughly what a charged 64kWh Kona pack reads
+const V_BATT: f32 = 356.0;
+
+/// Changes to the simulated car, from the simulator script
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    /// EVSE plugged in (true) or unplugged (false)
+    Plug(bool),
+    /// Driver moves the gear selector. Ignored unless EV Ready.
+    Gear(Gear),
+    /// Motor speed while in Drive or Reverse
+    Rpm(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Asleep,
+    /// Modules powered on, contactors open
+    Waking,
+    PreCharging,
+    /// Contactors closed, HV on
+    Ready,
+    /// Contactors open, modules still sending until they go to sleep
+    ShuttingDown,
+}
+
+// How long after the last IGPM BodyState with IGN1 set that the key is
+// considered off
+const KEY_TIMEOUT: Duration = Duration::millis(500);
+const WAKE_TIME: Duration = Duration::millis(500);
+// The real car takes a bit over 3 minutes
+const SHUTDOWN_TIME: Duration = Duration::secs(3);
+// OBC lock or unlock request, and VCU park actuator request
+const REQUEST_TIME: Duration = Duration::millis(500);
+
+pub struct Powertrain {
+    state: State,
+    state_since: Instant,
+
+    last_key_on: Option<Instant>,
+    plugged_in: bool,
+    port_request: Option<(bool, Instant)>, // (Lock, until)
+    park_request: Option<(bool, Instant)>, // (Lock, until)
+
+    gear: Gear,
+    rpm: u16,
+    soc: f32,
+    v_inverter: f32,
+
+    /// Hard wired EV Ready output from the VCU (logical level)
+    ev_ready: SimPin,
+
+    next_10ms: Instant,
+    next_100ms: Instant,
+}
+
+impl Powertrain {
+    pub fn new(ev_ready: SimPin) -> Self {
+        Self {
+            state: State::Asleep,
+            state_since: Instant::from_ticks(0),
+            last_key_on: None,
+            plugged_in: false,
+            port_request: None,
+            park_request: None,
+            gear: Gear::Park,
+            rpm: 0,
+            soc: 62.5,
+            v_inverter: 0.0,
+            ev_ready,
+            next_10ms: Instant::from_ticks(0),
+            next_100ms: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Apply a script event. Returns a message if the event was ignored.
+    pub fn apply(&mut self, now: Instant, event: Event) -> Option<&'static str> {
+        match event {
+            Event::Plug(plugged) => {
+                if plugged != self.plugged_in {
+                    self.plugged_in = plugged;
+                    self.port_request = Some((plugged, now + REQUEST_TIME));
+                }
+            }
+            Event::Gear(gear) => {
+                if !self.ev_ready.get() {
+                    return Some("gear change ignored, not EV Ready");
+                }
+                if gear != self.gear {
+                    if gear == Gear::Park || self.gear == Gear::Park {
+                        self.park_request = Some((gear == Gear::Park, now + REQUEST_TIME));
+                    }
+                    self.gear = gear;
+                }
+            }
+            Event::Rpm(rpm) => self.rpm = rpm,
+        }
+        None
+    }
+
+    /// Track Fakon's transmitted messages
+    pub fn on_can_rx(&mut self, now: Instant, msg: &Messages) {
+        if let Messages::BodyState(msg) = msg {
+            if msg.ign1() {
+                self.last_key_on = Some(now);
+            }
+        }
+    }
+
+    fn key_on(&self, now: Instant) -> bool {
+        self.last_key_on.is_some_and(|t| now - t < KEY_TIMEOUT)
+    }
+
+    /// Update the simulated state for this tick, and return any frames to send
+    pub fn poll(&mut self, now: Instant) -> Vec<SimFrame> {
+        let awake = self.key_on(now) || self.plugged_in;
+        let in_state = now - self.state_since;
+
+        let next = match self.state {
+            State::Asleep if awake => Some(State::Waking),
+            State::Waking if in_state >= WAKE_TIME => Some(if awake {
+                State::PreCharging
+            } else {
+                State::ShuttingDown
+            }),
+            State::PreCharging | State::Ready if !awake => Some(State::ShuttingDown),
+            State::PreCharging if self.v_inverter >= V_BATT * 0.95 => Some(State::Ready),
+            State::ShuttingDown if awake => Some(State::Waking),
+            State::ShuttingDown if in_state >= SHUTDOWN_TIME => Some(State::Asleep),
+            _ => None,
+        };
+        if let Some(next) = next {
+            self.state = next;
+            self.state_since = now;
+        }
+
+        // Inverter DC link charges through the precharge resistor, then
+        // discharges once the contactors open
+        match self.state {
+            State::PreCharging => self.v_inverter += (V_BATT - self.v_inverter) * 0.01,
+            State::Ready => self.v_inverter = V_BATT,
+            _ => self.v_inverter *= 0.99,
+        }
+
+        let ev_ready = self.state == State::Ready && self.key_on(now);
+        if !ev_ready {
+            self.gear = Gear::Park;
+        }
+        self.ev_ready.set(ev_ready);
+
+        let mut frames = Vec::new();
+        if self.state == State::Asleep {
+            return frames;
+        }
+
+        if now >= self.next_10ms {
+            self.next_10ms = now + 10.millis();
+            frames.push(SimFrame::from_frame(&self.inverter_status()));
+            frames.push(SimFrame::from_frame(&self.vcu200()));
+            frames.push(SimFrame::from_frame(&self.vcu109(now)));
+            frames.push(SimFrame::from_frame(&self.batt_hv_status()));
+        }
+        if now >= self.next_100ms {
+            self.next_100ms = now + 100.millis();
+            if self.state == State::Ready && self.plugged_in && !self.key_on(now) {
+                // Charging at about 1% per minute
+                self.soc = (self.soc + 1.0 / 600.0).min(100.0);
+            }
+            frames.push(SimFrame::from_frame(&self.bms5a3()));
+            frames.push(SimFrame::from_frame(&self.bms542()));
+            frames.push(SimFrame::from_frame(&self.obc58e(now)));
+        }
+        frames
+    }
+
+    fn batt_hv_status(&self) -> BattHvStatus {
+        let mut msg = BattHvStatus::try_from([0u8; BattHvStatus::DLC as usize].as_slice()).unwrap();
+        if self.state == State::PreCharging {
+            // Open is the zero value
+            msg.set_precharge_relay(BattHvStatusPrechargeRelay::Closed.into())
+                .unwrap();
+        }
+        msg.set_v_batt(V_BATT).unwrap();
+        msg
+    }
+
+    fn bms5a3(&self) -> Bms5a3 {
+        let mut msg = Bms5a3::try_from([0u8; Bms5a3::DLC as usize].as_slice()).unwrap();
+        msg.set_contactor_closed(self.state == State::Ready).unwrap();
+        msg
+    }
+
+    fn bms542(&self) -> Bms542 {
+        let mut msg = Bms542::try_from([0u8; Bms542::DLC as usize].as_slice()).unwrap();
+        // Display SoC is in 0.5% steps
+        msg.set_soc_disp((self.soc * 2.0).floor() / 2.0).unwrap();
+        msg
+    }
+
+    fn obc58e(&self, now: Instant) -> Obc58e {
+        let mut msg = Obc58e::try_from([0u8; Obc58e::DLC as usize].as_slice()).unwrap();
+        msg.set_evse_detected(self.plugged_in).unwrap();
+        if let Some((lock, until)) = self.port_request {
+            if now < until {
+                msg.set_port_lock_req(lock).unwrap();
+                msg.set_port_unlock_req(!lock).unwrap();
+            }
+        }
+        msg
+    }
+
+    fn inverter_status(&self) -> InverterStatus {
+        let mut msg =
+            InverterStatus::try_from([0u8; InverterStatus::DLC as usize].as_slice()).unwrap();
+        let rpm = match self.gear {
+            Gear::Drive | Gear::Reverse => self.rpm,
+            _ => 0,
+        };
+        msg.set_v_inverter(self.v_inverter as _).unwrap();
+        msg.set_speed_abs(rpm as _).unwrap();
+        msg
+    }
+
+    fn vcu200(&self) -> Vcu200 {
+        let mut msg = Vcu200::try_from([0u8; Vcu200::DLC as usize].as_slice()).unwrap();
+        let gear = match self.gear {
+            Gear::Park => Vcu200CurrentGear::P,
+            Gear::Reverse => Vcu200CurrentGear::R,
+            Gear::Neutral => Vcu200CurrentGear::N,
+            Gear::Drive => Vcu200CurrentGear::D,
+        };
+        msg.set_current_gear(gear.into()).unwrap();
+        msg
+    }
+
+    fn vcu109(&self, now: Instant) -> Vcu109 {
+        let mut msg = Vcu109::try_from([0u8; Vcu109::DLC as usize].as_slice()).unwrap();
+        if let Some((lock, until)) = self.park_request {
+            if now < until {
+                let request = if lock {
+                    Vcu109ParkActuatorRequest::RequestLock
+                } else {
+                    Vcu109ParkActuatorRequest::RequestUnlock
+                };
+                msg.set_park_actuator_request(request.into()).unwrap();
+            }
+        }
+        msg
+    }
+}
@@ -0,0 +1,242 @@
+//! Replay a CAN capture from a real car through CarState::update_state, and
+//! print a timeline of the vehicle state it tracks.
+//!
+//! Reads candump log files (`candump -l`, one `(TIMESTAMP) IFACE ID#DATA`
+//! frame per line) and Vector ASC files. Timestamps are made relative to the
+//! first frame in the capture.
+//!
+//! Between frames time advances 1ms at a time, so the timeline shows exactly
+//! when a value goes stale. The ignition inputs are hard wired so they aren't
+//! in the capture, "ig3" in the timeline is the OBC based guess from
+//! CarState::ig3_appears_powered().
+use crate::can_queue::SimFrame;
+use crate::hardware::SimClock;
+use crate::script::parse_frame;
+use crate::timestamp;
+use embedded_can::{ExtendedId, Id, StandardId};
+use fakon_core::car::{CarState, Contactor, Gear};
+use fakon_core::dbc::pcan;
+use fakon_core::fresh::IsFresh;
+use fakon_core::Instant;
+use fugit::ExtU32;
+use std::fmt::Debug;
+
+/// Parse a candump or ASC capture, returning the data frames with timestamps
+/// relative to the first frame.
+pub fn parse_log(text: &str) -> Result<Vec<(Instant, SimFrame)>, String> {
+    let mut frames = Vec::new();
+    let mut start: Option<f64> = None;
+    let mut last = Instant::from_ticks(0);
+
+    for (n, line) in text.lines().enumerate() {
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        let line = line.trim();
+
+        let parsed = if line.starts_with('(') {
+            parse_candump_line(line).map_err(|e| err(&e))?
+        } else {
+            // ASC files have headers, comments and non-frame events mixed in
+            // with the frames, so anything that isn't a frame is skipped
+            parse_asc_line(line)
+        };
+        let Some((secs, frame)) = parsed else {
+            continue;
+        };
+
+        let start = *start.get_or_insert(secs);
+        let at = Instant::from_ticks(((secs - start) * 1000.0).max(0.0) as u32);
+        // Captures from more than one interface can be slightly out of order
+        last = last.max(at);
+        frames.push((last, frame));
+    }
+
+    Ok(frames)
+}
+
+fn parse_candump_line(line: &str) -> Result<Option<(f64, SimFrame)>, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [ts, _iface, frame] = fields[..] else {
+        return Err("expected (TIMESTAMP) IFACE ID#DATA".to_string());
+    };
+    let secs = ts
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .parse::<f64>()
+        .map_err(|_| format!("invalid timestamp '{ts}'"))?;
+    if frame.contains("#R") {
+        return Ok(None); // Remote frame
+    }
+    Ok(Some((secs, parse_frame(frame)?)))
+}
+
+fn parse_asc_line(line: &str) -> Option<(f64, SimFrame)> {
+    // <time> <channel> <id>[x] Rx|Tx d <dlc> <data bytes...>
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 6 || !matches!(fields[3], "Rx" | "Tx") || fields[4] != "d" {
+        return None;
+    }
+    let secs = fields[0].parse::<f64>().ok()?;
+    let id = match fields[2].strip_suffix('x') {
+        Some(hex) => ExtendedId::new(u32::from_str_radix(hex, 16).ok()?).map(Id::Extended)?,
+        None => StandardId::new(u16::from_str_radix(fields[2], 16).ok()?).map(Id::Standard)?,
+    };
+    let dlc = fields[5].parse::<usize>().ok()?;
+    let data = fields
+        .get(6..6 + dlc)?
+        .iter()
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((secs, SimFrame { id, data }))
+}
+
+/// The parts of CarState shown in the timeline
+#[derive(Clone, Copy)]
+struct Snapshot {
+    ig3: bool,
+    pcan_receiving: bool,
+    contactor: Option<Contactor>,
+    gear: Option<Gear>,
+    soc_batt: f32,
+    evse_detected: Option<bool>,
+}
+
+impl Snapshot {
+    fn take(car: &CarState<SimClock>) -> Self {
+        Self {
+            ig3: car.ig3_appears_powered(),
+            pcan_receiving: car.pcan_receiving(),
+            contactor: car.contactor().get(),
+            gear: car.gear().get(),
+            soc_batt: car.soc_batt(),
+            evse_detected: car.evse_detected().get(),
+        }
+    }
+
+    /// One "name old => new" entry for each field which differs from `prev`
+    fn changes(&self, prev: &Snapshot) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut check = |name: &str, old: String, new: String| {
+            if old != new {
+                res.push(format!("{name} {old} => {new}"));
+            }
+        };
+        check("ig3", prev.ig3.to_string(), self.ig3.to_string());
+        check(
+            "pcan_receiving",
+            prev.pcan_receiving.to_string(),
+            self.pcan_receiving.to_string(),
+        );
+        check("contactor", fresh_str(prev.contactor), fresh_str(self.contactor));
+        check("gear", fresh_str(prev.gear), fresh_str(self.gear));
+        check(
+            "soc_batt",
+            format!("{:.1}", prev.soc_batt),
+            format!("{:.1}", self.soc_batt),
+        );
+        check(
+            "evse_detected",
+            fresh_str(prev.evse_detected),
+            fresh_str(self.evse_detected),
+        );
+        res
+    }
+}
+
+pub(crate) fn fresh_str<T: Debug>(value: Option<T>) -> String {
+    match value {
+        Some(value) => format!("{value:?}"),
+        None => "Stale".to_string(),
+    }
+}
+
+/// Feed the frames into a fresh CarState and return the timeline, one
+/// transition per line. Runs on until every value has gone stale after the
+/// last frame.
+pub fn timeline(frames: &[(Instant, SimFrame)]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let end = match frames.last() {
+        Some((at, _)) => *at + 5.secs(),
+        None => return lines,
+    };
+
+    let mut car = CarState::<SimClock>::new();
+    let mut prev = Snapshot::take(&car);
+    let mut frames = frames.iter().peekable();
+    let mut now = Instant::from_ticks(0);
+
+    while now <= end {
+        SimClock::advance_to(now);
+
+        while let Some((at, frame)) = frames.next_if(|(at, _)| *at <= now) {
+            // Captures include plenty of messages not in the DBC, skip those
+            if let Ok(msg) = pcan::Messages::from_can_message(frame.id, &frame.data) {
+                car.update_state(&msg, *at);
+            }
+        }
+
+        let snapshot = Snapshot::take(&car);
+        for change in snapshot.changes(&prev) {
+            lines.push(format!("{} {}", timestamp(now), change));
+        }
+        prev = snapshot;
+
+        now += 1.millis();
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::SimPin;
+    use crate::powertrain::{Event, Powertrain};
+
+    /// Bus traffic from the simulated powertrain waking for the EVSE,
+    /// precharging and closing the contactors, then shutting down and going
+    /// to sleep once it's unplugged.
+    fn charge_capture() -> Vec<(Instant, SimFrame)> {
+        let mut powertrain = Powertrain::new(SimPin::default());
+        let mut frames = Vec::new();
+        for ms in 0..10_000 {
+            let now = Instant::from_ticks(ms);
+            match ms {
+                100 => powertrain.apply(now, Event::Plug(true)),
+                3000 => powertrain.apply(now, Event::Plug(false)),
+                _ => None,
+            };
+            frames.extend(powertrain.poll(now).into_iter().map(|frame| (now, frame)));
+        }
+        frames
+    }
+
+    #[test]
+    fn contactor_timeline() {
+        let timeline = timeline(&charge_capture());
+        let contactor: Vec<&str> = timeline
+            .iter()
+            .filter_map(|line| line.split_once(" contactor ").map(|(_, change)| change))
+            .collect();
+        assert_eq!(
+            contactor,
+            [
+                "Stale => Open",
+                "Open => PreCharging",
+                "PreCharging => Closed",
+                "Closed => Open",
+                "Open => Stale",
+            ]
+        );
+    }
+
+    #[test]
+    fn candump_timestamps() {
+        let text = "(1700000000.500000) can0 5A3#0000000000000000\n\
+                    (1700000000.510000) can0 5A3#R\n\
+                    (1700000001.750000) can0 5A3#0100000000000000\n";
+        let frames = parse_log(text).unwrap();
+        let times: Vec<u32> = frames.iter().map(|(at, _)| at.ticks()).collect();
+        assert_eq!(times, [0, 1250]);
+        assert_eq!(frames[1].1.data, [1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,178 @@
+//! Input script for the simulator.
+//!
+//! One event per line, a word starting with `#` starts a comment:
+//!
+//! ```text
+//! # time_ms  what       value
+//! 0          ig1        low
+//! 1000       ig1        high
+//! 1200       brake      high
+//! 2500       ev_ready   high
+//! 3000       lock       low
+//! 4000       rx         5A3#0000000000000000
+//! ```
+//!
+//! Input levels are the logical level of the input (i.e. `ev_ready high` means
+//! EV Ready, even though that input is active low on the board.) The
+//! `lock` input is the charge port lock sensor, which is high when unlocked.
+//!
+//! `rx` frames use the candump `ID#DATA` syntax and are received by the
+//! emulation as if they came from PCAN.
+//!
+//! `scu_pwm` sets the duty cycle in percent of the VCU's 10Hz PWM signal on
+//! the SCU RX input (55 idle, 85 request lock, 25 request unlock, 0 line held
+//! low). A new duty starts with the next cycle, which begins with a falling
+//! edge every 100ms.
+//!
+//! With `--powertrain` there are also events for the simulated car:
+//!
+//! ```text
+//! 5000       plug       high      # EVSE plugged in
+//! 8000       gear       D         # P, R, N or D
+//! 9000       rpm        1500
+//! ```
+//!
+//! The VCU then drives the `ev_ready` input, so scripts shouldn't set it.
+//!
+//! `expect` lines make the script a test, see the expect module.
+use crate::can_queue::SimFrame;
+use crate::expect::Expect;
+use crate::powertrain;
+use embedded_can::{ExtendedId, Id, StandardId};
+use fakon_core::car::Gear;
+use fakon_core::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    Ig1,
+    Brake,
+    EvReady,
+    ChargeLockSensor,
+}
+
+#[derive(Clone, Debug)]
+pub enum Action {
+    SetInput(Input, bool),
+    Receive(SimFrame),
+    ScuPwm(u32),
+    Powertrain(powertrain::Event),
+    Expect(Expect),
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub at: Instant,
+    pub action: Action,
+}
+
+/// Parse a script, returning the events sorted by time
+pub fn parse(text: &str) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        // '#' is also the candump ID separator, so only a comment at the start of a word
+        let fields: Vec<&str> = line
+            .split_whitespace()
+            .take_while(|f| !f.starts_with('#'))
+            .collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+
+        let at = fields[0]
+            .parse::<u32>()
+            .map(Instant::from_ticks)
+            .map_err(|_| err("expected time in milliseconds"))?;
+
+        let action = match fields.get(1) {
+            Some(&"rx") => {
+                let frame = fields.get(2).ok_or_else(|| err("expected ID#DATA"))?;
+                Action::Receive(parse_frame(frame).map_err(|e| err(&e))?)
+            }
+            Some(&"expect") => Action::Expect(Expect::parse(n + 1, &fields[2..]).map_err(|e| err(&e))?),
+            Some(&"gear") => {
+                let gear = match fields.get(2) {
+                    Some(&"P") => Gear::Park,
+                    Some(&"R") => Gear::Reverse,
+                    Some(&"N") => Gear::Neutral,
+                    Some(&"D") => Gear::Drive,
+                    _ => return Err(err("expected P, R, N or D")),
+                };
+                Action::Powertrain(powertrain::Event::Gear(gear))
+            }
+            Some(&"rpm") => {
+                let rpm = fields
+                    .get(2)
+                    .and_then(|f| f.parse().ok())
+                    .ok_or_else(|| err("expected motor RPM"))?;
+                Action::Powertrain(powertrain::Event::Rpm(rpm))
+            }
+            Some(&"scu_pwm") => {
+                let duty = fields
+                    .get(2)
+                    .and_then(|f| f.parse().ok())
+                    .filter(|duty| *duty <= 100)
+                    .ok_or_else(|| err("expected duty cycle 0-100"))?;
+                Action::ScuPwm(duty)
+            }
+            Some(&"plug") => {
+                let level = parse_level(fields.get(2)).ok_or_else(|| err("expected high or low"))?;
+                Action::Powertrain(powertrain::Event::Plug(level))
+            }
+            Some(name) => {
+                let input = match *name {
+                    "ig1" => Input::Ig1,
+                    "brake" => Input::Brake,
+                    "ev_ready" => Input::EvReady,
+                    "lock" => Input::ChargeLockSensor,
+                    other => return Err(err(&format!("unknown input '{other}'"))),
+                };
+                let level = parse_level(fields.get(2)).ok_or_else(|| err("expected high or low"))?;
+                Action::SetInput(input, level)
+            }
+            None => return Err(err("missing event")),
+        };
+
+        events.push(Event { at, action });
+    }
+
+    // Stable sort, so events at the same time happen in file order
+    events.sort_by_key(|e| e.at);
+    Ok(events)
+}
+
+fn parse_level(field: Option<&&str>) -> Option<bool> {
+    match field {
+        Some(&"high") | Some(&"1") => Some(true),
+        Some(&"low") | Some(&"0") => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a frame in candump `ID#DATA` format. IDs longer than 3 hex digits are
+/// extended IDs, same as candump.
+pub fn parse_frame(s: &str) -> Result<SimFrame, String> {
+    let (id_hex, data_hex) = s
+        .split_once('#')
+        .ok_or_else(|| format!("invalid frame '{s}'"))?;
+
+    let raw_id = u32::from_str_radix(id_hex, 16).map_err(|_| format!("invalid ID '{id_hex}'"))?;
+    let id = if id_hex.len() <= 3 {
+        StandardId::new(raw_id as u16).map(Id::Standard)
+    } else {
+        ExtendedId::new(raw_id).map(Id::Extended)
+    }
+    .ok_or_else(|| format!("ID out of range '{id_hex}'"))?;
+
+    if data_hex.len() % 2 != 0 || data_hex.len() > 16 {
+        return Err(format!("invalid data '{data_hex}'"));
+    }
+    let data = (0..data_hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data_hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("invalid data '{data_hex}'"))?;
+
+    Ok(SimFrame { id, data })
+}
@@ -0,0 +1,43 @@
+//! Bridge the simulator's virtual PCAN bus to a Linux SocketCAN interface.
+//!
+//! For a virtual interface:
+//!
+//! ```text
+//! sudo ip link add dev vcan0 type vcan
+//! sudo ip link set up vcan0
+//! ```
+use crate::can_queue::SimFrame;
+use embedded_can::Frame;
+use socketcan::{CanFrame, CanSocket, Socket};
+use std::io;
+
+pub struct Vcan(CanSocket);
+
+impl Vcan {
+    pub fn open(iface: &str) -> io::Result<Self> {
+        let socket = CanSocket::open(iface)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self(socket))
+    }
+
+    /// Return the next received data frame, if there is one
+    pub fn recv(&mut self) -> Option<SimFrame> {
+        loop {
+            match self.0.read_frame() {
+                Ok(CanFrame::Data(frame)) => return Some(SimFrame::from_frame(&frame)),
+                Ok(_) => continue, // Skip remote and error frames
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => panic!("SocketCAN read failed: {e}"),
+            }
+        }
+    }
+
+    pub fn send(&mut self, frame: &SimFrame) {
+        let frame = CanFrame::new(frame.id, &frame.data).expect("invalid frame");
+        if let Err(e) = self.0.write_frame(&frame) {
+            // Usually means the interface TX queue is full, same as a bus off
+            // the emulation doesn't care if frames are lost
+            eprintln!("SocketCAN write failed: {e}");
+        }
+    }
+}